@@ -8,9 +8,51 @@ const videoFileInput = document.getElementById('videoFile');
 const sourceCamera = document.getElementById('sourceCamera');
 const sourceVideo = document.getElementById('sourceVideo');
 const videoFileContainer = document.getElementById('videoFileContainer');
+const simulcastInput = document.getElementById('simulcast');
+const recordInput = document.getElementById('record');
+const layerContainer = document.getElementById('layerContainer');
+const layerSelect = document.getElementById('layerSelect');
+const watchPartyBtn = document.getElementById('watchPartyBtn');
+const partyVideoInput = document.getElementById('partyVideo');
+const partyAudioInput = document.getElementById('partyAudio');
+const partyTokenInput = document.getElementById('partyToken');
+const playbackContainer = document.getElementById('playbackContainer');
+const playbackStatus = document.getElementById('playbackStatus');
+const playbackControls = document.getElementById('playbackControls');
+const seekPositionInput = document.getElementById('seekPosition');
+const chatMessages = document.getElementById('chatMessages');
+const chatNameInput = document.getElementById('chatName');
+const chatTextInput = document.getElementById('chatText');
+const subtitleContainer = document.getElementById('subtitleContainer');
+const subtitleSelect = document.getElementById('subtitleSelect');
+const subtitleText = document.getElementById('subtitleText');
+const cueLanguageInput = document.getElementById('cueLanguage');
+const cueTextInput = document.getElementById('cueText');
+const cueDurationInput = document.getElementById('cueDuration');
+const broadcastList = document.getElementById('broadcastList');
+const metaTitleInput = document.getElementById('metaTitle');
+const metaDescriptionInput = document.getElementById('metaDescription');
+const metaPosterInput = document.getElementById('metaPoster');
+const metaTagsInput = document.getElementById('metaTags');
+const metaLanguageInput = document.getElementById('metaLanguage');
+const metaAgeRatingInput = document.getElementById('metaAgeRating');
+const broadcastDetails = document.getElementById('broadcastDetails');
+const detailsPoster = document.getElementById('detailsPoster');
+const detailsTitle = document.getElementById('detailsTitle');
+const detailsInfo = document.getElementById('detailsInfo');
+const detailsDescription = document.getElementById('detailsDescription');
 var pc = null;
+// Chat with everyone in the broadcast, relayed by the server
+var chatChannel = null;
+// ICE servers handed out by the server in its 'config' message
+var iceServers = [];
+var onConfig = null;
+// Token handed out by the server once broadcasting, to resume the broadcast after a connection loss
+var resume = null;
 
 const WS_URL = 'ws://localhost:8080/ws'
+const API_URL = 'http://localhost:8080/api'
+const PROTOCOL_VERSION = 1;
 
 // Show/hide video file input based on selection
 sourceCamera.addEventListener('change', function() {
@@ -34,12 +76,39 @@ async function startSession(sessionType) {
   sendOffer(sessionType, streamName);
 }
 
+// List the live broadcasts, picking one fills in the stream name to join it
+async function refreshBroadcasts() {
+  let broadcasts;
+  try {
+    const response = await fetch(`${API_URL}/broadcasts`);
+    broadcasts = await response.json();
+  } catch (e) {
+    addToOutput('Failed to list broadcasts: ' + e);
+    return;
+  }
+
+  broadcastList.innerHTML = '';
+  if (broadcasts.length === 0) {
+    broadcastList.innerHTML = '<li>Nothing is live</li>';
+  }
+  broadcasts.forEach(broadcast => {
+    const codecs = [...new Set(broadcast.tracks.map(track => track.codec))].join(', ');
+    const item = document.createElement('li');
+    const title = broadcast.metadata.title ? ` (${broadcast.metadata.title})` : '';
+    item.textContent = `${broadcast.name}${title}: ${codecs}, ${broadcast.viewers} viewer(s), `
+      + `${Math.round(broadcast.bitrate / 1000)} kbps, live since ${new Date(broadcast.started_at).toLocaleTimeString()}`;
+    item.onclick = () => streamNameInput.value = broadcast.name;
+    broadcastList.appendChild(item);
+  });
+}
+
 async function connectWebSocket() {
   return new Promise((resolve, reject) => {
     try {
       updateStatus('connecting', 'Connecting...');
       broadcastBtn.disabled = true;
       joinSessionBtn.disabled = true;
+      watchPartyBtn.disabled = true;
       streamNameInput.disabled = true;
  
       socket = new WebSocket(WS_URL);
@@ -47,18 +116,21 @@ async function connectWebSocket() {
       socket.onopen = function(event) {
         updateStatus('connected', 'Connected to WebSocket');
         addToOutput('Connected to WebSocket server');
-        resolve();
+        // Ask for the session configuration, the peer connection is created once it arrives
+        onConfig = resolve;
+        sendMessage({ type: 'hello' });
       };
 
       socket.onmessage = function(event) {
         addToOutput('Received: ' + event.data);
-        setRemoteDescription(event.data);
+        handleServerMessage(event.data);
       };
 
       socket.onclose = function(event) {
         updateStatus('disconnected', 'Disconnected');
         broadcastBtn.disabled = false;
         joinSessionBtn.disabled = false;
+        watchPartyBtn.disabled = false;
         addToOutput('Disconnected from WebSocket');
       };
  
@@ -67,6 +139,7 @@ async function connectWebSocket() {
         addToOutput('WebSocket error: ' + error);
         broadcastBtn.disabled = false;
         joinSessionBtn.disabled = false;
+        watchPartyBtn.disabled = false;
         reject();
       };
     } catch (error) {
@@ -74,45 +147,59 @@ async function connectWebSocket() {
       updateStatus('disconnected', 'Connection Failed');
       broadcastBtn.disabled = false;
       joinSessionBtn.disabled = false;
+      watchPartyBtn.disabled = false;
       reject();
     }
   });
 }
 
 function sendOffer(sessionType, streamName) {
-  pc = new RTCPeerConnection({
-    iceServers: [
-      {
-        urls: 'stun:stun.l.google.com:19302'
-      }
-    ]
-  });
+  pc = new RTCPeerConnection({ iceServers: iceServers });
 
   pc.oniceconnectionstatechange = e => addToOutput(pc.iceConnectionState);
 
+  // The server creates the same channel on its side, so it is negotiated rather than announced
+  chatChannel = pc.createDataChannel('chat', { negotiated: true, id: 0 });
+  chatChannel.onopen = () => addToOutput('Chat connected');
+  chatChannel.onmessage = event => handleChatMessage(event.data);
+
+  // Trickle ICE: candidates are sent as soon as they are gathered
   pc.onicecandidate = event => {
-  if (event.candidate === null) {
-    if (!socket || socket.readyState !== WebSocket.OPEN) {
-      console.warn('socket not open, cannot send offer');
-      return;
+    if (event.candidate === null) {
+      sendMessage({ type: 'end-of-candidates' });
+    } else {
+      sendMessage({ type: 'candidate', candidate: event.candidate.toJSON() });
     }
+  };
 
-    const innerSdpB64 = btoa(JSON.stringify(pc.localDescription));
-
-    const payload = {
-      action: sessionType,   
-      name: streamName || 'default',
-      sdp: innerSdpB64
-    };
-
-    const outer = btoa(JSON.stringify(payload));
-
-    socket.send(outer);
-    addToOutput(`Sent payload for ${payload.name} (${payload.action})`);
-  }
-};
+  // Send the offer right away, without waiting for ICE gathering
+  const setLocalOffer = async function(offer) {
+    await pc.setLocalDescription(offer);
+    const message = { type: sessionType, name: streamName || 'default', sdp: pc.localDescription };
+    if (sessionType === 'broadcast' && resume && resume.name === message.name) {
+      message.resume_token = resume.token;
+    } else if (sessionType === 'broadcast' && recordInput.checked) {
+      message.record = true;
+    }
+    if (sessionType === 'broadcast') {
+      message.metadata = broadcastMetadata();
+    }
+    sendMessage(message);
+  };
 
     if (sessionType === 'broadcast') {
+      // Resume the broadcast from a new session when the connection is lost, viewers stay attached
+      pc.onconnectionstatechange = function() {
+        addToOutput('Connection state: ' + pc.connectionState);
+        if (pc.connectionState === 'failed' && resume) {
+          addToOutput('Connection lost, resuming broadcast...');
+          pc.close();
+          pc = null;
+          disconnectWebSocket();
+          setTimeout(() => startSession('broadcast'), 1000);
+        }
+      }
+
       const broadcastSource = document.querySelector('input[name="broadcastSource"]:checked').value;
       if (broadcastSource === 'video') {
         const file = videoFileInput.files[0];
@@ -151,15 +238,12 @@ function sendOffer(sessionType, streamName) {
             // Wait for tracks to be available
             await new Promise(resolve => setTimeout(resolve, 100));
  
-            stream.getTracks().forEach(track => {
-              pc.addTrack(track, stream);
-              addToOutput(`Added ${track.kind} track to connection`);
-            });
+            addBroadcastTracks(stream);
  
             // Create and set offer
             const offer = await pc.createOffer();
             addToOutput('Created offer, setting local description');
-            await pc.setLocalDescription(offer);
+            await setLocalOffer(offer);
             addToOutput('Local description set successfully');
             
           } catch (error) {
@@ -185,21 +269,31 @@ function sendOffer(sessionType, streamName) {
       // Use camera
       navigator.mediaDevices.getUserMedia({ video: true, audio: true })
         .then(stream => {
-          stream.getTracks().forEach(track => pc.addTrack(track, stream));
+          addBroadcastTracks(stream);
           document.getElementById('video1').srcObject = stream;
           pc.createOffer()
-            .then(d => pc.setLocalDescription(d))
+            .then(setLocalOffer)
             .catch(addToOutput)
         }).catch(addToOutput)
     }
   } else {
-    // For viewers, add transceivers for both video and audio
-    pc.addTransceiver('video');
-    pc.addTransceiver('audio');  // Added audio transceiver
+    // Viewers join without an offer, the server offers every track of the broadcast.
+    // The host of a watch party joins the same way, naming the film the server plays out.
+    if (sessionType === 'watch-party') {
+      const message = { type: 'watch-party', name: streamName || 'default' };
+      if (partyVideoInput.value.trim()) message.video = partyVideoInput.value.trim();
+      if (partyAudioInput.value.trim()) message.audio = partyAudioInput.value.trim();
+      if (partyTokenInput.value.trim()) message.token = partyTokenInput.value.trim();
+      sendMessage(message);
+      playbackControls.style.display = 'inline';
+    } else {
+      sendMessage({ type: 'join', name: streamName || 'default' });
+      playbackControls.style.display = 'none';
+    }
 
-    pc.createOffer()
-      .then(d => pc.setLocalDescription(d))
-      .catch(addToOutput)
+    // Subtitle cues are pushed by the server as the broadcast reaches them
+    const subtitleChannel = pc.createDataChannel('subtitles', { negotiated: true, id: 1 });
+    subtitleChannel.onmessage = event => handleSubtitleMessage(event.data);
 
     pc.ontrack = function (event) {
       var el = document.getElementById('video1');
@@ -224,44 +318,265 @@ function sendOffer(sessionType, streamName) {
   }
 }
 
-function setRemoteDescription(remoteDescription) {
-  if (!pc) return;
+// Add the broadcaster's tracks, video as three simulcast layers when enabled
+function addBroadcastTracks(stream) {
+  stream.getTracks().forEach(track => {
+    if (track.kind === 'video' && simulcastInput.checked) {
+      pc.addTransceiver(track, {
+        direction: 'sendonly',
+        streams: [stream],
+        sendEncodings: [
+          { rid: 'high' },
+          { rid: 'medium', scaleResolutionDownBy: 2 },
+          { rid: 'low', scaleResolutionDownBy: 4 },
+        ],
+      });
+    } else {
+      pc.addTrack(track, stream);
+    }
+    addToOutput(`Added ${track.kind} track to connection`);
+  });
+}
+
+// Ask for the chosen simulcast layer, or the one that fits the connection for 'Auto'
+function selectLayer() {
+  const rid = layerSelect.value;
+  sendMessage(rid ? { type: 'select-layer', rid: rid } : { type: 'select-layer' });
+}
+
+// Metadata of the broadcast from the form, leaving out the empty fields
+function broadcastMetadata() {
+  const metadata = {
+    title: metaTitleInput.value.trim(),
+    description: metaDescriptionInput.value.trim(),
+    poster: metaPosterInput.value.trim(),
+    language: metaLanguageInput.value.trim(),
+    age_rating: metaAgeRatingInput.value.trim(),
+  };
+  Object.keys(metadata).forEach(key => {
+    if (!metadata[key]) delete metadata[key];
+  });
+  metadata.tags = metaTagsInput.value.split(',').map(tag => tag.trim()).filter(tag => tag);
+  return metadata;
+}
 
+// Change the metadata of the live broadcast, as its broadcaster
+function updateMetadata() {
+  sendMessage({ type: 'update-metadata', metadata: broadcastMetadata() });
+}
+
+function showMetadata(name, metadata) {
+  detailsTitle.textContent = metadata.title || name;
+  detailsInfo.textContent = [metadata.language, metadata.age_rating, ...(metadata.tags || [])]
+    .filter(item => item).join(' · ');
+  detailsDescription.textContent = metadata.description || '';
+  if (metadata.poster) {
+    detailsPoster.src = metadata.poster;
+    detailsPoster.style.display = 'block';
+  } else {
+    detailsPoster.style.display = 'none';
+  }
+  broadcastDetails.style.display = 'flex';
+}
+
+// Show the subtitles in the chosen language, or none for 'Off'
+function selectSubtitles() {
+  const language = subtitleSelect.value;
+  sendMessage(language ? { type: 'select-subtitles', language: language } : { type: 'select-subtitles' });
+}
+
+// Show a subtitle line to the viewers right away, as the broadcaster
+function sendCue() {
+  const text = cueTextInput.value.trim();
+  if (!text) return;
+
+  sendMessage({
+    type: 'cue',
+    language: cueLanguageInput.value.trim() || 'en',
+    text: text,
+    duration: Number(cueDurationInput.value) || 3,
+  });
+  cueTextInput.value = '';
+}
+
+function handleSubtitleMessage(data) {
+  let message;
   try {
-    // remoteDescription is expected to be a base64 string sent by server
-    const decoded = atob(remoteDescription);
-    const parsed = JSON.parse(decoded);
+    message = JSON.parse(data);
+  } catch (e) {
+    return;
+  }
 
-    if (parsed.type && parsed.sdp) {
-      pc.setRemoteDescription(new RTCSessionDescription(parsed))
-        .then(() => addToOutput('Remote description (direct) set'))
-        .catch(e => addToOutput('Failed to set remote description: ' + e));
-      return;
+  switch (message.type) {
+    case 'cue': {
+      // Cue text may carry WebVTT markup such as <i>, shown as plain text
+      const line = document.createElement('div');
+      line.textContent = message.text.replace(/<[^>]*>/g, '');
+      subtitleText.appendChild(line);
+      setTimeout(() => line.remove(), message.duration * 1000);
+      break;
     }
+    case 'clear':
+      subtitleText.innerHTML = '';
+      break;
+  }
+}
 
-    if (parsed.sdp) {
-      // parsed.sdp is expected to be base64 of the inner RTCSessionDescription JSON
-      const inner = JSON.parse(atob(parsed.sdp));
-      pc.setRemoteDescription(new RTCSessionDescription(inner))
-        .then(() => addToOutput('Remote description (from payload) set'))
-        .catch(e => addToOutput('Failed to set remote description: ' + e));
-      return;
-    }
+function sendChat() {
+  const text = chatTextInput.value.trim();
+  if (!text || !chatChannel || chatChannel.readyState !== 'open') return;
 
-    throw new Error('Unknown remote description format');
+  chatChannel.send(JSON.stringify(withChatName({ type: 'chat', text: text })));
+  chatTextInput.value = '';
+}
+
+function sendReaction(emoji) {
+  if (!chatChannel || chatChannel.readyState !== 'open') return;
+  chatChannel.send(JSON.stringify(withChatName({ type: 'reaction', emoji: emoji })));
+}
+
+function withChatName(message) {
+  const name = chatNameInput.value.trim();
+  return name ? { ...message, name: name } : message;
+}
+
+function handleChatMessage(data) {
+  let message;
+  try {
+    message = JSON.parse(data);
   } catch (e) {
-    console.error(e);
-    alert('Failed to set remote description: ' + e);
+    return;
+  }
+
+  const line = document.createElement('div');
+  const sender = message.name || 'Anonymous';
+  switch (message.type) {
+    case 'chat':
+      line.textContent = `${sender}: ${message.text}`;
+      break;
+    case 'reaction':
+      line.textContent = `${sender} reacted ${message.emoji}`;
+      break;
+    case 'error':
+      line.textContent = `Chat error: ${message.message}`;
+      break;
+    default:
+      return;
   }
+  chatMessages.appendChild(line);
+  chatMessages.scrollTop = chatMessages.scrollHeight;
 }
 
-function sendMessage() {
-  if (socket && socket.readyState === WebSocket.OPEN) {
-    const message = 'Hello WebSocket! Current time: ' + new Date().toLocaleTimeString();
-    socket.send(message);
-    addToOutput('Sent: ' + message);
-  } else {
-    addToOutput('Cannot send message - WebSocket is not connected');
+// Move the watch party to the chosen position, as its host
+function seek() {
+  sendMessage({ type: 'seek', position: Number(seekPositionInput.value) || 0 });
+}
+
+function sendMessage(message) {
+  if (!socket || socket.readyState !== WebSocket.OPEN) {
+    console.warn('socket not open, cannot send ' + message.type);
+    return;
+  }
+
+  socket.send(JSON.stringify({ version: PROTOCOL_VERSION, ...message }));
+  addToOutput(`Sent ${message.type} message`);
+}
+
+function handleServerMessage(data) {
+  let message;
+  try {
+    message = JSON.parse(data);
+  } catch (e) {
+    addToOutput('Failed to parse server message: ' + e);
+    return;
+  }
+
+  if (message.type === 'config') {
+    iceServers = message.ice_servers;
+    if (onConfig) {
+      onConfig();
+      onConfig = null;
+    }
+    return;
+  }
+
+  if (!pc) return;
+
+  switch (message.type) {
+    case 'answer':
+      pc.setRemoteDescription(new RTCSessionDescription(message.sdp))
+        .then(() => addToOutput('Remote description set'))
+        .catch(e => addToOutput('Failed to set remote description: ' + e));
+      break;
+    case 'offer':
+      pc.setRemoteDescription(new RTCSessionDescription(message.sdp))
+        .then(() => pc.createAnswer())
+        .then(answer => pc.setLocalDescription(answer))
+        .then(() => sendMessage({ type: 'answer', sdp: pc.localDescription }))
+        .catch(e => addToOutput('Failed to answer server offer: ' + e));
+      break;
+    case 'candidate':
+      pc.addIceCandidate(message.candidate)
+        .catch(e => addToOutput('Failed to add ICE candidate: ' + e));
+      break;
+    case 'end-of-candidates':
+      pc.addIceCandidate()
+        .catch(e => addToOutput('Failed to signal end of candidates: ' + e));
+      break;
+    case 'broadcast-started':
+      resume = { name: message.name, token: message.resume_token };
+      addToOutput(`Broadcast '${message.name}' is live`);
+      break;
+    case 'layers':
+      layerSelect.innerHTML = '<option value="">Auto</option>';
+      message.rids.forEach(rid => {
+        const option = document.createElement('option');
+        option.value = rid;
+        option.textContent = rid;
+        layerSelect.appendChild(option);
+      });
+      layerContainer.style.display = 'block';
+      break;
+    case 'playback':
+      playbackStatus.textContent = `${message.playing ? 'Playing' : 'Paused'} at ${message.position.toFixed(1)}s`;
+      playbackContainer.style.display = 'block';
+      break;
+    case 'subtitles': {
+      const selected = subtitleSelect.value;
+      subtitleSelect.innerHTML = '<option value="">Off</option>';
+      message.languages.forEach(language => {
+        const option = document.createElement('option');
+        option.value = language;
+        option.textContent = language;
+        subtitleSelect.appendChild(option);
+      });
+      // The server shows the first language until the viewer picks one
+      subtitleSelect.value = message.languages.includes(selected) ? selected : message.languages[0] || '';
+      subtitleContainer.style.display = 'block';
+      break;
+    }
+    case 'metadata':
+      showMetadata(message.name, message.metadata);
+      break;
+    case 'viewer-count':
+      addToOutput(`Viewers watching: ${message.count}`);
+      break;
+    case 'broadcast-ended':
+      addToOutput(`Broadcast '${message.name}' ended`);
+      pc.close();
+      pc = null;
+      layerContainer.style.display = 'none';
+      playbackContainer.style.display = 'none';
+      subtitleContainer.style.display = 'none';
+      subtitleText.innerHTML = '';
+      broadcastDetails.style.display = 'none';
+      updateStatus('disconnected', 'Broadcast ended');
+      break;
+    case 'error':
+      addToOutput(`Server error (${message.code}): ${message.message}`);
+      break;
+    default:
+      addToOutput('Unknown server message type: ' + message.type);
   }
 }
 
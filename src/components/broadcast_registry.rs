@@ -1,45 +1,544 @@
-use crate::prelude::*;
+use crate::{
+    components::{
+        recorder::{ Recording, RecordingInfo },
+        track_manager::{ RelayTrack, ViewerTrack },
+        ServerToClientMsg,
+        ServerMessage,
+        BroadcastMetadata,
+        ErrorCode,
+        SignalingError,
+    },
+    prelude::*,
+    settings::RecordingSettings,
+};
+use anyhow::Result;
+use chrono::{ DateTime, Utc };
+use serde::Serialize;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// How long a client gets to accept the end of its broadcast before it is closed without it
+const END_NOTIFY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A viewer connected to a broadcast
+struct Viewer {
+    connected_at: DateTime<Utc>,
+    state: RTCPeerConnectionState,
+    // Closed when the broadcast ends
+    peer_connection: Arc<RTCPeerConnection>,
+    // The viewer's copies of the broadcast's tracks, unsubscribed when the viewer is removed
+    tracks: Vec<Arc<ViewerTrack>>,
+    // Signaling channel of the viewer, used to announce the end of the broadcast (None for WHEP viewers)
+    notifier: Option<mpsc::Sender<ServerToClientMsg>>,
+}
+
+/// Snapshot of a viewer, as reported by the control API
+#[derive(Debug, Clone, Serialize)]
+pub struct ViewerInfo {
+    pub session_id: String,
+    pub connected_at: DateTime<Utc>,
+    // State of the viewer's peer connection, such as `connected`
+    pub state: String,
+}
+
+/// A track of a broadcast, as reported by the control API
+#[derive(Debug, Clone, Serialize)]
+pub struct TrackInfo {
+    pub kind: String,
+    // MIME type of the codec, such as `video/VP8`
+    pub codec: String,
+    pub clock_rate: u32,
+    // Simulcast layer, omitted without simulcast
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub rid: String,
+    // Incoming bitrate in bits per second
+    pub bitrate: u64,
+}
+
+/// Snapshot of a broadcast, as reported by the control API
+#[derive(Debug, Clone, Serialize)]
+pub struct BroadcastInfo {
+    pub name: String,
+    pub metadata: BroadcastMetadata,
+    pub started_at: DateTime<Utc>,
+    // Set while the broadcaster is gone and the broadcast waits for it to resume
+    pub detached: bool,
+    pub recording: bool,
+    pub viewers: usize,
+    // Incoming bitrate of every track, in bits per second
+    pub bitrate: u64,
+    pub tracks: Vec<TrackInfo>,
+}
+
+/// The broadcaster session currently feeding a broadcast
+pub struct BroadcasterSession {
+    // Identifies the session, so a stale session never unregisters its successor
+    pub id: String,
+    // Closed when a resumed session takes over (None for playouts)
+    pub peer_connection: Option<Weak<RTCPeerConnection>>,
+    // Signaling channel of the broadcaster, used to push viewer counts (None for WHIP broadcasters)
+    pub notifier: Option<mpsc::Sender<ServerToClientMsg>>,
+}
+
+impl BroadcasterSession {
+    /// Whether the broadcaster's peer connection is still up. Playouts have none and always are.
+    fn is_connected(&self) -> bool {
+        let Some(peer_connection) = &self.peer_connection else {
+            return true;
+        };
+        peer_connection.upgrade().is_some_and(|pc| {
+            !matches!(pc.connection_state(), RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed)
+        })
+    }
+}
 
 struct Broadcast {
-    pub video_track: Arc<TrackLocalStaticRTP>,
-    pub audio_track: Arc<TrackLocalStaticRTP>,
+    pub broadcaster: BroadcasterSession,
+    // Set while the broadcaster is gone and the broadcast waits for it to resume
+    pub detached: bool,
+    // Lets the same broadcaster resume the broadcast from a new session
+    pub resume_token: String,
+    pub tracks: Vec<Arc<RelayTrack>>,
+    // Kept when the broadcaster resumes the broadcast
+    started_at: DateTime<Utc>,
+    metadata: BroadcastMetadata,
+    viewers: HashMap<String, Viewer>,
+    recording: Option<Recording>,
+}
+
+impl Broadcast {
+    fn info(&self, name: &str) -> BroadcastInfo {
+        let tracks: Vec<TrackInfo> = self.tracks.iter().map(|track| TrackInfo {
+            kind: track.kind.to_string(),
+            codec: track.codec.mime_type.clone(),
+            clock_rate: track.codec.clock_rate,
+            rid: track.rid.clone(),
+            bitrate: track.bitrate(),
+        }).collect();
+
+        BroadcastInfo {
+            name: name.to_owned(),
+            metadata: self.metadata.clone(),
+            started_at: self.started_at,
+            detached: self.detached,
+            recording: self.recording.is_some(),
+            viewers: self.viewers.len(),
+            bitrate: tracks.iter().map(|track| track.bitrate).sum(),
+            tracks,
+        }
+    }
+
+    /// Push the current viewer count to the broadcaster
+    fn notify_viewer_count(&self) {
+        if let Some(notifier) = &self.broadcaster.notifier {
+            let msg = ServerMessage::ViewerCount { count: self.viewers.len() };
+            // Never block the registry on a slow broadcaster, a later count will replace this one
+            try_notify(notifier, msg);
+        }
+    }
+
+    /// Tell the broadcaster it is live, with the token needed to resume the broadcast
+    fn notify_started(&self, name: &str) {
+        if let Some(notifier) = &self.broadcaster.notifier {
+            let msg = ServerMessage::BroadcastStarted {
+                name: name.to_owned(),
+                resume_token: self.resume_token.clone(),
+            };
+            try_notify(notifier, msg);
+        }
+        self.notify_viewer_count();
+    }
+
+    /// Tell every viewer that the broadcast ended and close their peer connections.
+    /// A running recording is finished in the background.
+    async fn end(self, name: &str) {
+        if let Some(recording) = self.recording {
+            tokio::spawn(recording.stop());
+        }
+
+        for (session_id, viewer) in self.viewers {
+            // Unlike the other notifications, the end of the broadcast waits for a slow viewer, in
+            // the background so the other viewers are not held up
+            if let Some(notifier) = viewer.notifier {
+                let msg = ServerMessage::BroadcastEnded { name: name.to_owned() };
+                let name = name.to_owned();
+                tokio::spawn(async move {
+                    for message in [ServerToClientMsg::Message(msg), ServerToClientMsg::Close] {
+                        if let Err(e) = notifier.send_timeout(message, END_NOTIFY_TIMEOUT).await {
+                            debug!("Broadcast '{}': Failed to notify a viewer of the end of the broadcast: {}", name, e);
+                            return;
+                        }
+                    }
+                });
+            }
+
+            if let Err(e) = viewer.peer_connection.close().await {
+                warn!("Broadcast '{}': Failed to close viewer session {}: {}", name, session_id, e);
+            }
+        }
+    }
+}
+
+/// Push a message to a client without waiting, which a client too slow to read its messages loses
+fn try_notify(notifier: &mpsc::Sender<ServerToClientMsg>, message: ServerMessage) {
+    if let Err(mpsc::error::TrySendError::Full(_)) = notifier.try_send(ServerToClientMsg::Message(message)) {
+        debug!("Dropped a notification for a client whose signaling channel is full");
+    }
 }
 
 type BroadcastRegistry = Arc<Mutex<HashMap<String, Broadcast>>>;
 
 pub struct BroadcastManager {
     registry: BroadcastRegistry,
+    recording: RecordingSettings,
 }
 
 impl BroadcastManager {
-    pub fn new() -> Self {
+    pub fn new(recording: RecordingSettings) -> Self {
         Self {
             registry: Arc::new(Mutex::new(HashMap::new())),
+            recording,
         }
     }
 
+    /// Register a broadcast, taking over a playout under the same name.
+    /// Fails with `name_taken` if a broadcaster holds the name, which it can only get back by
+    /// resuming, and with `conflict` if the broadcaster disconnected before its tracks arrived, as
+    /// its connection state handler could no longer unregister the broadcast.
     pub async fn register_broadcast(
-        &self, 
-        name: String, 
-        video_track: Arc<TrackLocalStaticRTP>,
-        audio_track: Arc<TrackLocalStaticRTP>
-    ) {
+        &self,
+        name: String,
+        broadcaster: BroadcasterSession,
+        tracks: Vec<Arc<RelayTrack>>,
+        metadata: BroadcastMetadata
+    ) -> Result<()> {
+        let broadcast = Broadcast {
+            broadcaster,
+            detached: false,
+            resume_token: Uuid::new_v4().to_string(),
+            tracks,
+            started_at: Utc::now(),
+            metadata,
+            viewers: HashMap::new(),
+            recording: None,
+        };
+
+        let replaced = {
+            let mut registry = self.registry.lock().await;
+            if let Some(holder) = registry.get(&name).filter(|b| b.broadcaster.peer_connection.is_some()) {
+                bail!(Self::name_taken(&name, holder));
+            }
+
+            // Checked under the registry lock, so a broadcaster closing right after is unregistered
+            if !broadcast.broadcaster.is_connected() {
+                bail!(SignalingError::new(
+                    ErrorCode::Conflict,
+                    format!("The broadcaster of '{}' disconnected before the broadcast started", name)
+                ));
+            }
+
+            broadcast.notify_started(&name);
+            info!("Registering broadcast: {}", name);
+            registry.insert(name.clone(), broadcast)
+        };
+
+        if let Some(replaced) = replaced {
+            warn!("Broadcast '{}': Replaced by a new broadcaster", name);
+            replaced.end(&name).await;
+        }
+        Ok(())
+    }
+
+    /// Fail with `name_taken` if a broadcaster holds the name of the broadcast
+    pub async fn check_name(&self, name: &str) -> Result<()> {
+        let registry = self.registry.lock().await;
+        if let Some(holder) = registry.get(name).filter(|b| b.broadcaster.peer_connection.is_some()) {
+            bail!(Self::name_taken(name, holder));
+        }
+        Ok(())
+    }
+
+    fn name_taken(name: &str, holder: &Broadcast) -> SignalingError {
+        let state = if holder.detached { "waiting for its broadcaster to resume" } else { "already live" };
+        SignalingError::new(ErrorCode::NameTaken, format!("Broadcast '{}' is {}", name, state))
+    }
+
+    /// The relay tracks of a broadcast, if `resume_token` allows resuming it
+    pub async fn get_resumable_tracks(
+        &self,
+        name: &str,
+        resume_token: &str
+    ) -> Option<Vec<Arc<RelayTrack>>> {
+        let registry = self.registry.lock().await;
+        registry.get(name)
+            .filter(|b| b.resume_token == resume_token)
+            .map(|b| b.tracks.clone())
+    }
+
+    /// Hand a broadcast over to a resumed broadcaster session, closing the previous one.
+    /// Returns false if the broadcast ended or `resume_token` does not match.
+    pub async fn resume_broadcast(&self, name: &str, resume_token: &str, broadcaster: BroadcasterSession) -> bool {
         let mut registry = self.registry.lock().await;
-        info!("Registering broadcast: {}", name);
-        registry.insert(name.clone(), Broadcast { video_track, audio_track });
+        let Some(broadcast) = registry.get_mut(name).filter(|b| b.resume_token == resume_token) else {
+            return false;
+        };
+
+        let previous = std::mem::replace(&mut broadcast.broadcaster, broadcaster);
+        broadcast.detached = false;
+        broadcast.notify_started(name);
+        info!("Broadcast '{}': Resumed by broadcaster session {}", name, broadcast.broadcaster.id);
+
+        // The previous session may not have noticed it is gone yet
+        if let Some(peer_connection) = previous.peer_connection.and_then(|pc| pc.upgrade()) {
+            tokio::spawn(async move {
+                let _ = peer_connection.close().await;
+            });
+        }
+
+        true
     }
 
-    pub async fn unregister_broadcast(&self, name: &str) {
+    /// Keep a broadcast registered after its broadcaster went away, so it can be resumed.
+    /// Returns false if `broadcaster_id` no longer feeds the broadcast.
+    pub async fn detach_broadcaster(&self, name: &str, broadcaster_id: &str) -> bool {
         let mut registry = self.registry.lock().await;
-        if registry.remove(name).is_some() {
-            info!("Unregistered broadcast: {}", name);
-        } else {
-            warn!("Attempted to unregister non-existent broadcast: {}", name);
+        match registry.get_mut(name) {
+            Some(broadcast) if broadcast.broadcaster.id == broadcaster_id => {
+                broadcast.detached = true;
+                info!("Broadcast '{}': Broadcaster gone, waiting for it to resume", name);
+                true
+            }
+            _ => false,
         }
     }
 
-    pub async fn get_broadcast(&self, name: &str) -> Option<(Arc<TrackLocalStaticRTP>, Arc<TrackLocalStaticRTP>)> {
+    /// Unregister the broadcast fed by `broadcaster_id`, unless it is waiting for a resume
+    pub async fn unregister_broadcast(&self, name: &str, broadcaster_id: &str) {
+        self.remove_broadcast(name, broadcaster_id, false).await;
+    }
+
+    /// Unregister the broadcast if `broadcaster_id` was detached and never resumed
+    pub async fn expire_broadcast(&self, name: &str, broadcaster_id: &str) {
+        self.remove_broadcast(name, broadcaster_id, true).await;
+    }
+
+    /// Remove the broadcast, then tell its viewers that it ended and close their peer connections
+    async fn remove_broadcast(&self, name: &str, broadcaster_id: &str, detached: bool) {
+        let broadcast = {
+            let mut registry = self.registry.lock().await;
+            match registry.get(name) {
+                Some(b) if b.broadcaster.id == broadcaster_id && b.detached == detached => registry.remove(name),
+                _ => None,
+            }
+        };
+
+        let Some(broadcast) = broadcast else {
+            debug!("Broadcast '{}': Not registered by this broadcaster, nothing to unregister", name);
+            return;
+        };
+        info!("Unregistered broadcast: {}", name);
+
+        broadcast.end(name).await;
+    }
+
+    /// Whether `broadcaster_id` still feeds the broadcast
+    pub async fn is_broadcaster(&self, name: &str, broadcaster_id: &str) -> bool {
+        let registry = self.registry.lock().await;
+        registry.get(name).is_some_and(|b| b.broadcaster.id == broadcaster_id)
+    }
+
+    /// The relay tracks of a broadcast, to be sent to its viewers
+    pub async fn get_broadcast(&self, name: &str) -> Option<Vec<Arc<RelayTrack>>> {
+        let registry = self.registry.lock().await;
+        registry.get(name).map(|b| b.tracks.clone())
+    }
+
+    /// Metadata of a broadcast, or None if it does not exist
+    pub async fn metadata(&self, name: &str) -> Option<BroadcastMetadata> {
+        let registry = self.registry.lock().await;
+        registry.get(name).map(|b| b.metadata.clone())
+    }
+
+    /// Replace the metadata of a broadcast and push it to its viewers.
+    /// Returns false if the broadcast does not exist.
+    pub async fn update_metadata(&self, name: &str, metadata: BroadcastMetadata) -> bool {
+        let mut registry = self.registry.lock().await;
+        let Some(broadcast) = registry.get_mut(name) else {
+            return false;
+        };
+
+        broadcast.metadata = metadata;
+        debug!("Broadcast '{}': Metadata updated", name);
+
+        let message = ServerMessage::Metadata { name: name.to_owned(), metadata: broadcast.metadata.clone() };
+        for notifier in broadcast.viewers.values().filter_map(|viewer| viewer.notifier.as_ref()) {
+            try_notify(notifier, message.clone());
+        }
+        true
+    }
+
+    /// Every registered broadcast, in order of name
+    pub async fn list_broadcasts(&self) -> Vec<BroadcastInfo> {
+        let registry = self.registry.lock().await;
+        let mut list: Vec<BroadcastInfo> = registry.iter().map(|(name, b)| b.info(name)).collect();
+
+        list.sort_by(|a, b| a.name.cmp(&b.name));
+        list
+    }
+
+    /// Snapshot of a broadcast, or None if it does not exist
+    pub async fn broadcast_info(&self, name: &str) -> Option<BroadcastInfo> {
+        let registry = self.registry.lock().await;
+        registry.get(name).map(|b| b.info(name))
+    }
+
+    /// Start recording a broadcast, failing with `not_found` if it is not live and with `conflict`
+    /// if it is already recorded
+    pub async fn start_recording(&self, name: &str) -> Result<RecordingInfo> {
+        let tracks = {
+            let mut registry = self.registry.lock().await;
+            Self::recordable(&mut registry, name)?.tracks.clone()
+        };
+
+        // The files are created without holding the registry, so the broadcast may have ended or
+        // been taken over meanwhile
+        let recording = Recording::create(&self.recording, name, &tracks).await?;
+
+        let mut registry = self.registry.lock().await;
+        let checked = Self::recordable(&mut registry, name).and_then(|broadcast| {
+            let unchanged = broadcast.tracks.len() == tracks.len()
+                && broadcast.tracks.iter().zip(&tracks).all(|(a, b)| Arc::ptr_eq(a, b));
+            if !unchanged {
+                bail!(SignalingError::new(ErrorCode::Conflict, format!("Broadcast '{}' changed while starting the recording", name)));
+            }
+            Ok(broadcast)
+        });
+        let broadcast = match checked {
+            Ok(broadcast) => broadcast,
+            Err(e) => {
+                drop(registry);
+                recording.discard().await;
+                return Err(e);
+            }
+        };
+
+        recording.attach();
+        let info = recording.info();
+        broadcast.recording = Some(recording);
+        Ok(info)
+    }
+
+    /// A broadcast that can start recording, failing with `not_found` if it is not live and with
+    /// `conflict` if it is already recorded
+    fn recordable<'a>(registry: &'a mut HashMap<String, Broadcast>, name: &str) -> Result<&'a mut Broadcast> {
+        let Some(broadcast) = registry.get_mut(name) else {
+            bail!(SignalingError::new(ErrorCode::NotFound, format!("Broadcast '{}' not found", name)));
+        };
+
+        if broadcast.recording.is_some() {
+            bail!(SignalingError::new(ErrorCode::Conflict, format!("Broadcast '{}' is already recorded", name)));
+        }
+        Ok(broadcast)
+    }
+
+    /// Stop recording a broadcast, returning the recorded files
+    pub async fn stop_recording(&self, name: &str) -> Result<RecordingInfo> {
+        let recording = {
+            let mut registry = self.registry.lock().await;
+            let Some(broadcast) = registry.get_mut(name) else {
+                bail!(SignalingError::new(ErrorCode::NotFound, format!("Broadcast '{}' not found", name)));
+            };
+            broadcast.recording.take()
+        };
+
+        // Muxing the files may take a while, so the registry is not held meanwhile
+        match recording {
+            Some(recording) => Ok(recording.stop().await),
+            None => bail!(SignalingError::new(ErrorCode::Conflict, format!("Broadcast '{}' is not recorded", name))),
+        }
+    }
+
+    /// Attach a viewer to a broadcast. Returns false if the broadcast does not exist.
+    pub async fn add_viewer(
+        &self,
+        name: &str,
+        session_id: String,
+        peer_connection: Arc<RTCPeerConnection>,
+        tracks: Vec<Arc<ViewerTrack>>,
+        notifier: Option<mpsc::Sender<ServerToClientMsg>>
+    ) -> bool {
+        let mut registry = self.registry.lock().await;
+        let Some(broadcast) = registry.get_mut(name) else {
+            return false;
+        };
+
+        broadcast.viewers.insert(session_id, Viewer {
+            connected_at: Utc::now(),
+            state: peer_connection.connection_state(),
+            peer_connection,
+            tracks,
+            notifier,
+        });
+        debug!("Broadcast '{}': Viewer added, {} viewer(s) connected", name, broadcast.viewers.len());
+
+        broadcast.notify_viewer_count();
+        true
+    }
+
+    /// The tracks of a viewer, or None if the viewer or its broadcast is gone
+    pub async fn viewer_tracks(&self, name: &str, session_id: &str) -> Option<Vec<Arc<ViewerTrack>>> {
         let registry = self.registry.lock().await;
-        registry.get(name).map(|b| (Arc::clone(&b.video_track), Arc::clone(&b.audio_track)))
+        registry.get(name)
+            .and_then(|b| b.viewers.get(session_id))
+            .map(|viewer| viewer.tracks.clone())
     }
-}
\ No newline at end of file
+
+    /// Record the new state of a viewer's peer connection.
+    /// Viewers whose connection failed or closed are removed from the broadcast.
+    pub async fn update_viewer_state(&self, name: &str, session_id: &str, state: RTCPeerConnectionState) {
+        let mut registry = self.registry.lock().await;
+        let Some(broadcast) = registry.get_mut(name) else {
+            return;
+        };
+
+        match state {
+            RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed => {
+                if broadcast.viewers.remove(session_id).is_some() {
+                    debug!("Broadcast '{}': Viewer removed ({}), {} viewer(s) connected",
+                           name, state, broadcast.viewers.len());
+                    broadcast.notify_viewer_count();
+                }
+            }
+            _ => {
+                if let Some(viewer) = broadcast.viewers.get_mut(session_id) {
+                    viewer.state = state;
+                }
+            }
+        }
+    }
+
+    /// Push a message to every viewer of a broadcast connected over the signaling channel
+    pub async fn notify_viewers(&self, name: &str, message: ServerMessage) {
+        let registry = self.registry.lock().await;
+        let Some(broadcast) = registry.get(name) else {
+            return;
+        };
+
+        for notifier in broadcast.viewers.values().filter_map(|viewer| viewer.notifier.as_ref()) {
+            // Never block the registry on a slow viewer
+            try_notify(notifier, message.clone());
+        }
+    }
+
+    /// The viewers connected to a broadcast, or None if it does not exist
+    pub async fn get_viewers(&self, name: &str) -> Option<Vec<ViewerInfo>> {
+        let registry = self.registry.lock().await;
+        registry.get(name).map(|b| {
+            b.viewers.iter().map(|(session_id, viewer)| ViewerInfo {
+                session_id: session_id.clone(),
+                connected_at: viewer.connected_at,
+                state: viewer.state.to_string(),
+            }).collect()
+        })
+    }
+}
@@ -0,0 +1,403 @@
+use crate::{
+    prelude::*,
+    settings::ChatSettings,
+};
+use std::{ collections::VecDeque, time::Instant };
+
+use anyhow::Result;
+use chrono::{ DateTime, Utc };
+use serde::{ Deserialize, Serialize };
+use webrtc::data_channel::{
+    data_channel_init::RTCDataChannelInit,
+    data_channel_message::DataChannelMessage,
+    RTCDataChannel,
+};
+
+/// Label of the chat data channel
+const CHAT_CHANNEL_LABEL: &str = "chat";
+
+/// Id of the chat data channel, negotiated out of band so both sides create it on their own
+const CHAT_CHANNEL_ID: u16 = 0;
+
+/// Messages a user can send at once before the rate limit applies
+const CHAT_BURST: f64 = 5.0;
+
+/// Bytes queued on a member's data channel, beyond which relayed messages are dropped for it
+const CHAT_MAX_BUFFERED: usize = 1024 * 1024;
+
+/// Longest display name and reaction, in characters
+const CHAT_MAX_NAME_LENGTH: usize = 32;
+const CHAT_MAX_REACTION_LENGTH: usize = 16;
+
+/// Messages sent by a client over the chat data channel
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case", deny_unknown_fields)]
+enum ChatRequest {
+    /// A chat message, shown with the sender's display name
+    Chat {
+        #[serde(default)]
+        name: Option<String>,
+        text: String,
+    },
+    /// An emoji reaction, not kept in the history
+    Reaction {
+        #[serde(default)]
+        name: Option<String>,
+        emoji: String,
+    },
+}
+
+/// Messages sent by the server over the chat data channel
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+enum ChatEvent {
+    /// A chat message, relayed to everyone in the broadcast including its sender
+    Chat {
+        // Session id of the sender
+        from: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+        text: String,
+        at: DateTime<Utc>,
+    },
+    /// An emoji reaction, relayed to everyone in the broadcast including its sender
+    Reaction {
+        from: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+        emoji: String,
+        at: DateTime<Utc>,
+    },
+    /// The sender's message was rejected
+    Error { message: String },
+}
+
+/// Token bucket limiting the messages of a member
+struct RateLimiter {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    fn new() -> Self {
+        Self { tokens: CHAT_BURST, updated: Instant::now() }
+    }
+
+    /// Take a token for a message sent at `now`, returns false if the member sends too fast
+    fn allow(&mut self, rate: f64, now: Instant) -> bool {
+        self.tokens = (self.tokens + now.duration_since(self.updated).as_secs_f64() * rate).min(CHAT_BURST);
+        self.updated = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+struct ChatMember {
+    channel: Arc<RTCDataChannel>,
+    limiter: RateLimiter,
+}
+
+/// Chat of a broadcast, dropped once its last member leaves
+#[derive(Default)]
+struct ChatRoom {
+    members: HashMap<String, ChatMember>,
+    // Latest chat messages, serialized, sent to members when they join
+    history: VecDeque<String>,
+}
+
+impl ChatRoom {
+    /// Keep a chat message for late joiners, forgetting the oldest one past `capacity`
+    fn remember(&mut self, text: String, capacity: usize) {
+        if capacity == 0 {
+            return;
+        }
+        if self.history.len() == capacity {
+            self.history.pop_front();
+        }
+        self.history.push_back(text);
+    }
+}
+
+/// Whether a member's data channel queues too much to be sent more messages
+fn is_congested(buffered_amount: usize) -> bool {
+    buffered_amount > CHAT_MAX_BUFFERED
+}
+
+type ChatRooms = Arc<Mutex<HashMap<String, ChatRoom>>>;
+
+/// Relays chat messages and reactions between the broadcaster and the viewers of a broadcast,
+/// over a data channel the server negotiates on each of their peer connections
+#[derive(Clone)]
+pub struct ChatManager {
+    settings: ChatSettings,
+    rooms: ChatRooms,
+}
+
+impl ChatManager {
+    pub fn new(settings: ChatSettings) -> Self {
+        Self {
+            settings,
+            rooms: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Create the chat data channel of a session's peer connection, before the SDP exchange.
+    /// The client creates its side with the same negotiated id. The session joins the
+    /// broadcast's chat once the channel opens, and leaves it when the channel closes.
+    pub async fn attach(&self, broadcast: &str, session_id: &str, peer_connection: &RTCPeerConnection) -> Result<()> {
+        let channel = peer_connection.create_data_channel(CHAT_CHANNEL_LABEL, Some(RTCDataChannelInit {
+            ordered: Some(true),
+            negotiated: Some(CHAT_CHANNEL_ID),
+            ..Default::default()
+        })).await?;
+
+        let chat = self.clone();
+        let broadcast = broadcast.to_owned();
+        let session_id = session_id.to_owned();
+        let weak_channel = Arc::downgrade(&channel);
+
+        channel.on_open(Box::new({
+            let (chat, broadcast, session_id) = (chat.clone(), broadcast.clone(), session_id.clone());
+            move || {
+                let (chat, broadcast, session_id) = (chat.clone(), broadcast.clone(), session_id.clone());
+                let channel = weak_channel.clone();
+                Box::pin(async move {
+                    if let Some(channel) = channel.upgrade() {
+                        chat.join(&broadcast, session_id, channel).await;
+                    }
+                })
+            }
+        }));
+
+        channel.on_message(Box::new({
+            let (chat, broadcast, session_id) = (chat.clone(), broadcast.clone(), session_id.clone());
+            move |message: DataChannelMessage| {
+                let (chat, broadcast, session_id) = (chat.clone(), broadcast.clone(), session_id.clone());
+                Box::pin(async move {
+                    chat.receive(&broadcast, &session_id, message).await;
+                })
+            }
+        }));
+
+        channel.on_close(Box::new(move || {
+            let (chat, broadcast, session_id) = (chat.clone(), broadcast.clone(), session_id.clone());
+            Box::pin(async move {
+                chat.leave(&broadcast, &session_id).await;
+            })
+        }));
+
+        Ok(())
+    }
+
+    /// Add a member to the chat of a broadcast and send it the history
+    async fn join(&self, broadcast: &str, session_id: String, channel: Arc<RTCDataChannel>) {
+        let history: Vec<String> = {
+            let mut rooms = self.rooms.lock().await;
+            let room = rooms.entry(broadcast.to_owned()).or_default();
+            room.members.insert(session_id, ChatMember { channel: Arc::clone(&channel), limiter: RateLimiter::new() });
+            debug!("Broadcast '{}': Chat member joined, {} member(s)", broadcast, room.members.len());
+            room.history.iter().cloned().collect()
+        };
+
+        for message in history {
+            if channel.send_text(message).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    /// Remove a member from the chat of a broadcast, dropping the chat once it is empty
+    pub async fn leave(&self, broadcast: &str, session_id: &str) {
+        let mut rooms = self.rooms.lock().await;
+        let Some(room) = rooms.get_mut(broadcast) else {
+            return;
+        };
+
+        if room.members.remove(session_id).is_some() {
+            debug!("Broadcast '{}': Chat member left, {} member(s)", broadcast, room.members.len());
+        }
+        if room.members.is_empty() {
+            rooms.remove(broadcast);
+        }
+    }
+
+    /// Check a member's message against the limits, then relay it to the whole broadcast
+    async fn receive(&self, broadcast: &str, session_id: &str, message: DataChannelMessage) {
+        // Rejected messages count against the rate limit too
+        let allowed = {
+            let mut rooms = self.rooms.lock().await;
+            let Some(member) = rooms.get_mut(broadcast).and_then(|room| room.members.get_mut(session_id)) else {
+                return;
+            };
+            member.limiter.allow(self.settings.rate_limit, Instant::now())
+        };
+        if !allowed {
+            self.reply(broadcast, session_id, "Slow down, you are sending messages too fast".to_owned()).await;
+            return;
+        }
+
+        let event = match self.parse(session_id, &message) {
+            Ok(event) => event,
+            Err(e) => {
+                self.reply(broadcast, session_id, e).await;
+                return;
+            }
+        };
+        let Ok(text) = serde_json::to_string(&event) else {
+            return;
+        };
+
+        let channels: Vec<Arc<RTCDataChannel>> = {
+            let mut rooms = self.rooms.lock().await;
+            let Some(room) = rooms.get_mut(broadcast) else {
+                return;
+            };
+
+            if matches!(event, ChatEvent::Chat { .. }) {
+                room.remember(text.clone(), self.settings.history);
+            }
+
+            room.members.values().map(|member| Arc::clone(&member.channel)).collect()
+        };
+
+        for channel in channels {
+            // A member that cannot keep up misses messages, never the rest of the broadcast
+            if is_congested(channel.buffered_amount().await) {
+                continue;
+            }
+            let _ = channel.send_text(text.clone()).await;
+        }
+    }
+
+    /// The event relayed for a member's message, or why it was rejected
+    fn parse(&self, session_id: &str, message: &DataChannelMessage) -> Result<ChatEvent, String> {
+        if message.data.len() > self.settings.max_message_size {
+            return Err(format!("Messages are limited to {} bytes", self.settings.max_message_size));
+        }
+
+        let request = std::str::from_utf8(&message.data).ok()
+            .and_then(|text| serde_json::from_str::<ChatRequest>(text).ok())
+            .ok_or_else(|| "Invalid chat message".to_owned())?;
+
+        let display_name = |name: Option<String>| {
+            name.map(|name| name.trim().chars().take(CHAT_MAX_NAME_LENGTH).collect::<String>())
+                .filter(|name| !name.is_empty())
+        };
+
+        match request {
+            ChatRequest::Chat { name, text } => {
+                if text.trim().is_empty() {
+                    return Err("Empty chat message".to_owned());
+                }
+                Ok(ChatEvent::Chat { from: session_id.to_owned(), name: display_name(name), text, at: Utc::now() })
+            }
+            ChatRequest::Reaction { name, emoji } => {
+                if emoji.is_empty() || emoji.chars().count() > CHAT_MAX_REACTION_LENGTH {
+                    return Err("Invalid reaction".to_owned());
+                }
+                Ok(ChatEvent::Reaction { from: session_id.to_owned(), name: display_name(name), emoji, at: Utc::now() })
+            }
+        }
+    }
+
+    /// Tell a member why its message was rejected
+    async fn reply(&self, broadcast: &str, session_id: &str, message: String) {
+        let channel = {
+            let rooms = self.rooms.lock().await;
+            rooms.get(broadcast)
+                .and_then(|room| room.members.get(session_id))
+                .map(|member| Arc::clone(&member.channel))
+        };
+
+        debug!("Broadcast '{}': Rejected chat message: {}", broadcast, message);
+        if let (Some(channel), Ok(text)) = (channel, serde_json::to_string(&ChatEvent::Error { message })) {
+            let _ = channel.send_text(text).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn chat() -> ChatManager {
+        ChatManager::new(ChatSettings { max_message_size: 64, rate_limit: 1.0, history: 3 })
+    }
+
+    fn message(text: &str) -> DataChannelMessage {
+        DataChannelMessage { is_string: true, data: text.to_owned().into() }
+    }
+
+    #[test]
+    fn allows_a_burst_then_the_rate() {
+        let mut limiter = RateLimiter::new();
+        let start = limiter.updated;
+
+        for _ in 0..5 {
+            assert!(limiter.allow(1.0, start));
+        }
+        assert!(!limiter.allow(1.0, start));
+
+        // One token comes back per second, and never more than the burst
+        assert!(!limiter.allow(1.0, start + Duration::from_millis(500)));
+        assert!(limiter.allow(1.0, start + Duration::from_millis(1000)));
+        assert!(!limiter.allow(1.0, start + Duration::from_millis(1000)));
+
+        let later = start + Duration::from_secs(60);
+        for _ in 0..5 {
+            assert!(limiter.allow(1.0, later));
+        }
+        assert!(!limiter.allow(1.0, later));
+    }
+
+    #[test]
+    fn limits_the_message_size() {
+        let chat = chat();
+        // 64 bytes, then 65
+        let fits = format!(r#"{{"type":"chat","text":"{}"}}"#, "x".repeat(39));
+        let too_long = format!(r#"{{"type":"chat","text":"{}"}}"#, "x".repeat(40));
+
+        assert!(matches!(chat.parse("a", &message(&fits)), Ok(ChatEvent::Chat { .. })));
+        assert_eq!(chat.parse("a", &message(&too_long)).unwrap_err(), "Messages are limited to 64 bytes");
+    }
+
+    #[test]
+    fn rejects_invalid_messages() {
+        let chat = chat();
+        assert!(chat.parse("a", &message(r#"{"type":"chat","text":"  "}"#)).is_err());
+        assert!(chat.parse("a", &message(r#"{"type":"reaction","emoji":""}"#)).is_err());
+        assert!(chat.parse("a", &message(r#"{"type":"shout","text":"hi"}"#)).is_err());
+        assert!(chat.parse("a", &message("not json")).is_err());
+
+        let Ok(ChatEvent::Reaction { name, .. }) = chat.parse("a", &message(r#"{"type":"reaction","name":" Ann ","emoji":"👏"}"#)) else {
+            panic!("Expected a reaction");
+        };
+        assert_eq!(name.as_deref(), Some("Ann"));
+    }
+
+    #[test]
+    fn caps_the_history() {
+        let mut room = ChatRoom::default();
+        for text in ["1", "2", "3", "4"] {
+            room.remember(text.to_owned(), 3);
+        }
+        assert_eq!(room.history, ["2", "3", "4"]);
+
+        // Without history, nothing is kept
+        let mut room = ChatRoom::default();
+        room.remember("1".to_owned(), 0);
+        assert!(room.history.is_empty());
+    }
+
+    #[test]
+    fn skips_congested_members() {
+        assert!(!is_congested(0));
+        assert!(!is_congested(CHAT_MAX_BUFFERED));
+        assert!(is_congested(CHAT_MAX_BUFFERED + 1));
+    }
+}
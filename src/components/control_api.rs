@@ -0,0 +1,268 @@
+use crate::{
+    components::{
+        http_signaling::error_response, BroadcastMetadata, ErrorCode, PlayoutManager, PlayoutSource, Scheduler,
+        SessionManager, SignalingError,
+    },
+    prelude::*,
+    settings::{ ApiSettings, ScreeningConfig },
+};
+
+use actix_web::{ dev::Payload, error::InternalError, http::header, web, FromRequest, HttpRequest, HttpResponse };
+use std::{ future::{ ready, Ready }, net::SocketAddr };
+
+/// HTTP API to control the live broadcasts.
+///
+/// - `GET /api/broadcasts` lists the live broadcasts, with their tracks, viewer count and bitrate
+/// - `GET /api/broadcasts/{broadcast}` describes a live broadcast
+/// - `GET /api/broadcasts/{broadcast}/viewers` lists the viewers of a live broadcast, with their
+///   connection time and state
+/// - `PUT /api/broadcasts/{broadcast}/metadata` replaces the metadata of a live broadcast, with a
+///   `{"title": "...", "description": "...", "poster": "https://...", "tags": [], "language": "en",
+///   "age_rating": "PG"}` body
+/// - `POST /api/broadcasts/{broadcast}/recording` starts recording a broadcast
+/// - `DELETE /api/broadcasts/{broadcast}/recording` stops it and returns the recorded files, with
+///   an `error` if they could not be muxed into a WebM file
+/// - `POST /api/broadcasts/{broadcast}/playout` publishes media files as the broadcast, with a
+///   `{"video": "movie.ivf", "audio": "movie.ogg", "loop": false}` body
+/// - `DELETE /api/broadcasts/{broadcast}/playout` stops the playout and ends the broadcast
+/// - `GET /api/broadcasts/{broadcast}/subtitles` lists the subtitle tracks of a broadcast
+/// - `PUT /api/broadcasts/{broadcast}/subtitles/{language}` loads a WebVTT file, sent as the
+///   body, as the subtitles in `language`. Tracks can be loaded before the broadcast goes live.
+/// - `DELETE /api/broadcasts/{broadcast}/subtitles/{language}` removes the subtitles in `language`
+/// - `GET /api/schedule` lists the scheduled screenings in order of start time
+/// - `POST /api/schedule` schedules a screening, with the body of a `[[schedule]]` entry
+/// - `DELETE /api/schedule/{id}` cancels a screening, stopping it if it is playing
+///
+/// Every request that changes something must carry the configured token as an
+/// `Authorization: Bearer <token>` header, or come from localhost if no token is configured.
+/// Other requests are rejected with `401 Unauthorized`.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg
+        .route("/api/broadcasts", web::get().to(broadcast_list))
+        .route("/api/broadcasts/{broadcast}", web::get().to(broadcast_get))
+        .route("/api/broadcasts/{broadcast}/viewers", web::get().to(viewer_list))
+        .route("/api/broadcasts/{broadcast}/metadata", web::put().to(metadata_update))
+        .route("/api/broadcasts/{broadcast}/recording", web::post().to(recording_start))
+        .route("/api/broadcasts/{broadcast}/recording", web::delete().to(recording_stop))
+        .route("/api/broadcasts/{broadcast}/playout", web::post().to(playout_start))
+        .route("/api/broadcasts/{broadcast}/playout", web::delete().to(playout_stop))
+        .route("/api/broadcasts/{broadcast}/subtitles", web::get().to(subtitles_list))
+        .route("/api/broadcasts/{broadcast}/subtitles/{language}", web::put().to(subtitles_load))
+        .route("/api/broadcasts/{broadcast}/subtitles/{language}", web::delete().to(subtitles_remove))
+        .route("/api/schedule", web::get().to(schedule_list))
+        .route("/api/schedule", web::post().to(schedule_add))
+        .route("/api/schedule/{id}", web::delete().to(schedule_cancel));
+}
+
+/// Extracted by the handlers that change something, rejecting the request unless it is authorized
+/// by the control API settings
+struct Authorized;
+
+impl FromRequest for Authorized {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let settings = req.app_data::<web::Data<ApiSettings>>().map(|s| s.get_ref().clone()).unwrap_or_default();
+
+        ready(match Self::check(req, &settings) {
+            Ok(()) => Ok(Authorized),
+            Err(e) => {
+                debug!("Rejected control API request {} {}: {}", req.method(), req.path(), e);
+                Err(InternalError::from_response(e.to_string(), error_response(&e)).into())
+            }
+        })
+    }
+}
+
+impl Authorized {
+    fn check(req: &HttpRequest, settings: &ApiSettings) -> anyhow::Result<()> {
+        let bearer = req.headers().get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        authorize(req.peer_addr(), bearer, settings)
+    }
+}
+
+/// Check the credentials of a request that controls the server, through the control API or the
+/// signaling of a watch party: the configured API token, or coming from localhost without one
+pub fn authorize(peer: Option<SocketAddr>, token: Option<&str>, settings: &ApiSettings) -> anyhow::Result<()> {
+    let Some(expected) = &settings.token else {
+        if peer.is_some_and(|addr| addr.ip().to_canonical().is_loopback()) {
+            return Ok(());
+        }
+        bail!(SignalingError::new(ErrorCode::Unauthorized, "Only accepted from localhost without an API token"));
+    };
+
+    match token {
+        Some(token) if tokens_match(token.trim().as_bytes(), expected.as_bytes()) => Ok(()),
+        Some(_) => bail!(SignalingError::new(ErrorCode::Unauthorized, "Invalid API token")),
+        None => bail!(SignalingError::new(ErrorCode::Unauthorized, "Missing API token")),
+    }
+}
+
+/// Compare the tokens in constant time, so their content cannot be guessed from response times
+fn tokens_match(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+async fn broadcast_list(session_manager: web::Data<SessionManager>) -> HttpResponse {
+    HttpResponse::Ok()
+        // Read by the web client, which is served from another origin
+        .insert_header(("Access-Control-Allow-Origin", "*"))
+        .json(session_manager.list_broadcasts().await)
+}
+
+async fn broadcast_get(
+    path: web::Path<String>,
+    session_manager: web::Data<SessionManager>,
+) -> HttpResponse {
+    match session_manager.broadcast_info(&path.into_inner()).await {
+        Ok(broadcast) => HttpResponse::Ok()
+            .insert_header(("Access-Control-Allow-Origin", "*"))
+            .json(broadcast),
+        Err(e) => error_response(&e),
+    }
+}
+
+async fn viewer_list(
+    path: web::Path<String>,
+    session_manager: web::Data<SessionManager>,
+) -> HttpResponse {
+    match session_manager.broadcast_viewers(&path.into_inner()).await {
+        Ok(viewers) => HttpResponse::Ok()
+            .insert_header(("Access-Control-Allow-Origin", "*"))
+            .json(viewers),
+        Err(e) => error_response(&e),
+    }
+}
+
+async fn metadata_update(
+    _: Authorized,
+    path: web::Path<String>,
+    metadata: web::Json<BroadcastMetadata>,
+    session_manager: web::Data<SessionManager>,
+) -> HttpResponse {
+    let broadcast = path.into_inner();
+    let metadata = metadata.into_inner();
+
+    match session_manager.update_metadata(&broadcast, metadata.clone()).await {
+        Ok(()) => HttpResponse::Ok().json(metadata),
+        Err(e) => error_response(&e),
+    }
+}
+
+async fn recording_start(
+    _: Authorized,
+    path: web::Path<String>,
+    session_manager: web::Data<SessionManager>,
+) -> HttpResponse {
+    let broadcast = path.into_inner();
+
+    match session_manager.start_recording(&broadcast).await {
+        Ok(recording) => {
+            info!("Broadcast '{}': Recording started through the control API", broadcast);
+            HttpResponse::Created().json(recording)
+        }
+        Err(e) => error_response(&e),
+    }
+}
+
+async fn recording_stop(
+    _: Authorized,
+    path: web::Path<String>,
+    session_manager: web::Data<SessionManager>,
+) -> HttpResponse {
+    let broadcast = path.into_inner();
+
+    match session_manager.stop_recording(&broadcast).await {
+        Ok(recording) => HttpResponse::Ok().json(recording),
+        Err(e) => error_response(&e),
+    }
+}
+
+async fn playout_start(
+    _: Authorized,
+    path: web::Path<String>,
+    source: web::Json<PlayoutSource>,
+    playout_manager: web::Data<PlayoutManager>,
+) -> HttpResponse {
+    let broadcast = path.into_inner();
+    let source = source.into_inner();
+
+    match playout_manager.start(broadcast, source.clone()).await {
+        Ok(_) => HttpResponse::Created().json(source),
+        Err(e) => error_response(&e),
+    }
+}
+
+async fn playout_stop(
+    _: Authorized,
+    path: web::Path<String>,
+    playout_manager: web::Data<PlayoutManager>,
+) -> HttpResponse {
+    match playout_manager.stop(&path.into_inner()).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(e) => error_response(&e),
+    }
+}
+
+async fn subtitles_list(
+    path: web::Path<String>,
+    session_manager: web::Data<SessionManager>,
+) -> HttpResponse {
+    HttpResponse::Ok().json(session_manager.subtitles().tracks(&path.into_inner()).await)
+}
+
+async fn subtitles_load(
+    _: Authorized,
+    path: web::Path<(String, String)>,
+    webvtt: String,
+    session_manager: web::Data<SessionManager>,
+) -> HttpResponse {
+    let (broadcast, language) = path.into_inner();
+
+    match session_manager.subtitles().load(&broadcast, &language, &webvtt).await {
+        Ok(track) => HttpResponse::Ok().json(track),
+        Err(e) => error_response(&e),
+    }
+}
+
+async fn subtitles_remove(
+    _: Authorized,
+    path: web::Path<(String, String)>,
+    session_manager: web::Data<SessionManager>,
+) -> HttpResponse {
+    let (broadcast, language) = path.into_inner();
+
+    match session_manager.subtitles().remove(&broadcast, &language).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(e) => error_response(&e),
+    }
+}
+
+async fn schedule_list(scheduler: web::Data<Scheduler>) -> HttpResponse {
+    HttpResponse::Ok().json(scheduler.list().await)
+}
+
+async fn schedule_add(
+    _: Authorized,
+    screening: web::Json<ScreeningConfig>,
+    scheduler: web::Data<Scheduler>,
+) -> HttpResponse {
+    match scheduler.add(screening.into_inner()).await {
+        Ok(screening) => HttpResponse::Created().json(screening),
+        Err(e) => error_response(&e),
+    }
+}
+
+async fn schedule_cancel(
+    _: Authorized,
+    path: web::Path<String>,
+    scheduler: web::Data<Scheduler>,
+) -> HttpResponse {
+    match scheduler.cancel(&path.into_inner()).await {
+        Ok(screening) => HttpResponse::Ok().json(screening),
+        Err(e) => error_response(&e),
+    }
+}
@@ -0,0 +1,143 @@
+use crate::{
+    components::{ SignalingError, ErrorCode },
+    prelude::*,
+};
+
+use actix_web::{
+    http::{ header, StatusCode },
+    HttpRequest,
+    HttpResponse,
+};
+
+/// The HTTP signaling endpoint a session was created through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpEndpoint {
+    Whip,
+    Whep,
+}
+
+impl HttpEndpoint {
+    /// Path prefix of the endpoint's resources
+    pub fn path(&self) -> &'static str {
+        match self {
+            HttpEndpoint::Whip => "/whip",
+            HttpEndpoint::Whep => "/whep",
+        }
+    }
+}
+
+struct HttpSession {
+    endpoint: HttpEndpoint,
+    broadcast: String,
+    peer_connection: Arc<RTCPeerConnection>,
+}
+
+/// Peer connections created through WHIP and WHEP, indexed by their resource id
+#[derive(Default)]
+pub struct HttpSessions {
+    sessions: Mutex<HashMap<String, HttpSession>>,
+}
+
+impl HttpSessions {
+    /// Store a new session and return its resource URL
+    pub async fn insert(
+        &self,
+        endpoint: HttpEndpoint,
+        broadcast: String,
+        peer_connection: Arc<RTCPeerConnection>
+    ) -> String {
+        let mut sessions = self.sessions.lock().await;
+        let resource = uuid::Uuid::new_v4().to_string();
+        let location = format!("{}/{}/{}", endpoint.path(), broadcast, resource);
+        sessions.insert(resource, HttpSession { endpoint, broadcast, peer_connection });
+        location
+    }
+
+    /// Forget the session of a peer connection that ended without a DELETE, if it has one
+    pub async fn forget(&self, peer_connection: &Weak<RTCPeerConnection>) {
+        let mut sessions = self.sessions.lock().await;
+        sessions.retain(|_, s| !std::ptr::eq(Arc::as_ptr(&s.peer_connection), peer_connection.as_ptr()));
+    }
+
+    /// Remove a session and close its peer connection. Returns false if it does not exist.
+    pub async fn close(&self, endpoint: HttpEndpoint, broadcast: &str, resource: &str) -> bool {
+        let session = {
+            let mut sessions = self.sessions.lock().await;
+            match sessions.get(resource) {
+                Some(s) if s.endpoint == endpoint && s.broadcast == broadcast => sessions.remove(resource),
+                _ => None,
+            }
+        };
+
+        match session {
+            Some(session) => {
+                if let Err(e) = session.peer_connection.close().await {
+                    warn!("Broadcast '{}': Failed to close peer connection: {}", broadcast, e);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Check that the request carries an SDP body and parse it as an offer
+pub fn parse_sdp_offer(req: &HttpRequest, body: String) -> Result<RTCSessionDescription, HttpResponse> {
+    let is_sdp = req.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/sdp"));
+
+    if !is_sdp {
+        return Err(HttpResponse::UnsupportedMediaType().body("Expected Content-Type: application/sdp"));
+    }
+
+    RTCSessionDescription::offer(body)
+        .map_err(|e| error_response(&SignalingError::new(ErrorCode::BadSdp, format!("Invalid SDP offer: {e}")).into()))
+}
+
+/// Build the `201 Created` response carrying the SDP answer and the session's resource URL.
+/// The ICE servers are advertised in `Link` headers, as described in RFC 9725.
+pub fn created_response(
+    location: String,
+    answer: &RTCSessionDescription,
+    ice_servers: &[RTCIceServer]
+) -> HttpResponse {
+    let mut res = HttpResponse::Created();
+    res.insert_header((header::LOCATION, location));
+
+    for server in ice_servers {
+        for url in &server.urls {
+            let link = if server.username.is_empty() {
+                format!("<{}>; rel=\"ice-server\"", url)
+            } else {
+                format!(
+                    "<{}>; rel=\"ice-server\"; username=\"{}\"; credential=\"{}\"; credential-type=\"password\"",
+                    url, server.username, server.credential
+                )
+            };
+            res.append_header((header::LINK, link));
+        }
+    }
+
+    res.content_type("application/sdp").body(answer.sdp.clone())
+}
+
+/// Map a failed request to the HTTP status matching its error code
+pub fn error_response(e: &anyhow::Error) -> HttpResponse {
+    let error = SignalingError::from_anyhow(e);
+
+    let status = match error.code {
+        ErrorCode::NotFound => StatusCode::NOT_FOUND,
+        ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+        ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+        ErrorCode::Conflict | ErrorCode::NameTaken => StatusCode::CONFLICT,
+        ErrorCode::BadSdp | ErrorCode::BadMessage | ErrorCode::UnsupportedVersion => StatusCode::BAD_REQUEST,
+        ErrorCode::Internal => {
+            error!("Failed to handle HTTP signaling request: {:#}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+
+    HttpResponse::build(status).body(error.message)
+}
@@ -0,0 +1,282 @@
+use webrtc::{
+    api::media_engine::{ MIME_TYPE_H264, MIME_TYPE_VP8, MIME_TYPE_VP9 },
+    rtp::packet::Packet,
+};
+
+/// Upper bound on the cached packets, which must fit in a viewer's queue to prime it. With
+/// packets of about 1200 bytes, that is about 1.8 MB per video layer: a 3 s group of pictures up
+/// to 5 Mbps, or 7 s at 2 Mbps. Longer groups of pictures are not cached, new viewers wait for
+/// the next keyframe instead.
+pub const MAX_CACHED_PACKETS: usize = 1536;
+
+/// Video codecs whose keyframes can be recognized from their RTP payload
+#[derive(Debug, Clone, Copy)]
+enum KeyframeCodec {
+    Vp8,
+    Vp9,
+    H264,
+}
+
+impl KeyframeCodec {
+    fn from_mime_type(mime_type: &str) -> Option<Self> {
+        if mime_type.eq_ignore_ascii_case(MIME_TYPE_VP8) {
+            Some(Self::Vp8)
+        } else if mime_type.eq_ignore_ascii_case(MIME_TYPE_VP9) {
+            Some(Self::Vp9)
+        } else if mime_type.eq_ignore_ascii_case(MIME_TYPE_H264) {
+            Some(Self::H264)
+        } else {
+            None
+        }
+    }
+
+    /// Whether the packet is the first one of a keyframe
+    fn is_keyframe_start(&self, payload: &[u8]) -> bool {
+        match self {
+            Self::Vp8 => Self::is_vp8_keyframe_start(payload),
+            Self::Vp9 => Self::is_vp9_keyframe_start(payload),
+            Self::H264 => Self::is_h264_keyframe_start(payload),
+        }
+    }
+
+    /// RFC 7741: start of partition 0, with the VP8 frame header's inverse key frame flag unset
+    fn is_vp8_keyframe_start(payload: &[u8]) -> bool {
+        let Some(&descriptor) = payload.first() else {
+            return false;
+        };
+        let start_of_partition = descriptor & 0x10 != 0;
+        let partition_id = descriptor & 0x07;
+        if !start_of_partition || partition_id != 0 {
+            return false;
+        }
+
+        // Skip the optional fields of the payload descriptor
+        let mut header = 1;
+        if descriptor & 0x80 != 0 {
+            let Some(&extension) = payload.get(1) else {
+                return false;
+            };
+            header = 2;
+
+            if extension & 0x80 != 0 {
+                // Picture ID, 15 bits long when its first bit is set
+                let Some(&picture_id) = payload.get(header) else {
+                    return false;
+                };
+                header += if picture_id & 0x80 != 0 { 2 } else { 1 };
+            }
+            if extension & 0x40 != 0 {
+                header += 1; // TL0PICIDX
+            }
+            if extension & 0x30 != 0 {
+                header += 1; // TID and KEYIDX
+            }
+        }
+
+        payload.get(header).is_some_and(|frame_header| frame_header & 0x01 == 0)
+    }
+
+    /// VP9 payload descriptor: start of a frame that is not inter-picture predicted
+    fn is_vp9_keyframe_start(payload: &[u8]) -> bool {
+        payload.first().is_some_and(|descriptor| descriptor & 0x40 == 0 && descriptor & 0x08 != 0)
+    }
+
+    /// RFC 6184: an SPS or IDR slice, on its own, in a STAP-A or at the start of a FU-A
+    fn is_h264_keyframe_start(payload: &[u8]) -> bool {
+        const NALU_IDR: u8 = 5;
+        const NALU_SPS: u8 = 7;
+        const NALU_STAP_A: u8 = 24;
+        const NALU_FU_A: u8 = 28;
+
+        let Some(&nal_header) = payload.first() else {
+            return false;
+        };
+
+        match nal_header & 0x1F {
+            NALU_IDR | NALU_SPS => true,
+            NALU_STAP_A => {
+                // Aggregated NAL units, each prefixed with its 16-bit size
+                let mut offset = 1;
+                while offset + 2 < payload.len() {
+                    let size = u16::from_be_bytes([payload[offset], payload[offset + 1]]) as usize;
+                    if matches!(payload[offset + 2] & 0x1F, NALU_IDR | NALU_SPS) {
+                        return true;
+                    }
+                    offset += 2 + size;
+                }
+                false
+            }
+            NALU_FU_A => payload.get(1).is_some_and(|fu_header| fu_header & 0x80 != 0 && fu_header & 0x1F == NALU_IDR),
+            _ => false,
+        }
+    }
+}
+
+/// The RTP packets of a video track since its most recent keyframe, used to prime new viewers.
+/// Every packet after the keyframe is kept too, so a viewer primed from the cache decodes the
+/// frames up to the live ones from the right references.
+pub struct KeyframeCache {
+    codec: KeyframeCodec,
+    // Contiguous packets from the start of the last keyframe, empty after a loss until the next one
+    packets: Vec<Packet>,
+}
+
+impl KeyframeCache {
+    /// A cache for tracks of `mime_type`, or None if keyframes of the codec cannot be recognized
+    pub fn new(mime_type: &str) -> Option<Self> {
+        KeyframeCodec::from_mime_type(mime_type).map(|codec| Self {
+            codec,
+            packets: Vec::new(),
+        })
+    }
+
+    /// Add a packet of the track, returning whether it starts a keyframe
+    pub fn push(&mut self, packet: &Packet) -> bool {
+        let contiguous = self.packets.last().is_some_and(|last| {
+            last.header.sequence_number.wrapping_add(1) == packet.header.sequence_number
+        });
+        // An H264 keyframe may span several packets recognized as its start, such as SPS and IDR
+        let same_frame = contiguous && self.packets.last().is_some_and(|last| last.header.timestamp == packet.header.timestamp);
+
+        let keyframe_start = !same_frame && self.codec.is_keyframe_start(&packet.payload);
+        if keyframe_start {
+            self.packets.clear();
+        } else if !contiguous || self.packets.len() >= MAX_CACHED_PACKETS {
+            // A lost or reordered packet would leave viewers primed from the cache with a hole
+            self.packets.clear();
+            return false;
+        }
+
+        self.packets.push(packet.clone());
+        keyframe_start
+    }
+
+    /// The packets from the last keyframe on, empty if none can be used
+    pub fn packets(&self) -> &[Packet] {
+        &self.packets
+    }
+
+    pub fn clear(&mut self) {
+        self.packets.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use webrtc::rtp::header::Header;
+
+    fn packet(sequence_number: u16, timestamp: u32, payload: &[u8]) -> Packet {
+        Packet {
+            header: Header { sequence_number, timestamp, ..Default::default() },
+            payload: Bytes::copy_from_slice(payload),
+        }
+    }
+
+    #[test]
+    fn recognizes_vp8_keyframes() {
+        let vp8 = KeyframeCodec::Vp8;
+        assert!(vp8.is_keyframe_start(&[0x10, 0x00]));
+        // Inter frame, continuation of a partition and a later partition
+        assert!(!vp8.is_keyframe_start(&[0x10, 0x01]));
+        assert!(!vp8.is_keyframe_start(&[0x00, 0x00]));
+        assert!(!vp8.is_keyframe_start(&[0x11, 0x00]));
+        // Extended descriptor with a 15-bit picture ID, TL0PICIDX and KEYIDX
+        assert!(vp8.is_keyframe_start(&[0x90, 0xd0, 0x81, 0x23, 0x05, 0x10, 0x00]));
+        assert!(!vp8.is_keyframe_start(&[0x90, 0xd0, 0x81, 0x23, 0x05, 0x10, 0x01]));
+        // Truncated payloads
+        assert!(!vp8.is_keyframe_start(&[]));
+        assert!(!vp8.is_keyframe_start(&[0x10]));
+        assert!(!vp8.is_keyframe_start(&[0x90, 0x80]));
+    }
+
+    #[test]
+    fn recognizes_vp9_keyframes() {
+        let vp9 = KeyframeCodec::Vp9;
+        assert!(vp9.is_keyframe_start(&[0x08]));
+        assert!(vp9.is_keyframe_start(&[0x88, 0x12]));
+        // Inter-picture predicted, or not the start of the frame
+        assert!(!vp9.is_keyframe_start(&[0x48]));
+        assert!(!vp9.is_keyframe_start(&[0x00]));
+        assert!(!vp9.is_keyframe_start(&[]));
+    }
+
+    #[test]
+    fn recognizes_h264_keyframes() {
+        let h264 = KeyframeCodec::H264;
+        // IDR slice and SPS on their own
+        assert!(h264.is_keyframe_start(&[0x65, 0x88]));
+        assert!(h264.is_keyframe_start(&[0x67, 0x42]));
+        assert!(!h264.is_keyframe_start(&[0x41, 0x9a]));
+        // STAP-A aggregating an SPS, or only a PPS and a non-IDR slice
+        assert!(h264.is_keyframe_start(&[0x18, 0x00, 0x02, 0x67, 0x42, 0x00, 0x02, 0x68, 0xce]));
+        assert!(!h264.is_keyframe_start(&[0x18, 0x00, 0x02, 0x68, 0xce, 0x00, 0x02, 0x41, 0x9a]));
+        // FU-A starting an IDR slice, continuing one, and starting a non-IDR slice
+        assert!(h264.is_keyframe_start(&[0x7c, 0x85, 0x88]));
+        assert!(!h264.is_keyframe_start(&[0x7c, 0x05, 0x88]));
+        assert!(!h264.is_keyframe_start(&[0x7c, 0x81, 0x9a]));
+        assert!(!h264.is_keyframe_start(&[0x7c]));
+    }
+
+    #[test]
+    fn caches_the_group_of_pictures() {
+        let mut cache = KeyframeCache::new(MIME_TYPE_VP8).unwrap();
+
+        // Nothing is cached before the first keyframe
+        assert!(!cache.push(&packet(9, 0, &[0x10, 0x01])));
+        assert!(cache.packets().is_empty());
+
+        assert!(cache.push(&packet(10, 3000, &[0x10, 0x00])));
+        assert!(!cache.push(&packet(11, 3000, &[0x00, 0xff])));
+        assert!(!cache.push(&packet(12, 6000, &[0x10, 0x01])));
+        let sequence_numbers: Vec<u16> = cache.packets().iter().map(|p| p.header.sequence_number).collect();
+        assert_eq!(sequence_numbers, [10, 11, 12]);
+
+        // The next keyframe starts over
+        assert!(cache.push(&packet(13, 9000, &[0x10, 0x00])));
+        assert_eq!(cache.packets().len(), 1);
+    }
+
+    #[test]
+    fn drops_the_group_of_pictures_after_a_loss() {
+        let mut cache = KeyframeCache::new(MIME_TYPE_VP8).unwrap();
+        cache.push(&packet(u16::MAX, 3000, &[0x10, 0x00]));
+        // Sequence numbers wrap around
+        cache.push(&packet(0, 6000, &[0x10, 0x01]));
+        assert_eq!(cache.packets().len(), 2);
+
+        assert!(!cache.push(&packet(2, 9000, &[0x10, 0x01])));
+        assert!(cache.packets().is_empty());
+        assert!(!cache.push(&packet(3, 12000, &[0x10, 0x01])));
+        assert!(cache.packets().is_empty());
+    }
+
+    #[test]
+    fn drops_groups_of_pictures_too_long_to_prime_a_viewer() {
+        let mut cache = KeyframeCache::new(MIME_TYPE_VP8).unwrap();
+        cache.push(&packet(0, 0, &[0x10, 0x00]));
+        for sequence_number in 1..MAX_CACHED_PACKETS as u16 {
+            cache.push(&packet(sequence_number, sequence_number as u32 * 3000, &[0x10, 0x01]));
+        }
+        assert_eq!(cache.packets().len(), MAX_CACHED_PACKETS);
+
+        cache.push(&packet(MAX_CACHED_PACKETS as u16, 0, &[0x10, 0x01]));
+        assert!(cache.packets().is_empty());
+    }
+
+    #[test]
+    fn keeps_h264_keyframes_spanning_several_starts() {
+        let mut cache = KeyframeCache::new(MIME_TYPE_H264).unwrap();
+        assert!(cache.push(&packet(1, 3000, &[0x67, 0x42])));
+        // The IDR slice of the same access unit does not start the cache over
+        assert!(!cache.push(&packet(2, 3000, &[0x65, 0x88])));
+        assert_eq!(cache.packets().len(), 2);
+    }
+
+    #[test]
+    fn only_caches_known_codecs() {
+        assert!(KeyframeCache::new("video/AV1").is_none());
+        assert!(KeyframeCache::new("video/vp8").is_some());
+    }
+}
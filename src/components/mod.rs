@@ -1,14 +1,38 @@
 pub mod signaling_server;
+pub mod protocol;
 pub mod peer_conn_factory;
 pub mod track_manager;
+pub mod keyframe_cache;
+pub mod recorder;
+pub mod playout;
+pub mod scheduler;
 pub mod session_manager;
+pub mod chat;
+pub mod subtitles;
 pub mod broadcast_registry;
+pub mod http_signaling;
+pub mod whip;
+pub mod whep;
+pub mod control_api;
 
 pub use signaling_server::{
     SignalingServer,
-    ClientPayload
+    ClientPayload,
+    SdpMessage,
+    ServerToClientMsg
+};
+pub use protocol::{
+    BroadcastMetadata,
+    ClientMessage,
+    ServerMessage,
+    ErrorCode,
+    SignalingError
 };
 pub use peer_conn_factory::PeerConnectionFactory;
 pub use track_manager::TrackManager;
 pub use session_manager::SessionManager;
-pub use broadcast_registry::BroadcastManager;
\ No newline at end of file
+pub use chat::ChatManager;
+pub use subtitles::SubtitleManager;
+pub use playout::{ PlaybackCommand, PlayoutManager, PlayoutSource };
+pub use scheduler::Scheduler;
+pub use broadcast_registry::{ BroadcastInfo, BroadcastManager, TrackInfo, ViewerInfo };
\ No newline at end of file
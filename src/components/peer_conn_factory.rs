@@ -1,32 +1,144 @@
-use crate::prelude::*;
+use crate::{
+    components::track_manager::ViewerTrack,
+    prelude::*,
+    settings::{ IceServerConfig, NetworkSettings },
+};
 use anyhow::Result;
+use base64::{ prelude::BASE64_STANDARD, Engine };
+use hmac::{ Hmac, Mac };
+use sha1::Sha1;
+use std::time::{ SystemTime, UNIX_EPOCH };
+use webrtc::{
+    api::setting_engine::SettingEngine,
+    ice::{
+        udp_mux::{ UDPMuxDefault, UDPMuxParams },
+        udp_network::{ EphemeralUDP, UDPNetwork },
+    },
+    ice_transport::ice_candidate_type::RTCIceCandidateType,
+    rtcp::{
+        payload_feedbacks::{
+            full_intra_request::FullIntraRequest,
+            receiver_estimated_maximum_bitrate::ReceiverEstimatedMaximumBitrate,
+        },
+        receiver_report::ReceiverReport,
+    },
+    rtp_transceiver::rtp_codec::RTCRtpHeaderExtensionCapability,
+};
+
+/// Username used for generated TURN credentials when none is configured
+const DEFAULT_TURN_USER: &str = "united-cinemas";
 
 pub struct PeerConnectionFactory {
     api: webrtc::api::API,
+    ice_servers: Vec<IceServerConfig>,
 }
 
 impl PeerConnectionFactory {
-    pub async fn new() -> Result<Self> {
+    pub async fn new(ice_servers: Vec<IceServerConfig>, network: &NetworkSettings) -> Result<Self> {
+        for server in &ice_servers {
+            let is_turn = server.urls.iter().any(|url| url.starts_with("turn:") || url.starts_with("turns:"));
+            let has_credential = server.secret.is_some()
+                || (server.username.is_some() && server.credential.is_some());
+
+            if is_turn && !has_credential {
+                bail!("TURN server {:?} needs a username and credential, or a shared secret", server.urls);
+            }
+        }
+
         let mut media_eng = MediaEngine::default();
         media_eng.register_default_codecs()?;
 
+        // Needed to tell apart the layers of simulcast broadcasters
+        for uri in [
+            webrtc::sdp::extmap::SDES_MID_URI,
+            webrtc::sdp::extmap::SDES_RTP_STREAM_ID_URI,
+            webrtc::sdp::extmap::SDES_REPAIR_RTP_STREAM_ID_URI,
+        ] {
+            media_eng.register_header_extension(
+                RTCRtpHeaderExtensionCapability { uri: uri.to_owned() },
+                RTPCodecType::Video,
+                None,
+            )?;
+        }
+
         let mut registry = Registry::new();
         registry = register_default_interceptors(registry, &mut media_eng)?;
 
         let api = APIBuilder::new()
             .with_media_engine(media_eng)
             .with_interceptor_registry(registry)
+            .with_setting_engine(Self::build_setting_engine(network).await?)
             .build();
 
-        Ok(Self { api })
+        Ok(Self { api, ice_servers })
+    }
+
+    async fn build_setting_engine(network: &NetworkSettings) -> Result<SettingEngine> {
+        let mut setting_eng = SettingEngine::default();
+
+        if let Some(port) = network.udp_mux_port {
+            let socket = tokio::net::UdpSocket::bind(("0.0.0.0", port)).await
+                .map_err(|e| anyhow!("Failed to bind UDP mux port {}: {}", port, e))?;
+            info!("Multiplexing WebRTC traffic over UDP port {}", port);
+
+            setting_eng.set_udp_network(UDPNetwork::Muxed(UDPMuxDefault::new(UDPMuxParams::new(socket))));
+        } else if let Some((port_min, port_max)) = network.udp_port_range {
+            let ephemeral = EphemeralUDP::new(port_min, port_max)
+                .map_err(|e| anyhow!("Invalid UDP port range {}-{}: {}", port_min, port_max, e))?;
+
+            setting_eng.set_udp_network(UDPNetwork::Ephemeral(ephemeral));
+        }
+
+        if !network.nat_1to1_ips.is_empty() {
+            setting_eng.set_nat_1to1_ips(network.nat_1to1_ips.clone(), RTCIceCandidateType::Host);
+        }
+
+        setting_eng.set_lite(network.ice_lite);
+
+        Ok(setting_eng)
+    }
+
+    /// The ICE servers to use for a new peer connection, on the server or the client side.
+    /// TURN servers with a shared secret get fresh time-limited credentials (TURN REST API).
+    pub fn ice_servers(&self) -> Vec<RTCIceServer> {
+        self.ice_servers.iter().map(|server| {
+            let (username, credential) = match &server.secret {
+                Some(secret) => Self::generate_turn_credential(
+                    secret,
+                    server.username.as_deref().unwrap_or(DEFAULT_TURN_USER),
+                    server.credential_ttl,
+                ),
+                None => (
+                    server.username.clone().unwrap_or_default(),
+                    server.credential.clone().unwrap_or_default(),
+                ),
+            };
+
+            RTCIceServer { urls: server.urls.clone(), username, credential }
+        }).collect()
+    }
+
+    /// The username is `<expiry timestamp>:<user>` and the credential is `base64(HMAC-SHA1(secret, username))`
+    fn generate_turn_credential(secret: &str, user: &str, ttl: u64) -> (String, String) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        Self::turn_credential(secret, user, now + ttl)
+    }
+
+    /// The TURN REST API credential of `user`, valid until the `expiry` Unix timestamp
+    fn turn_credential(secret: &str, user: &str, expiry: u64) -> (String, String) {
+        let username = format!("{}:{}", expiry, user);
+
+        let mut mac = Hmac::<Sha1>::new_from_slice(secret.as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(username.as_bytes());
+        let credential = BASE64_STANDARD.encode(mac.finalize().into_bytes());
+
+        (username, credential)
     }
 
     pub async fn create_peer_connection(&self) -> Result<Arc<RTCPeerConnection>> {
         let config = RTCConfiguration {
-            ice_servers: vec![RTCIceServer {
-                urls: vec!["stun:stun.l.google.com:19302".to_owned()],
-                ..Default::default()
-            }],
+            ice_servers: self.ice_servers(),
             ..Default::default()
         };
 
@@ -35,22 +147,18 @@ impl PeerConnectionFactory {
 
     pub async fn create_recv_only_peer_connection(
         &self,
-        video_track: Arc<TrackLocalStaticRTP>,
-        audio_track: Arc<TrackLocalStaticRTP>,
+        tracks: &[Arc<ViewerTrack>],
     ) -> Result<Arc<RTCPeerConnection>> {
         let peer_connection = self.create_peer_connection().await?;
 
-        let video_sender = peer_connection
-            .add_track(video_track as Arc<dyn TrackLocal + Send + Sync>)
-            .await?;
-        
-        let audio_sender = peer_connection
-            .add_track(audio_track as Arc<dyn TrackLocal + Send + Sync>)
-            .await?;
+        for track in tracks {
+            let sender = peer_connection
+                .add_track(Arc::clone(&track.local_track) as Arc<dyn TrackLocal + Send + Sync>)
+                .await?;
 
-        // Handle RTCP packets
-        self.spawn_rtcp_handler(video_sender);
-        self.spawn_rtcp_handler(audio_sender);
+            // Handle RTCP packets
+            self.spawn_rtcp_handler(sender, Arc::downgrade(track));
+        }
 
         Ok(peer_connection)
     }
@@ -58,11 +166,61 @@ impl PeerConnectionFactory {
     // Read incoming RTCP packets
     // Before these packets are returned they are processed by interceptors. For things
     // like NACK this needs to be called.
-    fn spawn_rtcp_handler(&self, rtp_sender: Arc<RTCRtpSender>) {
+    // Keyframe requests (PLI and FIR) from the viewer are forwarded to the broadcaster, and its
+    // bandwidth estimates (REMB) and losses (receiver reports) pick its simulcast layer.
+    fn spawn_rtcp_handler(&self, rtp_sender: Arc<RTCRtpSender>, track: Weak<ViewerTrack>) {
         tokio::spawn(async move {
-            let mut rtcp_buf = vec![0u8; 1500];
-            while let Ok((_, _)) = rtp_sender.read(&mut rtcp_buf).await {}
+            // Receiver reports may carry the reception of the viewer's other tracks too
+            let ssrc = rtp_sender.get_parameters().await.encodings.first().map(|e| e.ssrc);
+
+            while let Ok((packets, _)) = rtp_sender.read_rtcp().await {
+                // The viewer left the broadcast
+                let Some(track) = track.upgrade() else {
+                    break;
+                };
+
+                let wants_keyframe = packets.iter().any(|p| {
+                    p.as_any().is::<PictureLossIndication>() || p.as_any().is::<FullIntraRequest>()
+                });
+                if wants_keyframe {
+                    track.request_keyframe().await;
+                }
+
+                for packet in &packets {
+                    if let Some(remb) = packet.as_any().downcast_ref::<ReceiverEstimatedMaximumBitrate>() {
+                        track.report_estimate(remb.bitrate as u64).await;
+                    } else if let Some(report) = packet.as_any().downcast_ref::<ReceiverReport>() {
+                        for reception in report.reports.iter().filter(|r| Some(r.ssrc) == ssrc) {
+                            track.report_loss(reception.fraction_lost).await;
+                        }
+                    }
+                }
+            }
             Result::<()>::Ok(())
         });
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signs_turn_credentials_with_the_shared_secret() {
+        let (username, credential) = PeerConnectionFactory::turn_credential("north-secret", "united-cinemas", 1_700_086_400);
+        assert_eq!(username, "1700086400:united-cinemas");
+        assert_eq!(credential, "XMPaLjzMQiOoIZIAsrqnXMsZG/I=");
+    }
+
+    #[test]
+    fn generates_turn_credentials_expiring_after_their_ttl() {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let (username, credential) = PeerConnectionFactory::generate_turn_credential("north-secret", "viewer", 3600);
+
+        let (expiry, user) = username.split_once(':').unwrap();
+        let expiry: u64 = expiry.parse().unwrap();
+        assert!((now + 3600..=now + 3601).contains(&expiry));
+        assert_eq!(user, "viewer");
+        assert_eq!(credential, PeerConnectionFactory::turn_credential("north-secret", "viewer", expiry).1);
+    }
+}
@@ -0,0 +1,796 @@
+use crate::{
+    components::{
+        broadcast_registry::BroadcasterSession,
+        track_manager::RelayTrack,
+        BroadcastManager,
+        BroadcastMetadata,
+        ErrorCode,
+        ServerMessage,
+        SignalingError,
+    },
+    prelude::*,
+    settings::PlayoutSettings,
+};
+use std::{ collections::VecDeque, fs::File, io::{ self, BufReader, Read }, path::{ Component, Path }, time::Duration };
+
+use anyhow::Result;
+use bytes::{ Bytes, BytesMut };
+use serde::{ Deserialize, Serialize };
+use tokio::{ sync::watch, task::JoinSet, time::Instant };
+use uuid::Uuid;
+use webrtc::{
+    api::media_engine::{ MIME_TYPE_OPUS, MIME_TYPE_VP8, MIME_TYPE_VP9 },
+    media::io::ivf_reader::IVFReader,
+    rtp::{
+        codecs::{ opus::OpusPayloader, vp8::Vp8Payloader, vp9::Vp9Payloader },
+        packetizer::{ new_packetizer, Packetizer, Payloader },
+        sequence::new_random_sequencer,
+    },
+    rtp_transceiver::rtp_codec::RTCRtpCodecCapability,
+};
+
+/// Size of the RTP packets sent by a playout
+const PLAYOUT_MTU: usize = 1200;
+
+/// Frames read ahead of a playout track, so disk reads happen on a blocking thread
+const PLAYOUT_READ_AHEAD: usize = 16;
+
+/// How often a playout checks that its broadcast was not taken over by a broadcaster
+const PLAYOUT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Media files published by a playout, relative to the playout directory
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PlayoutSource {
+    /// IVF file with VP8 or VP9 video
+    #[serde(default)]
+    pub video: Option<PathBuf>,
+    /// Ogg file with Opus audio
+    #[serde(default)]
+    pub audio: Option<PathBuf>,
+    /// Start over at the end of the files instead of ending the broadcast
+    #[serde(default, rename = "loop")]
+    pub looping: bool,
+}
+
+/// Playback state of a playout, as reported to its viewers
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct PlaybackState {
+    pub playing: bool,
+    // Media time in seconds
+    pub position: f64,
+}
+
+/// A command of the host of a watch party
+#[derive(Debug, Clone, Copy)]
+pub enum PlaybackCommand {
+    Play,
+    Pause,
+    // Media time to resume from, in seconds
+    Seek(f64),
+}
+
+/// Playback position shared by the tracks of a playout
+#[derive(Debug, Clone, Copy)]
+struct Timeline {
+    // Media time at `anchor`
+    position: Duration,
+    anchor: Instant,
+    playing: bool,
+    // Bumped on every seek, so the tracks read their files again from `position`
+    seek: u64,
+}
+
+impl Timeline {
+    fn position(&self) -> Duration {
+        match self.playing {
+            true => self.position + self.anchor.elapsed(),
+            false => self.position,
+        }
+    }
+
+    /// When the frame at media time `at` is due
+    fn due(&self, at: Duration) -> Instant {
+        self.anchor + at.saturating_sub(self.position)
+    }
+
+    fn state(&self) -> PlaybackState {
+        PlaybackState { playing: self.playing, position: self.position().as_secs_f64() }
+    }
+}
+
+/// The Opus packets of an Ogg file. `OggReader` only returns whole pages, while a page can hold
+/// several packets and a packet can continue on the next page, so the pages are split here along
+/// their segment table.
+struct OggPacketReader<R> {
+    reader: R,
+    // Packets of the last page not returned yet
+    packets: VecDeque<Bytes>,
+    // Start of a packet that continues on the next page
+    partial: BytesMut,
+}
+
+impl<R: Read> OggPacketReader<R> {
+    fn new(reader: R) -> Self {
+        Self { reader, packets: VecDeque::new(), partial: BytesMut::new() }
+    }
+
+    /// The next packet, None at the end of the file
+    fn next_packet(&mut self) -> Result<Option<Bytes>> {
+        loop {
+            if let Some(packet) = self.packets.pop_front() {
+                return Ok(Some(packet));
+            }
+            if !self.read_page()? {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Read a page into its packets. Returns false at the end of the file.
+    fn read_page(&mut self) -> Result<bool> {
+        let mut header = [0u8; 27];
+        match self.reader.read_exact(&mut header) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+            result => result?,
+        }
+        if &header[..4] != b"OggS" {
+            bail!("Invalid Ogg page");
+        }
+
+        let mut lacing = vec![0u8; header[26] as usize];
+        self.reader.read_exact(&mut lacing)?;
+        let mut payload = BytesMut::zeroed(lacing.iter().map(|&size| size as usize).sum());
+        self.reader.read_exact(&mut payload)?;
+
+        // The checksum is computed with its own field zeroed
+        let checksum = u32::from_le_bytes([header[22], header[23], header[24], header[25]]);
+        header[22..26].fill(0);
+        if ogg_crc(&[&header, &lacing, &payload]) != checksum {
+            bail!("Ogg page checksum mismatch");
+        }
+
+        // A packet ends with the first segment shorter than 255 bytes
+        let mut size = 0;
+        for &segment in &lacing {
+            size += segment as usize;
+            if segment < 255 {
+                self.partial.extend_from_slice(&payload.split_to(size));
+                self.packets.push_back(self.partial.split().freeze());
+                size = 0;
+            }
+        }
+        self.partial.extend_from_slice(&payload);
+        Ok(true)
+    }
+}
+
+/// CRC-32 of an Ogg page: polynomial 0x04c11db7, without reflection, starting from 0
+fn ogg_crc(parts: &[&[u8]]) -> u32 {
+    let mut crc = 0u32;
+    for &byte in parts.iter().flat_map(|part| part.iter()) {
+        crc ^= (byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 { (crc << 1) ^ 0x04c1_1db7 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// Duration of an Opus packet in 48 kHz samples, from its TOC byte (RFC 6716, section 3.1)
+fn opus_packet_samples(packet: &[u8]) -> u64 {
+    let Some(&toc) = packet.first() else {
+        return 0;
+    };
+
+    let config = (toc >> 3) as usize;
+    let frame = match config {
+        // SILK
+        0..=11 => [480, 960, 1920, 2880][config % 4],
+        // Hybrid
+        12..=15 => [480, 960][config % 2],
+        // CELT
+        _ => [120, 240, 480, 960][config % 4],
+    };
+    let frames = match toc & 0x03 {
+        0 => 1,
+        1 | 2 => 2,
+        // The frame count follows the TOC byte
+        _ => packet.get(1).map_or(0, |&count| count & 0x3f) as u64,
+    };
+    frame * frames
+}
+
+/// A media file read frame by frame
+enum MediaReader {
+    Ivf {
+        reader: IVFReader<BufReader<File>>,
+        // Seconds per unit of the frame timestamps
+        timebase: f64,
+        end: Duration,
+    },
+    Ogg {
+        reader: OggPacketReader<BufReader<File>>,
+        // Duration of the packets read so far, in 48 kHz samples
+        position: u64,
+    },
+}
+
+impl MediaReader {
+    fn open(path: &Path, kind: RTPCodecType) -> Result<Self> {
+        let file = BufReader::new(File::open(path)?);
+
+        if kind == RTPCodecType::Video {
+            let (reader, header) = IVFReader::new(file)?;
+            let timebase = header.timebase_numerator as f64 / header.timebase_denominator.max(1) as f64;
+            Ok(Self::Ivf { reader, timebase, end: Duration::ZERO })
+        } else {
+            let mut reader = OggPacketReader::new(file);
+            if !reader.next_packet()?.is_some_and(|packet| packet.starts_with(b"OpusHead")) {
+                bail!("Not an Ogg Opus file");
+            }
+            Ok(Self::Ogg { reader, position: 0 })
+        }
+    }
+
+    /// The codec of an IVF file's video
+    fn video_mime_type(path: &Path) -> Result<&'static str> {
+        let (_, header) = IVFReader::new(BufReader::new(File::open(path)?))?;
+        match &header.four_cc {
+            b"VP80" => Ok(MIME_TYPE_VP8),
+            b"VP90" => Ok(MIME_TYPE_VP9),
+            four_cc => bail!("Unsupported IVF codec '{}'", String::from_utf8_lossy(four_cc)),
+        }
+    }
+
+    /// The next frame and the time it is due at from the start of the file, None at the end.
+    /// For Opus, every packet is a frame of its own.
+    fn next_frame(&mut self) -> Option<(Bytes, Duration)> {
+        match self {
+            Self::Ivf { reader, timebase, end } => {
+                let (frame, header) = reader.parse_next_frame().ok()?;
+                let time = Duration::from_secs_f64(header.timestamp as f64 * *timebase);
+                *end = time + Duration::from_secs_f64(*timebase);
+                Some((frame.freeze(), time))
+            }
+            Self::Ogg { reader, position } => loop {
+                let packet = reader.next_packet().ok()??;
+                // The comment header carries no audio
+                if packet.starts_with(b"OpusTags") {
+                    continue;
+                }
+
+                let time = Duration::from_secs_f64(*position as f64 / 48000.0);
+                *position += opus_packet_samples(&packet);
+                return Some((packet, time));
+            },
+        }
+    }
+
+    /// Duration of the frames read so far
+    fn end(&self) -> Duration {
+        match self {
+            Self::Ivf { end, .. } => *end,
+            Self::Ogg { position, .. } => Duration::from_secs_f64(*position as f64 / 48000.0),
+        }
+    }
+}
+
+/// The frames of a track's file in media time, over every pass of a looping playout
+struct MediaCursor {
+    path: PathBuf,
+    kind: RTPCodecType,
+    looping: bool,
+    reader: Option<MediaReader>,
+    // Media time at which the current pass over the file started
+    offset: Duration,
+}
+
+impl MediaCursor {
+    fn new(path: PathBuf, kind: RTPCodecType, looping: bool) -> Self {
+        Self { path, kind, looping, reader: None, offset: Duration::ZERO }
+    }
+
+    /// The next frame and its media time, None at the end of a playout that does not loop
+    fn next_frame(&mut self) -> Result<Option<(Bytes, Duration)>> {
+        loop {
+            let reader = match &mut self.reader {
+                Some(reader) => reader,
+                None => self.reader.insert(MediaReader::open(&self.path, self.kind)?),
+            };
+            if let Some((frame, time)) = reader.next_frame() {
+                return Ok(Some((frame, self.offset + time)));
+            }
+
+            // An empty file would loop without ever waiting
+            if !self.looping || reader.end().is_zero() {
+                return Ok(None);
+            }
+            self.offset += reader.end();
+            self.reader = None;
+        }
+    }
+
+    /// A cursor over the same file, from its start
+    fn restart(&self) -> Self {
+        Self::new(self.path.clone(), self.kind, self.looping)
+    }
+}
+
+/// The frames of a track's file, read ahead on a blocking thread so the files never block the
+/// runtime. The thread stops once the reader is dropped.
+struct FrameReader {
+    frames: mpsc::Receiver<Result<(Bytes, Duration)>>,
+}
+
+impl FrameReader {
+    /// Read the file of `cursor` from its start, skipping the frames before `from`, and for video
+    /// also those up to the next keyframe
+    fn spawn(mut cursor: MediaCursor, mime_type: String, from: Option<Duration>) -> Self {
+        let (frames_tx, frames) = mpsc::channel(PLAYOUT_READ_AHEAD);
+
+        tokio::task::spawn_blocking(move || {
+            let mut skip_until = from;
+            loop {
+                let (frame, at) = match cursor.next_frame() {
+                    Ok(Some(frame)) => frame,
+                    Ok(None) => return,
+                    Err(e) => {
+                        let _ = frames_tx.blocking_send(Err(e));
+                        return;
+                    }
+                };
+
+                if let Some(position) = skip_until {
+                    let is_start = cursor.kind != RTPCodecType::Video || is_keyframe(&mime_type, &frame);
+                    if at < position || !is_start {
+                        continue;
+                    }
+                    skip_until = None;
+                }
+
+                if frames_tx.blocking_send(Ok((frame, at))).is_err() {
+                    return;
+                }
+            }
+        });
+
+        Self { frames }
+    }
+
+    /// The next frame and its media time, None at the end of a playout that does not loop
+    async fn next_frame(&mut self) -> Result<Option<(Bytes, Duration)>> {
+        self.frames.recv().await.transpose()
+    }
+}
+
+/// Whether an IVF frame is a keyframe, which video resumes from after a seek
+fn is_keyframe(mime_type: &str, frame: &[u8]) -> bool {
+    let Some(&header) = frame.first() else {
+        return false;
+    };
+
+    match mime_type {
+        // The frame tag's first bit is 0 for keyframes
+        MIME_TYPE_VP8 => header & 0x01 == 0,
+        // The uncompressed header starts with the frame marker, the profile, a reserved bit for
+        // profile 3, show_existing_frame, then frame_type which is 0 for keyframes
+        MIME_TYPE_VP9 => {
+            let profile = ((header >> 5) & 1) | ((header >> 3) & 2);
+            let show_existing_frame = if profile == 3 { 2 } else { 3 };
+            (header >> show_existing_frame) & 1 == 0 && (header >> (show_existing_frame - 1)) & 1 == 0
+        }
+        _ => true,
+    }
+}
+
+/// A running playout, stopped when dropped
+struct PlayoutHandle {
+    // Broadcaster id the playout registered its broadcast with
+    id: String,
+    stop: watch::Sender<bool>,
+    timeline: watch::Sender<Timeline>,
+}
+
+/// Publishes media files from disk as broadcasts, so a channel can run without any broadcaster
+/// online. The files are packetized at their own pace, the same way `TrackLocalStaticSample`
+/// does, but into relay tracks: viewers subscribe to the relay tracks of a broadcast, with their
+/// keyframe cache and sequence rewriting, while a `TrackLocalStaticSample` is bound to the peer
+/// connections it is added to. A playout thus looks like any other broadcaster to its viewers.
+pub struct PlayoutManager {
+    broadcast_manager: Arc<BroadcastManager>,
+    settings: PlayoutSettings,
+    playouts: Arc<Mutex<HashMap<String, PlayoutHandle>>>,
+}
+
+impl PlayoutManager {
+    pub fn new(broadcast_manager: Arc<BroadcastManager>, settings: PlayoutSettings) -> Self {
+        Self {
+            broadcast_manager,
+            settings,
+            playouts: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Path of a file in the playout directory, which clients cannot escape
+    async fn resolve(&self, file: &Path) -> Result<PathBuf> {
+        if file.components().any(|c| !matches!(c, Component::Normal(_))) {
+            bail!(SignalingError::new(ErrorCode::BadMessage, format!("Invalid playout file '{}'", file.display())));
+        }
+
+        let path = self.settings.directory.join(file);
+        if !fs::metadata(&path).await.is_ok_and(|metadata| metadata.is_file()) {
+            bail!(SignalingError::new(ErrorCode::NotFound, format!("Playout file '{}' not found", file.display())));
+        }
+        Ok(path)
+    }
+
+    /// Publish the files of `source` as the broadcast `broadcast`, returning the playout's id.
+    /// Fails with `conflict` if the broadcast is already live.
+    pub async fn start(&self, broadcast: String, source: PlayoutSource) -> Result<String> {
+        let video = match source.video.as_deref() {
+            Some(file) => Some(self.resolve(file).await?),
+            None => None,
+        };
+        let audio = match source.audio.as_deref() {
+            Some(file) => Some(self.resolve(file).await?),
+            None => None,
+        };
+        if video.is_none() && audio.is_none() {
+            bail!(SignalingError::new(ErrorCode::BadMessage, "A playout needs a video or an audio file"));
+        }
+
+        let mut playouts = self.playouts.lock().await;
+        if playouts.contains_key(&broadcast) || self.broadcast_manager.get_broadcast(&broadcast).await.is_some() {
+            bail!(SignalingError::new(ErrorCode::Conflict, format!("Broadcast '{}' is already live", broadcast)));
+        }
+
+        let mut media = Vec::new();
+        if let Some(path) = video {
+            let header_path = path.clone();
+            let mime_type = tokio::task::spawn_blocking(move || MediaReader::video_mime_type(&header_path)).await?
+                .map_err(|e| SignalingError::new(ErrorCode::BadMessage, format!("Invalid video file: {e}")))?;
+            let codec = RTCRtpCodecCapability {
+                mime_type: mime_type.to_owned(),
+                clock_rate: 90000,
+                ..Default::default()
+            };
+            media.push((path, RelayTrack::new(broadcast.clone(), codec, RTPCodecType::Video, "0".to_owned(), String::new())));
+        }
+        if let Some(path) = audio {
+            let codec = RTCRtpCodecCapability {
+                mime_type: MIME_TYPE_OPUS.to_owned(),
+                clock_rate: 48000,
+                channels: 2,
+                sdp_fmtp_line: "minptime=10;useinbandfec=1".to_owned(),
+                ..Default::default()
+            };
+            media.push((path, RelayTrack::new(broadcast.clone(), codec, RTPCodecType::Audio, "1".to_owned(), String::new())));
+        }
+
+        // Every track of the playout is paced against the same timeline
+        let (stop_tx, stop_rx) = watch::channel(false);
+        let start = Instant::now();
+        let (timeline_tx, timeline_rx) = watch::channel(Timeline {
+            position: Duration::ZERO,
+            anchor: start,
+            playing: true,
+            seek: 0,
+        });
+        let mut tasks = JoinSet::new();
+        let mut tracks = Vec::new();
+        for (path, track) in media {
+            let track = Arc::new(track);
+            let source_id = track.attach_source(Weak::new(), 0).await;
+            tasks.spawn(Self::play(
+                broadcast.clone(),
+                Arc::clone(&track),
+                source_id,
+                MediaCursor::new(path, track.kind, source.looping),
+                start,
+                timeline_rx.clone(),
+                stop_rx.clone(),
+            ));
+            tracks.push(track);
+        }
+
+        let id = Uuid::new_v4().to_string();
+        let broadcaster = BroadcasterSession { id: id.clone(), peer_connection: None, notifier: None };
+        self.broadcast_manager.register_broadcast(broadcast.clone(), broadcaster, tracks, BroadcastMetadata::default()).await?;
+        playouts.insert(broadcast.clone(), PlayoutHandle { id: id.clone(), stop: stop_tx, timeline: timeline_tx });
+        info!("Broadcast '{}': Playout started", broadcast);
+
+        self.spawn_supervisor(broadcast, id.clone(), tasks);
+        Ok(id)
+    }
+
+    /// Stop the playout of a broadcast, failing with `not_found` if there is none
+    pub async fn stop(&self, broadcast: &str) -> Result<()> {
+        if !self.remove(broadcast, None).await {
+            bail!(SignalingError::new(ErrorCode::NotFound, format!("No playout for broadcast '{}'", broadcast)));
+        }
+        Ok(())
+    }
+
+    /// Stop the playout `id` of a broadcast. Returns false if it already ended.
+    pub async fn stop_playout(&self, broadcast: &str, id: &str) -> bool {
+        self.remove(broadcast, Some(id)).await
+    }
+
+    /// The playback state of a broadcast's playout, None if the broadcast is not played out
+    pub async fn playback(&self, broadcast: &str) -> Option<PlaybackState> {
+        self.playouts.lock().await.get(broadcast).map(|p| p.timeline.borrow().state())
+    }
+
+    /// Play, pause or seek the playout `id` of a broadcast, and push its new playback state
+    /// to the broadcast's viewers
+    pub async fn control(&self, broadcast: &str, id: &str, command: PlaybackCommand) -> Result<PlaybackState> {
+        let state = {
+            let playouts = self.playouts.lock().await;
+            let Some(playout) = playouts.get(broadcast).filter(|p| p.id == id) else {
+                bail!(SignalingError::new(ErrorCode::NotFound, format!("No playout for broadcast '{}'", broadcast)));
+            };
+
+            let mut timeline = *playout.timeline.borrow();
+            timeline.position = timeline.position();
+            timeline.anchor = Instant::now();
+            match command {
+                PlaybackCommand::Play => timeline.playing = true,
+                PlaybackCommand::Pause => timeline.playing = false,
+                PlaybackCommand::Seek(position) => {
+                    if !position.is_finite() || position < 0.0 {
+                        bail!(SignalingError::new(ErrorCode::BadMessage, format!("Invalid position {}", position)));
+                    }
+                    timeline.position = Duration::from_secs_f64(position);
+                    timeline.seek += 1;
+                }
+            }
+
+            playout.timeline.send_replace(timeline);
+            timeline.state()
+        };
+
+        debug!("Broadcast '{}': Playback {:?}, now {} at {:.1}s",
+               broadcast, command, if state.playing { "playing" } else { "paused" }, state.position);
+        self.broadcast_manager.notify_viewers(broadcast, ServerMessage::Playback {
+            playing: state.playing,
+            position: state.position,
+        }).await;
+        Ok(state)
+    }
+
+    /// Whether the playout `id` still publishes a broadcast
+    pub async fn is_playing(&self, broadcast: &str, id: &str) -> bool {
+        self.playouts.lock().await.get(broadcast).is_some_and(|p| p.id == id)
+    }
+
+    /// Stop the playout of a broadcast, if it is `id` when given, and unregister its broadcast
+    async fn remove(&self, broadcast: &str, id: Option<&str>) -> bool {
+        let playout = {
+            let mut playouts = self.playouts.lock().await;
+            match playouts.get(broadcast) {
+                Some(p) if id.is_none_or(|id| p.id == id) => playouts.remove(broadcast),
+                _ => None,
+            }
+        };
+
+        let Some(playout) = playout else {
+            return false;
+        };
+        let _ = playout.stop.send(true);
+        self.broadcast_manager.unregister_broadcast(broadcast, &playout.id).await;
+        true
+    }
+
+    /// Wait for the tracks of a playout to finish, then unregister its broadcast.
+    /// A playout whose broadcast was taken over by a broadcaster stops.
+    fn spawn_supervisor(&self, broadcast: String, id: String, mut tasks: JoinSet<()>) {
+        let broadcast_manager = Arc::clone(&self.broadcast_manager);
+        let playouts = Arc::clone(&self.playouts);
+
+        tokio::spawn(async move {
+            let mut check = tokio::time::interval(PLAYOUT_CHECK_INTERVAL);
+            loop {
+                tokio::select! {
+                    task = tasks.join_next() => if task.is_none() {
+                        break;
+                    },
+                    _ = check.tick() => if !broadcast_manager.is_broadcaster(&broadcast, &id).await {
+                        info!("Broadcast '{}': Taken over, stopping playout", broadcast);
+                        tasks.abort_all();
+                        break;
+                    },
+                }
+            }
+
+            {
+                let mut playouts = playouts.lock().await;
+                if playouts.get(&broadcast).is_some_and(|p| p.id == id) {
+                    playouts.remove(&broadcast);
+                }
+            }
+            broadcast_manager.unregister_broadcast(&broadcast, &id).await;
+            info!("Broadcast '{}': Playout ended", broadcast);
+        });
+    }
+
+    /// Packetize the frames of a file into `track`, each when the timeline reaches it.
+    /// After a seek, video resumes from the first keyframe past the new position.
+    async fn play(
+        broadcast: String,
+        track: Arc<RelayTrack>,
+        source: u64,
+        cursor: MediaCursor,
+        epoch: Instant,
+        mut timeline: watch::Receiver<Timeline>,
+        mut stop: watch::Receiver<bool>
+    ) {
+        let clock_rate = track.codec.clock_rate;
+        let payloader: Box<dyn Payloader + Send + Sync> = match track.codec.mime_type.as_str() {
+            MIME_TYPE_VP8 => Box::<Vp8Payloader>::default(),
+            MIME_TYPE_VP9 => Box::<Vp9Payloader>::default(),
+            _ => Box::new(OpusPayloader),
+        };
+        let mut packetizer = new_packetizer(PLAYOUT_MTU, 0, 0, payloader, Box::new(new_random_sequencer()), clock_rate);
+
+        let mime_type = track.codec.mime_type.clone();
+        let mut reader = FrameReader::spawn(cursor.restart(), mime_type.clone(), None);
+        let mut seek = 0;
+        // Frame held back by a pause
+        let mut pending = None;
+
+        loop {
+            let state = *timeline.borrow_and_update();
+            if state.seek != seek {
+                seek = state.seek;
+                reader = FrameReader::spawn(cursor.restart(), mime_type.clone(), Some(state.position));
+                pending = None;
+            }
+
+            if !state.playing {
+                tokio::select! {
+                    changed = timeline.changed() => if changed.is_err() {
+                        return;
+                    },
+                    _ = stop.changed() => return,
+                }
+                continue;
+            }
+
+            let (frame, at) = match pending.take() {
+                Some(frame) => frame,
+                // Seeking far into a file reads many frames, which a new seek or a stop need not wait for
+                None => tokio::select! {
+                    frame = reader.next_frame() => match frame {
+                        Ok(Some(frame)) => frame,
+                        Ok(None) => {
+                            debug!("Broadcast '{}': Reached the end of {}", broadcast, cursor.path.display());
+                            return;
+                        }
+                        Err(e) => {
+                            warn!("Broadcast '{}': Failed to read playout file {}: {}", broadcast, cursor.path.display(), e);
+                            return;
+                        }
+                    },
+                    changed = timeline.changed() => {
+                        if changed.is_err() {
+                            return;
+                        }
+                        continue;
+                    }
+                    _ = stop.changed() => return,
+                },
+            };
+
+            let due = state.due(at);
+            tokio::select! {
+                _ = tokio::time::sleep_until(due) => {}
+                changed = timeline.changed() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                    pending = Some((frame, at));
+                    continue;
+                }
+                _ = stop.changed() => return,
+            }
+
+            // Timestamps follow the wall clock, so they keep increasing across pauses and seeks
+            let timestamp = (due.saturating_duration_since(epoch).as_secs_f64() * clock_rate as f64) as u64 as u32;
+            let packets = match packetizer.packetize(&frame, 0) {
+                Ok(packets) => packets,
+                Err(e) => {
+                    debug!("Broadcast '{}': Failed to packetize frame of {}: {}", broadcast, cursor.path.display(), e);
+                    continue;
+                }
+            };
+            for mut packet in packets {
+                packet.header.timestamp = timestamp;
+                track.write_rtp(source, &mut packet).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use webrtc::{ media::io::{ ogg_writer::OggWriter, Writer }, rtp::packet::Packet };
+
+    /// An Ogg page of `segments`, with a valid checksum
+    fn page(segments: &[u8], payload: &[u8]) -> Vec<u8> {
+        let mut header = [0u8; 27];
+        header[..4].copy_from_slice(b"OggS");
+        header[26] = segments.len() as u8;
+        let checksum = ogg_crc(&[&header, segments, payload]);
+        header[22..26].copy_from_slice(&checksum.to_le_bytes());
+        [&header, segments, payload].concat()
+    }
+
+    fn packets(data: Vec<u8>) -> Result<Vec<Bytes>> {
+        let mut reader = OggPacketReader::new(Cursor::new(data));
+        let mut packets = Vec::new();
+        while let Some(packet) = reader.next_packet()? {
+            packets.push(packet);
+        }
+        Ok(packets)
+    }
+
+    #[test]
+    fn reads_packets_of_ogg_writer() {
+        let mut file = Cursor::new(Vec::new());
+        let mut writer = OggWriter::new(&mut file, 48000, 2).unwrap();
+        for (timestamp, payload) in [(960, &[0xfc, 1, 2][..]), (1920, &[0xfc, 3][..])] {
+            let mut packet = Packet { payload: Bytes::copy_from_slice(payload), ..Default::default() };
+            packet.header.timestamp = timestamp;
+            writer.write_rtp(&packet).unwrap();
+        }
+        drop(writer);
+
+        let packets = packets(file.into_inner()).unwrap();
+        assert_eq!(packets.len(), 4);
+        assert!(packets[0].starts_with(b"OpusHead"));
+        assert!(packets[1].starts_with(b"OpusTags"));
+        assert_eq!(&packets[2][..], &[0xfc, 1, 2]);
+        assert_eq!(&packets[3][..], &[0xfc, 3]);
+    }
+
+    #[test]
+    fn splits_pages_into_packets() {
+        let payload: Vec<u8> = (0..=255u8).cycle().take(3 + 300 + 255).collect();
+        // Packets of 3 and 300 bytes, then one that continues on the next page
+        let mut data = page(&[3, 255, 45, 255], &payload);
+        data.extend(page(&[10], &[7; 10]));
+
+        let packets = packets(data).unwrap();
+        assert_eq!(packets.len(), 3);
+        assert_eq!(&packets[0][..], &payload[..3]);
+        assert_eq!(&packets[1][..], &payload[3..303]);
+        assert_eq!(packets[2].len(), 265);
+        assert_eq!(&packets[2][..255], &payload[303..]);
+        assert_eq!(&packets[2][255..], &[7; 10]);
+    }
+
+    #[test]
+    fn rejects_corrupted_pages() {
+        let mut data = page(&[3], &[1, 2, 3]);
+        *data.last_mut().unwrap() ^= 0xff;
+        assert!(packets(data).is_err());
+
+        assert!(packets(b"RIFF".repeat(8)).is_err());
+    }
+
+    #[test]
+    fn opus_packet_durations() {
+        // CELT, 20 ms
+        assert_eq!(opus_packet_samples(&[31 << 3]), 960);
+        // SILK, 60 ms
+        assert_eq!(opus_packet_samples(&[3 << 3]), 2880);
+        // Hybrid, 10 ms, two frames
+        assert_eq!(opus_packet_samples(&[(14 << 3) | 1]), 960);
+        // CELT, 2.5 ms, with the frame count in the second byte
+        assert_eq!(opus_packet_samples(&[(16 << 3) | 3, 0x80 | 6]), 720);
+        assert_eq!(opus_packet_samples(&[]), 0);
+    }
+}
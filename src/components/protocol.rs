@@ -0,0 +1,327 @@
+use crate::prelude::*;
+
+use serde::{ Deserialize, Serialize };
+
+/// Version of the JSON signaling protocol spoken over the WebSocket
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Longest metadata fields of a broadcast, in characters
+const METADATA_MAX_TITLE_LENGTH: usize = 200;
+const METADATA_MAX_DESCRIPTION_LENGTH: usize = 5000;
+const METADATA_MAX_POSTER_LENGTH: usize = 2048;
+const METADATA_MAX_TAG_LENGTH: usize = 32;
+const METADATA_MAX_LANGUAGE_LENGTH: usize = 35;
+const METADATA_MAX_AGE_RATING_LENGTH: usize = 16;
+
+/// Most tags a broadcast can have
+const METADATA_MAX_TAGS: usize = 20;
+
+/// Description of a broadcast, attached by its broadcaster and shown to viewers
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BroadcastMetadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    // URL of the poster image
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub poster: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    // Spoken language, as a language tag such as `en` or `pt-BR`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    // Age rating, such as `PG-13`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub age_rating: Option<String>,
+}
+
+impl BroadcastMetadata {
+    /// Check the fields against their limits, failing with `bad_message`
+    pub fn validate(&self) -> Result<(), SignalingError> {
+        let check = |field: &str, value: Option<&str>, max_length: usize| match value {
+            Some(value) if value.chars().count() > max_length => Err(SignalingError::new(
+                ErrorCode::BadMessage,
+                format!("The broadcast {field} is limited to {max_length} characters"),
+            )),
+            _ => Ok(()),
+        };
+
+        check("title", self.title.as_deref(), METADATA_MAX_TITLE_LENGTH)?;
+        check("description", self.description.as_deref(), METADATA_MAX_DESCRIPTION_LENGTH)?;
+        check("poster", self.poster.as_deref(), METADATA_MAX_POSTER_LENGTH)?;
+        check("language", self.language.as_deref(), METADATA_MAX_LANGUAGE_LENGTH)?;
+        check("age rating", self.age_rating.as_deref(), METADATA_MAX_AGE_RATING_LENGTH)?;
+        for tag in &self.tags {
+            check("tag", Some(tag), METADATA_MAX_TAG_LENGTH)?;
+        }
+
+        if self.tags.len() > METADATA_MAX_TAGS {
+            return Err(SignalingError::new(
+                ErrorCode::BadMessage,
+                format!("A broadcast has at most {METADATA_MAX_TAGS} tags"),
+            ));
+        }
+        // The poster is shown by clients as an image, so only web URLs are accepted
+        if let Some(poster) = self.poster.as_deref().filter(|p| !p.starts_with("https://") && !p.starts_with("http://")) {
+            return Err(SignalingError::new(ErrorCode::BadMessage, format!("Invalid poster URL '{poster}'")));
+        }
+        if let Some(language) = self.language.as_deref().filter(|l| l.is_empty() || !l.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')) {
+            return Err(SignalingError::new(ErrorCode::BadMessage, format!("Invalid broadcast language '{language}'")));
+        }
+
+        Ok(())
+    }
+}
+
+/// Messages sent from the client to the server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum ClientMessage {
+    /// Ask for the session configuration, answered with `ServerMessage::Config`
+    Hello,
+    /// Start broadcasting under `name`, or resume it with the token of a previous session.
+    /// With `record`, the broadcast is recorded from the start. The metadata of a resumed
+    /// broadcast is kept unless new metadata is sent.
+    Broadcast {
+        name: String,
+        sdp: RTCSessionDescription,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        resume_token: Option<String>,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        record: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        metadata: Option<BroadcastMetadata>,
+    },
+    /// Replace the metadata of the live broadcast, from its broadcaster
+    UpdateMetadata { metadata: BroadcastMetadata },
+    /// Watch the broadcast registered under `name`. Without an offer, the server sends its own
+    /// offer carrying every track of the broadcast, to be answered with `Answer`.
+    Join {
+        name: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sdp: Option<RTCSessionDescription>,
+    },
+    /// The answer to an offer from the server
+    Answer { sdp: RTCSessionDescription },
+    /// A trickled ICE candidate
+    Candidate { candidate: RTCIceCandidateInit },
+    /// The client finished gathering ICE candidates
+    EndOfCandidates,
+    /// Receive the simulcast layer `rid` of the broadcast's video, or the one that fits the
+    /// viewer's bandwidth when omitted
+    SelectLayer {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        rid: Option<String>,
+    },
+    /// Start a watch party under `name`: the server plays out `video` and `audio` from its
+    /// playout directory, and the client joins it as the host that controls playback. The party
+    /// ends with the host's session. Like starting a playout through the control API, it needs
+    /// the API `token`, or to come from localhost if the server has none.
+    WatchParty {
+        name: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        video: Option<PathBuf>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        audio: Option<PathBuf>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sdp: Option<RTCSessionDescription>,
+    },
+    /// Resume the watch party, from its host
+    Play,
+    /// Pause the watch party, from its host
+    Pause,
+    /// Move the watch party to `position` seconds into the film, from its host
+    Seek { position: f64 },
+    /// Show the subtitles in `language`, or none when omitted, from a viewer
+    SelectSubtitles {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        language: Option<String>,
+    },
+    /// Show a subtitle cue to the viewers of `language` right away, for `duration` seconds,
+    /// from the broadcaster
+    Cue { language: String, text: String, duration: f64 },
+}
+
+/// Messages sent from the server to the client
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum ServerMessage {
+    /// Session configuration, with the ICE servers to use in the client's peer connection
+    Config { ice_servers: Vec<RTCIceServer> },
+    /// The SDP answer to the client's offer
+    Answer { sdp: Box<RTCSessionDescription> },
+    /// An SDP offer for a viewer that joined without one
+    Offer { sdp: Box<RTCSessionDescription> },
+    /// A trickled ICE candidate
+    Candidate { candidate: RTCIceCandidateInit },
+    /// The server finished gathering ICE candidates
+    EndOfCandidates,
+    /// The broadcast is live. The token lets the broadcaster resume it after losing its connection.
+    BroadcastStarted { name: String, resume_token: String },
+    /// Simulcast layers of the broadcast's video, from the highest to the lowest bitrate,
+    /// sent to viewers that can pick one with `SelectLayer`
+    Layers { rids: Vec<String> },
+    /// Playback state of a broadcast played out by the server, sent to viewers when they join and
+    /// whenever the host of a watch party changes it
+    Playback { playing: bool, position: f64 },
+    /// Languages of the broadcast's subtitles, sent to viewers when they join and whenever a
+    /// subtitle track is added or removed. Viewers pick one with `SelectSubtitles`.
+    Subtitles { languages: Vec<String> },
+    /// Metadata of the broadcast, sent to viewers when they join and whenever the broadcaster
+    /// changes it
+    Metadata { name: String, metadata: BroadcastMetadata },
+    /// Number of viewers watching the broadcast, pushed to its broadcaster whenever it changes
+    ViewerCount { count: usize },
+    /// The broadcast being watched ended, the viewer's peer connection is closed
+    BroadcastEnded { name: String },
+    /// The client's request could not be fulfilled
+    Error { code: ErrorCode, message: String },
+}
+
+/// Machine-readable error codes sent in `ServerMessage::Error`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The message is not valid JSON or does not match any known message
+    BadMessage,
+    /// The message was sent with a protocol version the server does not speak
+    UnsupportedVersion,
+    /// The requested broadcast does not exist
+    NotFound,
+    /// The session description could not be applied
+    BadSdp,
+    /// The request lacks valid credentials, such as a control API call without its token
+    Unauthorized,
+    /// The client is not allowed to perform the request, such as resuming with a wrong token
+    Forbidden,
+    /// The request does not fit the state of the broadcast, such as recording it twice
+    Conflict,
+    /// The broadcast name is held by another broadcaster, live or waiting to resume
+    NameTaken,
+    /// Something went wrong on the server while handling the request
+    Internal,
+}
+
+/// An error that is reported back to the client as a `ServerMessage::Error`
+#[derive(Debug, Clone)]
+pub struct SignalingError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl SignalingError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self { code, message: message.into() }
+    }
+
+    /// Recover the `SignalingError` carried by an `anyhow::Error`.
+    /// Any other error is reported as `internal`, without leaking its details to the client.
+    pub fn from_anyhow(e: &anyhow::Error) -> Self {
+        match e.downcast_ref::<SignalingError>() {
+            Some(e) => e.clone(),
+            None => Self::new(ErrorCode::Internal, "Internal server error"),
+        }
+    }
+}
+
+impl std::fmt::Display for SignalingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for SignalingError {}
+
+impl From<SignalingError> for ServerMessage {
+    fn from(e: SignalingError) -> Self {
+        ServerMessage::Error { code: e.code, message: e.message }
+    }
+}
+
+/// Wire format of every message: the protocol version next to the tagged message
+#[derive(Serialize, Deserialize)]
+struct Envelope<T> {
+    version: u32,
+    #[serde(flatten)]
+    message: T,
+}
+
+/// Parse a client message, checking the protocol version before the message body
+pub fn parse_client_message(text: &str) -> Result<ClientMessage, SignalingError> {
+    let value = serde_json::from_str::<serde_json::Value>(text)
+        .map_err(|e| SignalingError::new(ErrorCode::BadMessage, format!("Invalid JSON: {e}")))?;
+
+    match value.get("version").and_then(serde_json::Value::as_u64) {
+        Some(version) if version == PROTOCOL_VERSION as u64 => {}
+        Some(version) => return Err(SignalingError::new(
+            ErrorCode::UnsupportedVersion,
+            format!("Unsupported protocol version {version}, expected {PROTOCOL_VERSION}"),
+        )),
+        None => return Err(SignalingError::new(
+            ErrorCode::BadMessage,
+            "Missing protocol version field 'version'",
+        )),
+    }
+
+    serde_json::from_value::<Envelope<ClientMessage>>(value)
+        .map(|envelope| envelope.message)
+        .map_err(|e| SignalingError::new(ErrorCode::BadMessage, format!("Invalid message: {e}")))
+}
+
+/// Serialize a server message with the current protocol version
+pub fn serialize_server_message(message: &ServerMessage) -> Result<String, serde_json::Error> {
+    serde_json::to_string(&Envelope { version: PROTOCOL_VERSION, message })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_versioned_message() {
+        let message = parse_client_message(r#"{"version": 1, "type": "join", "name": "main-hall"}"#).unwrap();
+        assert!(matches!(message, ClientMessage::Join { name, sdp: None } if name == "main-hall"));
+
+        let message = parse_client_message(r#"{"version": 1, "type": "select-layer"}"#).unwrap();
+        assert!(matches!(message, ClientMessage::SelectLayer { rid: None }));
+    }
+
+    #[test]
+    fn rejects_invalid_json() {
+        let error = parse_client_message("{not json").unwrap_err();
+        assert_eq!(error.code, ErrorCode::BadMessage);
+    }
+
+    #[test]
+    fn rejects_missing_version() {
+        let error = parse_client_message(r#"{"type": "hello"}"#).unwrap_err();
+        assert_eq!(error.code, ErrorCode::BadMessage);
+    }
+
+    #[test]
+    fn rejects_other_version_before_body() {
+        // The body would not parse either, the version is reported first
+        let error = parse_client_message(r#"{"version": 2, "type": "teleport"}"#).unwrap_err();
+        assert_eq!(error.code, ErrorCode::UnsupportedVersion);
+    }
+
+    #[test]
+    fn rejects_unknown_message() {
+        let error = parse_client_message(r#"{"version": 1, "type": "teleport"}"#).unwrap_err();
+        assert_eq!(error.code, ErrorCode::BadMessage);
+
+        let error = parse_client_message(r#"{"version": 1, "type": "join"}"#).unwrap_err();
+        assert_eq!(error.code, ErrorCode::BadMessage);
+    }
+
+    #[test]
+    fn serializes_with_version() {
+        let text = serialize_server_message(&ServerMessage::ViewerCount { count: 3 }).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(value, serde_json::json!({ "version": PROTOCOL_VERSION, "type": "viewer-count", "count": 3 }));
+    }
+}
@@ -0,0 +1,409 @@
+use crate::{
+    components::{ track_manager::RelayTrack, ErrorCode, SignalingError },
+    prelude::*,
+    settings::RecordingSettings,
+};
+use std::{ fs::File, io::{ BufWriter, Seek, SeekFrom, Write } };
+
+use anyhow::Result;
+use chrono::{ DateTime, Utc };
+use serde::Serialize;
+use webrtc::{
+    api::media_engine::{ MIME_TYPE_OPUS, MIME_TYPE_VP8, MIME_TYPE_VP9 },
+    media::{ io::{ ogg_writer::OggWriter, Writer }, Error as MediaError },
+    rtp::{
+        codecs::{ vp8::Vp8Packet, vp9::Vp9Packet },
+        packet::Packet,
+        packetizer::Depacketizer,
+    },
+};
+
+/// Packets queued for a file writer, beyond which a recording that cannot keep up with the disk loses packets
+const RECORDER_QUEUE_SIZE: usize = 1024;
+
+/// Timebase of the recorded IVF files, the RTP clock rate of VP8 and VP9, so frames keep the
+/// timing they were sent with
+const IVF_TIMEBASE: u32 = 90000;
+
+/// A recording, as reported by the control API
+#[derive(Debug, Clone, Serialize)]
+pub struct RecordingInfo {
+    pub broadcast: String,
+    pub started_at: DateTime<Utc>,
+    // Files being written, or the recorded files once the recording stopped
+    pub files: Vec<PathBuf>,
+    // Why the stopped recording could not be muxed into a WebM file, keeping its IVF and Ogg files
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Writes VP8 or VP9 frames to an IVF file. Frames are timestamped from their RTP timestamps
+/// rather than counted at a fixed frame rate, and the header is sized from the first keyframe.
+///
+/// webrtc's `IVFWriter` numbers the frames instead, so video whose frame rate varies drifts from
+/// its audio once muxed. It also needs the dimensions before the first frame, checks VP9
+/// keyframes with the VP8 bit, and keeps writing the undecodable frames after a lost packet.
+struct IvfWriter {
+    file: BufWriter<File>,
+    vp9: bool,
+    // RTP clock ticks since the first packet, so the video keeps its offset to the audio
+    // while waiting for its first keyframe
+    elapsed: u64,
+    last_ts: Option<u32>,
+    last_seq: Option<u16>,
+    // Frame being depacketized, until its last packet (marker bit)
+    frame: Vec<u8>,
+    // Set until the first keyframe and after a lost packet, as the frames in between cannot be decoded
+    waiting_keyframe: bool,
+    header_written: bool,
+    frames: u32,
+}
+
+impl IvfWriter {
+    fn new(file: File, vp9: bool) -> Self {
+        Self {
+            file: BufWriter::new(file),
+            vp9,
+            elapsed: 0,
+            last_ts: None,
+            last_seq: None,
+            frame: Vec::new(),
+            waiting_keyframe: true,
+            header_written: false,
+            frames: 0,
+        }
+    }
+
+    fn write_header(&mut self, width: u16, height: u16) -> Result<(), MediaError> {
+        self.file.write_all(b"DKIF")?;
+        self.file.write_all(&0u16.to_le_bytes())?; // Version
+        self.file.write_all(&32u16.to_le_bytes())?; // Header size
+        self.file.write_all(if self.vp9 { b"VP90" } else { b"VP80" })?;
+        self.file.write_all(&width.to_le_bytes())?;
+        self.file.write_all(&height.to_le_bytes())?;
+        self.file.write_all(&IVF_TIMEBASE.to_le_bytes())?; // Timebase denominator
+        self.file.write_all(&1u32.to_le_bytes())?; // Timebase numerator
+        self.file.write_all(&0u32.to_le_bytes())?; // Frame count, written on close
+        self.file.write_all(&0u32.to_le_bytes())?;
+        self.header_written = true;
+        Ok(())
+    }
+}
+
+impl Writer for IvfWriter {
+    fn write_rtp(&mut self, packet: &Packet) -> Result<(), MediaError> {
+        if let Some(last_ts) = self.last_ts {
+            // Reordered packets never move the timeline back
+            self.elapsed += (packet.header.timestamp.wrapping_sub(last_ts) as i32).max(0) as u64;
+        }
+        self.last_ts = Some(packet.header.timestamp);
+
+        if self.last_seq.is_some_and(|seq| seq.wrapping_add(1) != packet.header.sequence_number) {
+            self.frame.clear();
+            self.waiting_keyframe = true;
+        }
+        self.last_seq = Some(packet.header.sequence_number);
+
+        if packet.payload.is_empty() {
+            return Ok(());
+        }
+        let mut depacketizer: Box<dyn Depacketizer> = match self.vp9 {
+            true => Box::<Vp9Packet>::default(),
+            false => Box::<Vp8Packet>::default(),
+        };
+
+        // A frame starts with the head of its first partition, whatever came before it is incomplete
+        if depacketizer.is_partition_head(&packet.payload) {
+            self.frame.clear();
+        } else if self.frame.is_empty() {
+            return Ok(());
+        }
+        self.frame.extend_from_slice(&depacketizer.depacketize(&packet.payload)?);
+        if !packet.header.marker {
+            return Ok(());
+        }
+
+        let frame = std::mem::take(&mut self.frame);
+        if self.waiting_keyframe {
+            let size = match self.vp9 {
+                true => vp9_keyframe_size(&frame),
+                false => vp8_keyframe_size(&frame),
+            };
+            let Some((width, height)) = size else {
+                return Ok(());
+            };
+            if !self.header_written {
+                self.write_header(width, height)?;
+            }
+            self.waiting_keyframe = false;
+        }
+
+        self.file.write_all(&(frame.len() as u32).to_le_bytes())?;
+        self.file.write_all(&self.elapsed.to_le_bytes())?; // Presentation timestamp
+        self.file.write_all(&frame)?;
+        self.frames += 1;
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), MediaError> {
+        // A recording without any keyframe is still a valid, empty IVF file
+        if !self.header_written {
+            self.write_header(0, 0)?;
+        }
+
+        self.file.seek(SeekFrom::Start(24))?;
+        self.file.write_all(&self.frames.to_le_bytes())?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+/// Width and height of a VP8 keyframe (RFC 6386 section 9.1), None for other frames
+fn vp8_keyframe_size(frame: &[u8]) -> Option<(u16, u16)> {
+    if frame.len() < 10 || frame[0] & 0x01 != 0 || frame[3..6] != [0x9d, 0x01, 0x2a] {
+        return None;
+    }
+
+    let width = u16::from_le_bytes([frame[6], frame[7]]) & 0x3fff;
+    let height = u16::from_le_bytes([frame[8], frame[9]]) & 0x3fff;
+    Some((width, height))
+}
+
+/// Width and height of a VP9 keyframe, from its uncompressed header, None for other frames
+fn vp9_keyframe_size(frame: &[u8]) -> Option<(u16, u16)> {
+    let mut position = 0;
+    let mut read = |bits: usize| -> Option<u32> {
+        let mut value = 0;
+        for _ in 0..bits {
+            let byte = frame.get(position / 8)?;
+            value = (value << 1) | ((byte >> (7 - position % 8)) & 1) as u32;
+            position += 1;
+        }
+        Some(value)
+    };
+
+    if read(2)? != 2 {
+        return None; // Frame marker
+    }
+    let profile_low = read(1)?;
+    let profile = (read(1)? << 1) | profile_low;
+    if profile == 3 {
+        read(1)?; // Reserved
+    }
+    // Shown existing frames and inter frames carry no size
+    if read(1)? != 0 || read(1)? != 0 {
+        return None;
+    }
+    read(2)?; // Show frame and error resilient mode
+    if read(24)? != 0x49_83_42 {
+        return None; // Sync code
+    }
+
+    // Color config
+    if profile >= 2 {
+        read(1)?; // Bit depth
+    }
+    let color_space = read(3)?;
+    let subsampling = profile == 1 || profile == 3;
+    if color_space != 7 {
+        read(1)?; // Color range
+        if subsampling {
+            read(3)?; // Subsampling and reserved bit
+        }
+    } else if subsampling {
+        read(1)?; // Reserved
+    }
+
+    let width = u16::try_from(read(16)? + 1).ok()?;
+    let height = u16::try_from(read(16)? + 1).ok()?;
+    Some((width, height))
+}
+
+/// A relay track being written to a file
+struct TrackRecording {
+    track: Arc<RelayTrack>,
+    path: PathBuf,
+    // Packets to write, handed to the track once the recording is attached
+    packets: mpsc::Sender<Packet>,
+    writer: tokio::task::JoinHandle<()>,
+}
+
+/// Recording of a broadcast's video to IVF (VP8 or VP9) and audio to Ogg (Opus), muxed into a
+/// single WebM file with ffmpeg once it stops
+pub struct Recording {
+    broadcast: String,
+    started_at: DateTime<Utc>,
+    // Recording path without its extension
+    base_path: PathBuf,
+    ffmpeg: PathBuf,
+    tracks: Vec<TrackRecording>,
+}
+
+impl Recording {
+    /// Create the files to record the first video and audio tracks of a broadcast in a codec that
+    /// can be recorded, of simulcast video the layer with the highest bitrate. Packets are only
+    /// written once the recording is attached to the tracks with `attach`.
+    pub async fn create(settings: &RecordingSettings, broadcast: &str, tracks: &[Arc<RelayTrack>]) -> Result<Self> {
+        let video = tracks.iter()
+            .find(|t| t.kind == RTPCodecType::Video && Self::is_codec(t, &[MIME_TYPE_VP8, MIME_TYPE_VP9]))
+            .and_then(|first| tracks.iter()
+                .filter(|t| t.kind == RTPCodecType::Video && t.mid == first.mid)
+                .max_by_key(|t| t.bitrate()));
+        let audio = tracks.iter()
+            .find(|t| t.kind == RTPCodecType::Audio && Self::is_codec(t, &[MIME_TYPE_OPUS]));
+
+        if video.is_none() && audio.is_none() {
+            bail!(SignalingError::new(
+                ErrorCode::Conflict,
+                format!("Broadcast '{}' has no VP8, VP9 or Opus track to record", broadcast)
+            ));
+        }
+
+        fs::create_dir_all(&settings.directory).await
+            .map_err(|e| anyhow!("Failed to create recording directory '{}': {}", settings.directory.display(), e))?;
+
+        let started_at = Utc::now();
+        let file_name: String = broadcast.chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+            .collect();
+        let base_path = settings.directory.join(format!("{}-{}", file_name, started_at.format("%Y%m%d-%H%M%S")));
+
+        let mut recording = Self {
+            broadcast: broadcast.to_owned(),
+            started_at,
+            base_path,
+            ffmpeg: settings.ffmpeg.clone(),
+            tracks: Vec::new(),
+        };
+
+        if let Some(video) = video {
+            let path = recording.base_path.with_extension("ivf");
+            let vp9 = video.codec.mime_type.eq_ignore_ascii_case(MIME_TYPE_VP9);
+            let writer = IvfWriter::new(fs::File::create(&path).await?.into_std().await, vp9);
+            recording.add_track(video, path, Box::new(writer));
+        }
+
+        if let Some(audio) = audio {
+            let path = recording.base_path.with_extension("ogg");
+            let file = fs::File::create(&path).await?.into_std().await;
+            let (clock_rate, channels) = (audio.codec.clock_rate, audio.codec.channels.max(1) as u8);
+            let writer = tokio::task::spawn_blocking(move || OggWriter::new(file, clock_rate, channels)).await??;
+            recording.add_track(audio, path, Box::new(writer));
+        }
+
+        Ok(recording)
+    }
+
+    /// Start writing the packets of the recorded tracks to the files
+    pub fn attach(&self) {
+        for track in &self.tracks {
+            track.track.set_recorder(Some(track.packets.clone()));
+
+            // The video file can only start with a keyframe
+            if track.track.kind == RTPCodecType::Video {
+                track.track.request_keyframe();
+            }
+        }
+        info!("Broadcast '{}': Recording to {}.*", self.broadcast, self.base_path.display());
+    }
+
+    /// Remove the files of a recording that was never attached
+    pub async fn discard(self) {
+        for track in self.tracks {
+            drop(track.packets);
+            let _ = track.writer.await;
+            let _ = fs::remove_file(&track.path).await;
+        }
+    }
+
+    fn is_codec(track: &RelayTrack, mime_types: &[&str]) -> bool {
+        mime_types.iter().any(|mime_type| track.codec.mime_type.eq_ignore_ascii_case(mime_type))
+    }
+
+    /// Write the packets sent for `track` with `writer` on a blocking thread, until the recording
+    /// is detached from the track
+    fn add_track(&mut self, track: &Arc<RelayTrack>, path: PathBuf, mut writer: Box<dyn Writer + Send>) {
+        let (packet_tx, mut packet_rx) = mpsc::channel::<Packet>(RECORDER_QUEUE_SIZE);
+        let broadcast = self.broadcast.clone();
+        let file = path.clone();
+
+        let writer = tokio::task::spawn_blocking(move || {
+            while let Some(packet) = packet_rx.blocking_recv() {
+                // A packet that cannot be depacketized only loses its own frame
+                if let Err(e) = writer.write_rtp(&packet) {
+                    debug!("Broadcast '{}': Failed to record packet to {}: {}", broadcast, file.display(), e);
+                }
+            }
+
+            if let Err(e) = writer.close() {
+                warn!("Broadcast '{}': Failed to finish recording {}: {}", broadcast, file.display(), e);
+            }
+        });
+
+        self.tracks.push(TrackRecording { track: Arc::clone(track), path, packets: packet_tx, writer });
+    }
+
+    pub fn info(&self) -> RecordingInfo {
+        RecordingInfo {
+            broadcast: self.broadcast.clone(),
+            started_at: self.started_at,
+            files: self.tracks.iter().map(|t| t.path.clone()).collect(),
+            error: None,
+        }
+    }
+
+    /// Stop recording and mux the recorded files into a WebM file.
+    /// The IVF and Ogg files are kept if muxing fails, with the reason in `error`.
+    pub async fn stop(self) -> RecordingInfo {
+        let mut info = self.info();
+
+        for track in self.tracks {
+            track.track.set_recorder(None);
+            drop(track.packets);
+            let _ = track.writer.await;
+        }
+
+        let output = self.base_path.with_extension("webm");
+        let mut ffmpeg = tokio::process::Command::new(&self.ffmpeg);
+        ffmpeg.args(["-y", "-loglevel", "error"]);
+        for file in &info.files {
+            ffmpeg.arg("-i").arg(file);
+        }
+        ffmpeg.args(["-c", "copy"]).arg(&output);
+
+        match ffmpeg.output().await {
+            Ok(result) if result.status.success() => {
+                for file in &info.files {
+                    let _ = fs::remove_file(file).await;
+                }
+                info.files = vec![output];
+            }
+            Ok(result) => {
+                let stderr = String::from_utf8_lossy(&result.stderr);
+                let reason = stderr.lines().rfind(|line| !line.trim().is_empty()).unwrap_or_default();
+                info.error = Some(format!("ffmpeg failed to mux the recording ({}): {}", result.status, reason.trim()));
+            }
+            Err(e) => info.error = Some(format!("Failed to run ffmpeg '{}': {}", self.ffmpeg.display(), e)),
+        }
+
+        match &info.error {
+            Some(error) => warn!("Broadcast '{}': {}, keeping {:?}", self.broadcast, error, info.files),
+            None => info!("Broadcast '{}': Recording stopped, saved to {:?}", self.broadcast, info.files),
+        }
+        info
+    }
+
+    /// Check that ffmpeg can be run, as recordings are only muxed into WebM files with it
+    pub async fn check_ffmpeg(settings: &RecordingSettings) -> Result<()> {
+        let status = tokio::process::Command::new(&settings.ffmpeg)
+            .arg("-version")
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status().await
+            .map_err(|e| anyhow!("Failed to run ffmpeg '{}': {}", settings.ffmpeg.display(), e))?;
+        if !status.success() {
+            bail!("ffmpeg '{}' failed to start ({})", settings.ffmpeg.display(), status);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,203 @@
+use crate::{
+    components::{ ErrorCode, PlayoutManager, PlayoutSource, SignalingError },
+    prelude::*,
+    settings::ScreeningConfig,
+};
+use anyhow::Result;
+use chrono::{ DateTime, Utc };
+use serde::Serialize;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScreeningStatus {
+    Scheduled,
+    Playing,
+    Finished,
+    Failed,
+}
+
+/// A screening of the schedule, as reported by the control API
+#[derive(Debug, Clone, Serialize)]
+pub struct ScreeningInfo {
+    pub id: String,
+    #[serde(flatten)]
+    pub screening: ScreeningConfig,
+    pub status: ScreeningStatus,
+    // Why the playout could not start, for failed screenings
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+enum ScreeningState {
+    Scheduled,
+    // Started as the playout with this id, which may have reached the end of its files since
+    Started(String),
+    Finished,
+    Failed(String),
+}
+
+struct Screening {
+    config: ScreeningConfig,
+    state: ScreeningState,
+    // Starts and ends the playout, aborted when the screening is cancelled
+    task: tokio::task::JoinHandle<()>,
+}
+
+type Screenings = Arc<Mutex<HashMap<String, Screening>>>;
+
+/// Timetable of screenings, each starting a playout at its start time and stopping it at its end
+pub struct Scheduler {
+    playout_manager: Arc<PlayoutManager>,
+    screenings: Screenings,
+}
+
+impl Scheduler {
+    pub fn new(playout_manager: Arc<PlayoutManager>) -> Self {
+        Self {
+            playout_manager,
+            screenings: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Schedule the screenings of the configuration file, skipping the invalid ones
+    pub async fn load(&self, screenings: Vec<ScreeningConfig>) {
+        for screening in screenings {
+            let broadcast = screening.broadcast.clone();
+            if let Err(e) = self.add(screening).await {
+                warn!("Broadcast '{}': Skipping scheduled screening: {}", broadcast, e);
+            }
+        }
+    }
+
+    /// Schedule a screening. A screening whose start time passed starts right away, as long as
+    /// it has an end time that did not.
+    pub async fn add(&self, config: ScreeningConfig) -> Result<ScreeningInfo> {
+        if config.video.is_none() && config.audio.is_none() {
+            bail!(SignalingError::new(ErrorCode::BadMessage, "A screening needs a video or an audio file"));
+        }
+
+        let now = Utc::now();
+        match config.end {
+            Some(end) if end <= config.start => {
+                bail!(SignalingError::new(ErrorCode::BadMessage, "A screening must end after it starts"));
+            }
+            Some(end) if end <= now => {
+                bail!(SignalingError::new(ErrorCode::BadMessage, "The screening is already over"));
+            }
+            None if config.start <= now => {
+                bail!(SignalingError::new(ErrorCode::BadMessage, "The screening start time has passed"));
+            }
+            _ => {}
+        }
+
+        // Held until the screening is stored, so its task never misses it
+        let mut screenings = self.screenings.lock().await;
+        let id = Uuid::new_v4().to_string();
+        let task = tokio::spawn(Self::run(
+            Arc::clone(&self.playout_manager),
+            Arc::clone(&self.screenings),
+            id.clone(),
+            config.clone(),
+        ));
+
+        info!("Broadcast '{}': Screening {} scheduled at {}", config.broadcast, id, config.start);
+        let screening = Screening { config, state: ScreeningState::Scheduled, task };
+        let info = self.info(&id, &screening).await;
+        screenings.insert(id, screening);
+        Ok(info)
+    }
+
+    /// Every screening, in order of start time
+    pub async fn list(&self) -> Vec<ScreeningInfo> {
+        let screenings = self.screenings.lock().await;
+        let mut list = Vec::with_capacity(screenings.len());
+        for (id, screening) in screenings.iter() {
+            list.push(self.info(id, screening).await);
+        }
+
+        list.sort_by_key(|s| s.screening.start);
+        list
+    }
+
+    /// Remove a screening from the schedule, stopping its playout if it is playing
+    pub async fn cancel(&self, id: &str) -> Result<ScreeningInfo> {
+        let Some(screening) = self.screenings.lock().await.remove(id) else {
+            bail!(SignalingError::new(ErrorCode::NotFound, format!("Screening '{}' not found", id)));
+        };
+
+        screening.task.abort();
+        let info = self.info(id, &screening).await;
+        if let ScreeningState::Started(playout_id) = &screening.state {
+            self.playout_manager.stop_playout(&screening.config.broadcast, playout_id).await;
+        }
+
+        info!("Broadcast '{}': Screening {} cancelled", screening.config.broadcast, id);
+        Ok(info)
+    }
+
+    async fn info(&self, id: &str, screening: &Screening) -> ScreeningInfo {
+        let (status, error) = match &screening.state {
+            ScreeningState::Scheduled => (ScreeningStatus::Scheduled, None),
+            ScreeningState::Started(playout_id) => {
+                match self.playout_manager.is_playing(&screening.config.broadcast, playout_id).await {
+                    true => (ScreeningStatus::Playing, None),
+                    false => (ScreeningStatus::Finished, None),
+                }
+            }
+            ScreeningState::Finished => (ScreeningStatus::Finished, None),
+            ScreeningState::Failed(error) => (ScreeningStatus::Failed, Some(error.clone())),
+        };
+
+        ScreeningInfo {
+            id: id.to_owned(),
+            screening: screening.config.clone(),
+            status,
+            error,
+        }
+    }
+
+    /// Wait for the start time to start the playout, then for the end time to stop it
+    async fn run(playout_manager: Arc<PlayoutManager>, screenings: Screenings, id: String, config: ScreeningConfig) {
+        sleep_until(config.start).await;
+
+        let source = PlayoutSource { video: config.video, audio: config.audio, looping: config.looping };
+        let state = match playout_manager.start(config.broadcast.clone(), source).await {
+            Ok(playout_id) => ScreeningState::Started(playout_id),
+            Err(e) => {
+                warn!("Broadcast '{}': Failed to start screening {}: {}", config.broadcast, id, e);
+                ScreeningState::Failed(e.to_string())
+            }
+        };
+        let Some(playout_id) = Self::set_state(&screenings, &id, state).await else {
+            return;
+        };
+
+        if let Some(end) = config.end {
+            sleep_until(end).await;
+            playout_manager.stop_playout(&config.broadcast, &playout_id).await;
+            Self::set_state(&screenings, &id, ScreeningState::Finished).await;
+            info!("Broadcast '{}': Screening {} ended", config.broadcast, id);
+        }
+    }
+
+    /// Update the state of a screening, returning the id of its playout if it started
+    async fn set_state(screenings: &Screenings, id: &str, state: ScreeningState) -> Option<String> {
+        let playout_id = match &state {
+            ScreeningState::Started(playout_id) => Some(playout_id.clone()),
+            _ => None,
+        };
+
+        if let Some(screening) = screenings.lock().await.get_mut(id) {
+            screening.state = state;
+        }
+        playout_id
+    }
+}
+
+/// Sleep until a wall clock time, returning right away if it passed
+async fn sleep_until(time: DateTime<Utc>) {
+    if let Ok(duration) = (time - Utc::now()).to_std() {
+        tokio::time::sleep(duration).await;
+    }
+}
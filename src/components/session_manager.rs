@@ -1,123 +1,597 @@
 use crate::{
-    components::{ PeerConnectionFactory, TrackManager, BroadcastManager },
-    prelude::*
+    components::{
+        PeerConnectionFactory,
+        TrackManager,
+        ChatManager,
+        SubtitleManager,
+        http_signaling::HttpSessions,
+        BroadcastManager,
+        BroadcastInfo,
+        ViewerInfo,
+        BroadcastMetadata,
+        broadcast_registry::BroadcasterSession,
+        recorder::RecordingInfo,
+        track_manager::{ RelayTrack, ViewerTrack },
+        ServerToClientMsg,
+        ServerMessage,
+        ErrorCode,
+        SignalingError,
+    },
+    prelude::*,
+    settings::SessionSettings,
 };
+use std::time::Duration;
+
 use anyhow::Result;
+use uuid::Uuid;
+
+/// Which side of a broadcast a peer connection belongs to
+enum SessionRole {
+    Broadcaster { session_id: String },
+    Viewer { session_id: String },
+}
+
+/// A viewer's session, as created by `join_broadcast`
+pub struct ViewerSession {
+    // Identifies the viewer in the registry
+    pub id: String,
+    pub peer_connection: Arc<RTCPeerConnection>,
+}
 
 #[derive(Clone)]
 pub struct SessionManager {
     peer_conn_factory: Arc<PeerConnectionFactory>,
-    broadcast_manager: Arc<BroadcastManager>
+    broadcast_manager: Arc<BroadcastManager>,
+    chat: ChatManager,
+    subtitles: SubtitleManager,
+    // Sessions created through WHIP and WHEP, forgotten once their peer connection ends
+    http_sessions: Arc<HttpSessions>,
+    settings: SessionSettings
 }
 
 impl SessionManager {
-    pub fn new(peer_conn_factory: Arc<PeerConnectionFactory>, broadcast_manager: Arc<BroadcastManager>) -> Self {
-        Self { peer_conn_factory, broadcast_manager }
+    pub fn new(
+        peer_conn_factory: Arc<PeerConnectionFactory>,
+        broadcast_manager: Arc<BroadcastManager>,
+        chat: ChatManager,
+        subtitles: SubtitleManager,
+        settings: SessionSettings
+    ) -> Self {
+        Self { peer_conn_factory, broadcast_manager, chat, subtitles, http_sessions: Arc::default(), settings }
+    }
+
+    /// The sessions created through WHIP and WHEP
+    pub fn http_sessions(&self) -> Arc<HttpSessions> {
+        Arc::clone(&self.http_sessions)
+    }
+
+    /// The ICE servers clients should use, matching the ones used by the server
+    pub fn ice_servers(&self) -> Vec<RTCIceServer> {
+        self.peer_conn_factory.ice_servers()
+    }
+
+    /// Start a broadcast from the broadcaster's offer.
+    /// The broadcast is registered in the background, as soon as its tracks arrive.
+    /// With a `resume_token`, the broadcast of a previous session is resumed instead, and keeps
+    /// being recorded if it was. Otherwise it is recorded from the start with `record`.
+    /// Viewer counts are pushed through `notifier`, when the broadcaster has a signaling session.
+    /// The metadata of a resumed broadcast is only replaced when `metadata` is given.
+    pub async fn start_broadcast(
+        &self,
+        broadcast: String,
+        offer: RTCSessionDescription,
+        resume_token: Option<String>,
+        record: bool,
+        metadata: Option<BroadcastMetadata>,
+        notifier: Option<mpsc::Sender<ServerToClientMsg>>
+    ) -> Result<Arc<RTCPeerConnection>> {
+        let session_id = Uuid::new_v4().to_string();
+
+        if let Some(metadata) = &metadata {
+            metadata.validate()?;
+        }
+
+        if let Some(resume_token) = resume_token {
+            let peer_connection = self.resume_broadcast(broadcast.clone(), session_id, offer, resume_token, notifier).await?;
+            if let Some(metadata) = metadata {
+                self.broadcast_manager.update_metadata(&broadcast, metadata).await;
+            }
+            return Ok(peer_connection);
+        }
+        self.broadcast_manager.check_name(&broadcast).await?;
+
+        // Create a dedicated track manager for this broadcaster
+        let mut track_manager = TrackManager::new(broadcast.clone());
+
+        let peer_connection = self
+            .create_broadcaster_session(broadcast.clone(), session_id.clone(), offer, &mut track_manager)
+            .await?;
+
+        // Wait for the tracks negotiated in the offer to arrive, then register the broadcast
+        let expected_tracks = TrackManager::negotiated_track_count(&peer_connection).await;
+        let settle_timeout = self.settings.track_settle_timeout;
+        let broadcast_manager = Arc::clone(&self.broadcast_manager);
+        let broadcaster = BroadcasterSession {
+            id: session_id,
+            peer_connection: Some(Arc::downgrade(&peer_connection)),
+            notifier,
+        };
+
+        tokio::spawn(async move {
+            debug!("Broadcast '{}': Waiting for {} track(s) from broadcaster", broadcast, expected_tracks);
+
+            let tracks = track_manager.collect_tracks(expected_tracks, settle_timeout).await;
+            if tracks.is_empty() {
+                warn!("Broadcast '{}': No tracks received from broadcaster", broadcast);
+                return;
+            }
+
+            let video_count = tracks.iter().filter(|t| t.kind == RTPCodecType::Video).count();
+            let audio_count = tracks.len() - video_count;
+            debug!("Broadcast '{}': {} track(s) received, registering broadcast", broadcast, tracks.len());
+
+            // The name may have been taken while the tracks arrived
+            let peer_connection = broadcaster.peer_connection.clone();
+            let notifier = broadcaster.notifier.clone();
+            if let Err(e) = broadcast_manager.register_broadcast(broadcast.clone(), broadcaster, tracks, metadata.unwrap_or_default()).await {
+                let error = SignalingError::from_anyhow(&e);
+                warn!("Broadcast '{}': Not registered: {}", broadcast, error.message);
+                if let Some(notifier) = notifier {
+                    let _ = notifier.send(ServerToClientMsg::Message(error.into())).await;
+                }
+                if let Some(peer_connection) = peer_connection.and_then(|pc| pc.upgrade()) {
+                    let _ = peer_connection.close().await;
+                }
+                return;
+            }
+
+            info!("Broadcast '{}': Ready for viewers (with {} video and {} audio track(s))",
+                  broadcast, video_count, audio_count);
+
+            if record {
+                if let Err(e) = broadcast_manager.start_recording(&broadcast).await {
+                    warn!("Broadcast '{}': Failed to start recording: {}", broadcast, e);
+                }
+            }
+        });
+
+        Ok(peer_connection)
+    }
+
+    /// Resume a broadcast from a new session of its broadcaster, keeping its viewers attached.
+    /// Fails with `forbidden` if the broadcast ended or the token does not match.
+    async fn resume_broadcast(
+        &self,
+        broadcast: String,
+        session_id: String,
+        offer: RTCSessionDescription,
+        resume_token: String,
+        notifier: Option<mpsc::Sender<ServerToClientMsg>>
+    ) -> Result<Arc<RTCPeerConnection>> {
+        let forbidden = || SignalingError::new(
+            ErrorCode::Forbidden,
+            format!("Broadcast '{}' cannot be resumed with this token", broadcast)
+        );
+
+        let Some(tracks) = self.broadcast_manager.get_resumable_tracks(&broadcast, &resume_token).await else {
+            bail!(forbidden());
+        };
+        info!("Broadcast '{}': Broadcaster is resuming its broadcast", broadcast);
+
+        // The new tracks are re-bound into the relay tracks viewers already hold
+        let mut track_manager = TrackManager::resume(broadcast.clone(), tracks);
+
+        let peer_connection = self
+            .create_broadcaster_session(broadcast.clone(), session_id.clone(), offer, &mut track_manager)
+            .await?;
+
+        let broadcaster = BroadcasterSession {
+            id: session_id,
+            peer_connection: Some(Arc::downgrade(&peer_connection)),
+            notifier,
+        };
+        if !self.broadcast_manager.resume_broadcast(&broadcast, &resume_token, broadcaster).await {
+            let _ = peer_connection.close().await;
+            bail!(forbidden());
+        }
+
+        Ok(peer_connection)
+    }
+
+    /// Join a registered broadcast, failing with `not_found` if it is not live.
+    /// Without an offer from the viewer, the server makes the offer with `create_offer`, so the
+    /// viewer receives every track of the broadcast. Otherwise it only gets the tracks it offered for.
+    /// The end of the broadcast is announced through `notifier`, when the viewer has a signaling session.
+    pub async fn join_broadcast(
+        &self,
+        broadcast: String,
+        offer: Option<RTCSessionDescription>,
+        notifier: Option<mpsc::Sender<ServerToClientMsg>>
+    ) -> Result<ViewerSession> {
+        // Look up the broadcast in the registry
+        let Some(tracks) = self.broadcast_manager.get_broadcast(&broadcast).await else {
+            debug!("Broadcast '{}': Broadcast not found in registry", broadcast);
+            bail!(SignalingError::new(ErrorCode::NotFound, format!("Broadcast '{}' not found", broadcast)));
+        };
+        debug!("Broadcast '{}': Broadcast found in registry (with {} track(s))", broadcast, tracks.len());
+
+        self.create_viewer_session(broadcast, offer, tracks, notifier).await
     }
 
     pub async fn create_broadcaster_session(
         &self,
         broadcast: String,
+        session_id: String,
         offer: RTCSessionDescription,
         track_manager: &mut TrackManager
     ) -> Result<Arc<RTCPeerConnection>> {
+        // A receiving transceiver is created for every media section of the offer
         let peer_connection = self.peer_conn_factory
             .create_peer_connection()
             .await?;
 
-        // Add transceiver for receiving video
-        peer_connection
-            .add_transceiver_from_kind(RTPCodecType::Video, None)
-            .await?;
+        // Setup track handlers
+        track_manager.setup_track_handlers(Arc::clone(&peer_connection))?;
 
-        peer_connection
-            .add_transceiver_from_kind(RTPCodecType::Audio, None)
-            .await?;
+        // The broadcaster chats with its viewers, if its offer has the chat data channel
+        self.chat.attach(&broadcast, &session_id, &peer_connection).await?;
 
-        // Setup track handlers
-        let _ = track_manager.setup_track_handlers(Arc::clone(&peer_connection))?;
+        // Handle offer
+        Self::apply_offer(&peer_connection, offer).await?;
 
-        // Setup connection state handler
+        // Setup connection state handler, only once the offer is accepted, so a rejected
+        // offer never affects the registry
         self.setup_conn_state_handler(
             broadcast,
-            true,
+            SessionRole::Broadcaster { session_id },
             Arc::clone(&peer_connection),
             Arc::clone(&self.broadcast_manager)
         ).await;
 
-        // Handle offer
-        peer_connection.set_remote_description(offer).await?;
-
         Ok(peer_connection)
     }
 
     pub async fn create_viewer_session(
         &self,
         broadcast: String,
-        offer: RTCSessionDescription,
-        video_track: Arc<TrackLocalStaticRTP>,
-        audio_track: Arc<TrackLocalStaticRTP>
-    ) -> Result<Arc<RTCPeerConnection>> {
+        offer: Option<RTCSessionDescription>,
+        tracks: Vec<Arc<RelayTrack>>,
+        notifier: Option<mpsc::Sender<ServerToClientMsg>>
+    ) -> Result<ViewerSession> {
+        // Each viewer gets its own copy of the tracks, primed with the cached keyframe once connected
+        let tracks: Vec<_> = ViewerTrack::subscribe(&tracks).into_iter().map(Arc::new).collect();
         let peer_connection = self.peer_conn_factory
-            .create_recv_only_peer_connection(video_track, audio_track)
+            .create_recv_only_peer_connection(&tracks)
             .await?;
 
-        // Setup connection state handler
+        // The chat and subtitles data channels are part of the server's offer, or of the viewer's
+        // if it has them
+        let session_id = Uuid::new_v4().to_string();
+        self.chat.attach(&broadcast, &session_id, &peer_connection).await?;
+        self.subtitles.attach(&broadcast, &session_id, &peer_connection).await?;
+
+        // Handle offer
+        if let Some(offer) = offer {
+            Self::apply_offer(&peer_connection, offer).await?;
+        }
+
+        // Track the viewer in the registry, the broadcast may have ended since it was looked up
+        if !self.broadcast_manager.add_viewer(&broadcast, session_id.clone(), Arc::clone(&peer_connection), tracks, notifier).await {
+            let _ = peer_connection.close().await;
+            bail!(SignalingError::new(ErrorCode::NotFound, format!("Broadcast '{}' not found", broadcast)));
+        }
+        debug!("Broadcast '{}': Viewer session {} registered", broadcast, session_id);
+
+        // Setup connection state handler, only once the offer is accepted, so a rejected
+        // offer never affects the registry
         self.setup_conn_state_handler(
             broadcast,
-            false,
+            SessionRole::Viewer { session_id: session_id.clone() },
             Arc::clone(&peer_connection),
             Arc::clone(&self.broadcast_manager)
         ).await;
 
-        // Handle offer
-        peer_connection.set_remote_description(offer).await?;
+        Ok(ViewerSession { id: session_id, peer_connection })
+    }
 
-        Ok(peer_connection)
+    /// Every live broadcast, in order of name
+    pub async fn list_broadcasts(&self) -> Vec<BroadcastInfo> {
+        self.broadcast_manager.list_broadcasts().await
+    }
+
+    /// Snapshot of a live broadcast, failing with `not_found` if it does not exist
+    pub async fn broadcast_info(&self, broadcast: &str) -> Result<BroadcastInfo> {
+        match self.broadcast_manager.broadcast_info(broadcast).await {
+            Some(info) => Ok(info),
+            None => bail!(SignalingError::new(ErrorCode::NotFound, format!("Broadcast '{}' not found", broadcast))),
+        }
+    }
+
+    /// Viewers of a live broadcast, failing with `not_found` if it does not exist
+    pub async fn broadcast_viewers(&self, broadcast: &str) -> Result<Vec<ViewerInfo>> {
+        match self.broadcast_manager.get_viewers(broadcast).await {
+            Some(viewers) => Ok(viewers),
+            None => bail!(SignalingError::new(ErrorCode::NotFound, format!("Broadcast '{}' not found", broadcast))),
+        }
+    }
+
+    /// Metadata of a live broadcast, or None if it does not exist
+    pub async fn broadcast_metadata(&self, broadcast: &str) -> Option<BroadcastMetadata> {
+        self.broadcast_manager.metadata(broadcast).await
+    }
+
+    /// Replace the metadata of a live broadcast and push it to its viewers.
+    /// Fails with `bad_message` if it is invalid, and with `not_found` if the broadcast is not live.
+    pub async fn update_metadata(&self, broadcast: &str, metadata: BroadcastMetadata) -> Result<()> {
+        metadata.validate()?;
+
+        if !self.broadcast_manager.update_metadata(broadcast, metadata).await {
+            bail!(SignalingError::new(ErrorCode::NotFound, format!("Broadcast '{}' not found", broadcast)));
+        }
+        Ok(())
+    }
+
+    /// Start recording a live broadcast
+    pub async fn start_recording(&self, broadcast: &str) -> Result<RecordingInfo> {
+        self.broadcast_manager.start_recording(broadcast).await
+    }
+
+    /// Stop recording a broadcast, returning the recorded files
+    pub async fn stop_recording(&self, broadcast: &str) -> Result<RecordingInfo> {
+        self.broadcast_manager.stop_recording(broadcast).await
+    }
+
+    /// Subtitles of the broadcasts, delivered on the viewers' sessions
+    pub fn subtitles(&self) -> &SubtitleManager {
+        &self.subtitles
+    }
+
+    /// The simulcast layers a viewer can pick with `select_layer`, from the highest to the
+    /// lowest bitrate. Empty if the broadcast's video has no simulcast.
+    pub async fn viewer_layers(&self, broadcast: &str, session_id: &str) -> Vec<String> {
+        let tracks = self.broadcast_manager.viewer_tracks(broadcast, session_id).await.unwrap_or_default();
+        tracks.iter()
+            .filter(|track| track.kind == RTPCodecType::Video)
+            .map(|track| track.layers())
+            .find(|layers| !layers.is_empty())
+            .unwrap_or_default()
+    }
+
+    /// Pin a viewer's video to a simulcast layer, or let it follow the viewer's bandwidth with None.
+    /// Fails with `bad_message` if the broadcast has no such layer.
+    pub async fn select_layer(&self, broadcast: &str, session_id: &str, rid: Option<&str>) -> Result<()> {
+        let tracks = self.broadcast_manager.viewer_tracks(broadcast, session_id).await.unwrap_or_default();
+
+        let mut selected = false;
+        for track in tracks.iter().filter(|track| track.kind == RTPCodecType::Video) {
+            selected |= track.select_layer(rid).await;
+        }
+
+        if let Some(rid) = rid.filter(|_| !selected) {
+            bail!(SignalingError::new(ErrorCode::BadMessage, format!("Broadcast '{}' has no layer '{}'", broadcast, rid)));
+        }
+
+        Ok(())
+    }
+
+    /// Set the client's offer as remote description, reporting failures as `bad_sdp`
+    async fn apply_offer(
+        peer_connection: &Arc<RTCPeerConnection>,
+        offer: RTCSessionDescription
+    ) -> Result<()> {
+        if offer.sdp_type != RTCSdpType::Offer {
+            let _ = peer_connection.close().await;
+            bail!(SignalingError::new(ErrorCode::BadSdp, format!("Expected an SDP offer, got '{}'", offer.sdp_type)));
+        }
+
+        if let Err(e) = peer_connection.set_remote_description(offer).await {
+            let _ = peer_connection.close().await;
+            bail!(SignalingError::new(ErrorCode::BadSdp, format!("Invalid SDP offer: {e}")));
+        }
+
+        Ok(())
+    }
+
+    /// Create an SDP offer for a viewer that joined without one, without waiting for ICE gathering.
+    /// The viewer's answer is applied with `apply_answer`.
+    pub async fn create_offer(
+        &self,
+        peer_connection: &Arc<RTCPeerConnection>
+    ) -> Result<RTCSessionDescription> {
+        let offer = peer_connection.create_offer(None).await?;
+
+        peer_connection.set_local_description(offer).await?;
+
+        peer_connection.local_description().await
+            .ok_or_else(|| anyhow::anyhow!("Failed to get local description"))
+    }
+
+    /// Set the viewer's answer to an offer from `create_offer`, reporting failures as `bad_sdp`
+    pub async fn apply_answer(
+        &self,
+        peer_connection: &Arc<RTCPeerConnection>,
+        answer: RTCSessionDescription
+    ) -> Result<()> {
+        if answer.sdp_type != RTCSdpType::Answer {
+            bail!(SignalingError::new(ErrorCode::BadSdp, format!("Expected an SDP answer, got '{}'", answer.sdp_type)));
+        }
+
+        if let Err(e) = peer_connection.set_remote_description(answer).await {
+            bail!(SignalingError::new(ErrorCode::BadSdp, format!("Invalid SDP answer: {e}")));
+        }
+
+        Ok(())
     }
 
+    /// Create the SDP answer without waiting for ICE gathering.
+    /// Local candidates are trickled to the client by the handler set up in `setup_ice_candidate_handler`.
     pub async fn create_answer(
         &self,
         peer_connection: &Arc<RTCPeerConnection>
     ) -> Result<RTCSessionDescription> {
         let answer = peer_connection.create_answer(None).await?;
 
+        peer_connection.set_local_description(answer).await?;
+
+        peer_connection.local_description().await
+            .ok_or_else(|| anyhow::anyhow!("Failed to get local description"))
+    }
+
+    /// Create the SDP answer once ICE gathering is complete, so it carries every local candidate.
+    /// Used by clients that cannot trickle candidates, such as WHIP and WHEP clients.
+    pub async fn create_gathered_answer(
+        &self,
+        peer_connection: &Arc<RTCPeerConnection>
+    ) -> Result<RTCSessionDescription> {
+        let answer = peer_connection.create_answer(None).await?;
+
         let mut gather_complete = peer_connection.gathering_complete_promise().await;
 
         peer_connection.set_local_description(answer).await?;
 
-        // Block until ICE Gathering is complete, disabling trickle ICE.
-        // We do this because we only can exchange one signaling message
-        // in a production application we should exchange ICE Candidates via OnICECandidate.
+        // Block until ICE Gathering is complete
         let _ = gather_complete.recv().await;
 
         peer_connection.local_description().await
             .ok_or_else(|| anyhow::anyhow!("Failed to get local description"))
     }
 
+    /// Send every gathered local ICE candidate to the client, followed by `end-of-candidates`.
+    /// Must be called before `create_answer`, since gathering starts with the local description.
+    pub fn setup_ice_candidate_handler(
+        &self,
+        broadcast: String,
+        peer_connection: &Arc<RTCPeerConnection>,
+        responder: mpsc::Sender<ServerToClientMsg>
+    ) {
+        peer_connection.on_ice_candidate(Box::new(move |candidate: Option<RTCIceCandidate>| {
+            let broadcast = broadcast.clone();
+            let responder = responder.clone();
+
+            Box::pin(async move {
+                let candidate = match candidate.map(|c| c.to_json()).transpose() {
+                    Ok(candidate) => candidate.map(|mut c| {
+                        // Everything is bundled on the first m-line, and browsers reject an empty mid
+                        c.sdp_mid = None;
+                        c
+                    }),
+                    Err(e) => {
+                        warn!("Broadcast '{}': Failed to serialize local ICE candidate: {}", broadcast, e);
+                        return;
+                    }
+                };
+
+                let msg = match candidate {
+                    Some(candidate) => ServerMessage::Candidate { candidate },
+                    None => ServerMessage::EndOfCandidates,
+                };
+                let _ = responder.send(ServerToClientMsg::Message(msg)).await;
+            })
+        }));
+    }
+
+    /// Add an ICE candidate trickled by the client. An empty candidate marks the end of candidates.
+    pub async fn add_remote_candidate(
+        &self,
+        peer_connection: &Arc<RTCPeerConnection>,
+        candidate: RTCIceCandidateInit
+    ) -> Result<()> {
+        peer_connection.add_ice_candidate(candidate).await?;
+        Ok(())
+    }
+
     async fn setup_conn_state_handler(
         &self,
         broadcast: String,
-        is_broadcaster: bool,
+        role: SessionRole,
         peer_connection: Arc<RTCPeerConnection>,
         broadcast_manager: Arc<BroadcastManager>
     ) {
+        let peer_conn_weak = Arc::downgrade(&peer_connection);
+        let chat = self.chat.clone();
+        let subtitles = self.subtitles.clone();
+        let http_sessions = Arc::clone(&self.http_sessions);
+        let disconnect_timeout = self.settings.broadcaster_disconnect_timeout;
+        let grace_period = self.settings.broadcaster_grace_period;
+
         peer_connection.on_peer_connection_state_change(Box::new(
             move |s: RTCPeerConnectionState| {
                 debug!("Broadcast '{}': Peer connection state has changed: {s}", &broadcast);
 
-                if is_broadcaster {
-                    match s {
-                        RTCPeerConnectionState::Closed => {
-                            let broadcast_manager = Arc::clone(&broadcast_manager);
-                            let broadcast = broadcast.clone();
+                // The data channels may never report closing with their peer connection, and WHIP
+                // and WHEP clients may never DELETE their session
+                if matches!(s, RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed) {
+                    let chat = chat.clone();
+                    let subtitles = subtitles.clone();
+                    let http_sessions = Arc::clone(&http_sessions);
+                    let peer_conn_weak = peer_conn_weak.clone();
+                    let broadcast = broadcast.clone();
+                    let session_id = match &role {
+                        SessionRole::Broadcaster { session_id } | SessionRole::Viewer { session_id } => session_id.clone(),
+                    };
+                    tokio::spawn(async move {
+                        chat.leave(&broadcast, &session_id).await;
+                        subtitles.leave(&broadcast, &session_id).await;
+                        http_sessions.forget(&peer_conn_weak).await;
+                    });
+                }
+
+                match &role {
+                    SessionRole::Broadcaster { session_id } => {
+                        let broadcast_manager = Arc::clone(&broadcast_manager);
+                        let broadcast = broadcast.clone();
+                        let session_id = session_id.clone();
+                        let peer_conn_weak = peer_conn_weak.clone();
 
-                            tokio::spawn(async move {
-                                debug!("Broadcast '{}': Broadcaster disconnected, unregistering", &broadcast);
-                                broadcast_manager.unregister_broadcast(&broadcast).await;
-                            });
+                        match s {
+                            RTCPeerConnectionState::Failed => {
+                                tokio::spawn(async move {
+                                    Self::broadcaster_lost(broadcast, session_id, peer_conn_weak, broadcast_manager, grace_period).await;
+                                });
+                            }
+                            RTCPeerConnectionState::Disconnected => {
+                                // Give the connection a chance to recover before giving it up
+                                tokio::spawn(async move {
+                                    tokio::time::sleep(disconnect_timeout).await;
+
+                                    let still_disconnected = peer_conn_weak.upgrade()
+                                        .is_some_and(|pc| pc.connection_state() == RTCPeerConnectionState::Disconnected);
+                                    if still_disconnected {
+                                        info!("Broadcast '{}': Broadcaster still disconnected after {:?}",
+                                              broadcast, disconnect_timeout);
+                                        Self::broadcaster_lost(broadcast, session_id, peer_conn_weak, broadcast_manager, grace_period).await;
+                                    }
+                                });
+                            }
+                            RTCPeerConnectionState::Closed => {
+                                // A closed broadcaster ends its broadcast right away, unless it was lost
+                                // and the broadcast is waiting for it to resume
+                                tokio::spawn(async move {
+                                    debug!("Broadcast '{}': Broadcaster closed, unregistering", &broadcast);
+                                    broadcast_manager.unregister_broadcast(&broadcast, &session_id).await;
+                                });
+                            }
+                            _ => {}
                         }
-                        _ => {}
+                    }
+                    SessionRole::Viewer { session_id } => {
+                        let broadcast_manager = Arc::clone(&broadcast_manager);
+                        let broadcast = broadcast.clone();
+                        let session_id = session_id.clone();
+
+                        // Failed and closed viewers are removed from the broadcast
+                        tokio::spawn(async move {
+                            broadcast_manager.update_viewer_state(&broadcast, &session_id, s).await;
+
+                            // A new viewer can only start decoding from a keyframe: the cached one
+                            // right away, then a fresh one from the broadcaster
+                            if s == RTCPeerConnectionState::Connected {
+                                for track in broadcast_manager.viewer_tracks(&broadcast, &session_id).await.unwrap_or_default() {
+                                    track.start().await;
+                                    track.request_keyframe().await;
+                                }
+                            }
+                        });
                     }
                 }
 
@@ -125,4 +599,29 @@ impl SessionManager {
             }
         ));
     }
-}
\ No newline at end of file
+
+    /// Keep the broadcast of a lost broadcaster for the grace period, so it can be resumed,
+    /// then close the dead peer connection
+    async fn broadcaster_lost(
+        broadcast: String,
+        session_id: String,
+        peer_conn_weak: Weak<RTCPeerConnection>,
+        broadcast_manager: Arc<BroadcastManager>,
+        grace_period: Duration
+    ) {
+        if !grace_period.is_zero() && broadcast_manager.detach_broadcaster(&broadcast, &session_id).await {
+            let broadcast_manager = Arc::clone(&broadcast_manager);
+            let broadcast = broadcast.clone();
+            let session_id = session_id.clone();
+
+            tokio::spawn(async move {
+                tokio::time::sleep(grace_period).await;
+                broadcast_manager.expire_broadcast(&broadcast, &session_id).await;
+            });
+        }
+
+        if let Some(peer_connection) = peer_conn_weak.upgrade() {
+            let _ = peer_connection.close().await;
+        }
+    }
+}
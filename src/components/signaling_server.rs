@@ -1,4 +1,23 @@
-use crate::prelude::*;
+use crate::{
+    components::{
+        protocol::{
+            self,
+            BroadcastMetadata,
+            ClientMessage,
+            ServerMessage,
+            ErrorCode,
+            SignalingError,
+        },
+        whip,
+        whep,
+        control_api,
+        PlayoutManager,
+        Scheduler,
+        SessionManager,
+    },
+    prelude::*,
+    settings::ApiSettings,
+};
 
 use anyhow::Result;
 use base64::{
@@ -7,52 +26,85 @@ use base64::{
 };
 
 use actix_web::{ rt, web, App, Error, HttpRequest, HttpResponse, HttpServer };
-use actix_ws::AggregatedMessage;
+use actix_ws::{ AggregatedMessage, Session };
 use futures_util::StreamExt;
 
-#[derive(Debug, Clone, serde::Deserialize)]
+/// Base64 payload of the legacy signaling format, where `sdp` is itself base64-encoded JSON.
+/// Only accepted when the server runs with legacy signaling enabled.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct ClientPayload {
     pub action: String,
     pub name: String,
     pub sdp: String,
+    // Metadata attached to a 'broadcast' action
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<BroadcastMetadata>,
 }
 
-/// This message will be sent from the SignalingServer to the ws_handler via the ws_send channel
+/// This message will be sent from the SignalingServer to the ws_handler via the session's responder
 pub enum ServerToClientMsg {
-    Text(String),
+    Message(ServerMessage),
     Close,
 }
 
 /// This message will be sent from the ws_handler to the SignalingServer via the ws_recv channel
-struct SdpMessage {
-    payload: ClientPayload,
-    // Used by the SignalingServer to send a response back to the ws_handler
-    responder: oneshot::Sender<String>,
+pub struct SdpMessage {
+    pub message: ClientMessage,
+    // Used by the SignalingServer to send messages back to the ws_handler
+    pub responder: mpsc::Sender<ServerToClientMsg>,
+    // Messages sent by the client after the offer (trickled ICE candidates)
+    pub incoming: mpsc::Receiver<ClientMessage>,
 }
 
+/// Maximum number of offers that can be queued before the ws_handlers are back-pressured
+const OFFER_QUEUE_SIZE: usize = 64;
+
 pub struct SignalingServer {
     ws_recv_rx: mpsc::Receiver<SdpMessage>,
-    // Receiver to get the session's WebSocket message sender from the ws_handler
-    ws_send_rx: mpsc::Receiver<mpsc::Sender<ServerToClientMsg>>, 
 }
 
-impl SignalingServer {
-    pub async fn new(host: String, port: u16) -> Result<Self> {
-        let (ws_recv_tx, ws_recv_rx) = mpsc::channel::<SdpMessage>(1);
+/// Whether the ws_handler accepts the legacy base64 format next to the JSON protocol
+#[derive(Clone, Copy)]
+struct LegacySignaling(bool);
 
-        // Create a channel for receiving the active WS sender
-        let (ws_send_tx, ws_send_rx) = mpsc::channel::<mpsc::Sender<ServerToClientMsg>>(1);
+impl SignalingServer {
+    pub async fn new(
+        host: String,
+        port: u16,
+        legacy_signaling: bool,
+        session_manager: SessionManager,
+        playout_manager: Arc<PlayoutManager>,
+        scheduler: Arc<Scheduler>,
+        api: ApiSettings
+    ) -> Result<Self> {
+        let (ws_recv_tx, ws_recv_rx) = mpsc::channel::<SdpMessage>(OFFER_QUEUE_SIZE);
 
-        // Inject the Session Sender transmitter into Actix app state
+        // Inject the offer transmitter into Actix app state
         let ws_recv_tx_data = web::Data::new(ws_recv_tx);
-        let ws_send_tx_data = web::Data::new(ws_send_tx);
+        let legacy_data = web::Data::new(LegacySignaling(legacy_signaling));
+
+        // The HTTP endpoints (WHIP and WHEP) talk to the session manager directly
+        let http_sessions_data = web::Data::from(session_manager.http_sessions());
+        let session_manager_data = web::Data::new(session_manager);
+        // The control API starts and stops playouts and manages the schedule
+        let playout_manager_data = web::Data::from(playout_manager);
+        let scheduler_data = web::Data::from(scheduler);
+        let api_data = web::Data::new(api);
 
         tokio::spawn(async move {
             let server = HttpServer::new(move || {
                 App::new()
                     .app_data(ws_recv_tx_data.clone())
-                    .app_data(ws_send_tx_data.clone()) // Inject the new sender
+                    .app_data(legacy_data.clone())
+                    .app_data(session_manager_data.clone())
+                    .app_data(http_sessions_data.clone())
+                    .app_data(playout_manager_data.clone())
+                    .app_data(scheduler_data.clone())
+                    .app_data(api_data.clone())
                     .route("/ws", web::get().to(ws_handler))
+                    .configure(whip::configure)
+                    .configure(whep::configure)
+                    .configure(control_api::configure)
             })
             .bind((host, port))
             .map_err(|e| anyhow!("Failed to bind Actix-Web server: {}", e))?
@@ -64,34 +116,73 @@ impl SignalingServer {
             Ok::<(), anyhow::Error>(())
         });
 
-        Ok(Self {
-            ws_recv_rx,
-            ws_send_rx,
-        })
+        Ok(Self { ws_recv_rx })
     }
 
-    pub async fn wait_for_offer(
-        &mut self,
-    ) -> Result<(ClientPayload, oneshot::Sender<String>)> {
-        let _sender = self.ws_send_rx.recv().await.unwrap();
+    /// Wait for the next offer from any WebSocket session.
+    /// Each offer carries its own responder, so it can be answered from a separate task.
+    pub async fn wait_for_offer(&mut self) -> Result<SdpMessage> {
+        self.ws_recv_rx.recv().await
+            .ok_or_else(|| anyhow!("Signaling server offer channel closed"))
+    }
 
-        let msg = self.ws_recv_rx.recv().await.unwrap();
+    /// Decode a message in the legacy double-base64 format
+    fn decode_legacy(text: &str) -> Result<(String, ClientMessage)> {
+        let payload = serde_json::from_str::<ClientPayload>(&SignalingServer::decode(text)?)?;
 
-        // let desc_data = SignalingServer::decode(&msg.sdp)?;
-        // let offer = serde_json::from_str::<RTCSessionDescription>(&msg.payload.sdp)?;
+        let message = match payload.action.as_str() {
+            "broadcast" => ClientMessage::Broadcast {
+                name: payload.name.clone(),
+                sdp: serde_json::from_str(&SignalingServer::decode(&payload.sdp)?)?,
+                resume_token: None,
+                record: false,
+                metadata: payload.metadata.clone(),
+            },
+            "join" => ClientMessage::Join {
+                name: payload.name.clone(),
+                sdp: Some(serde_json::from_str(&SignalingServer::decode(&payload.sdp)?)?),
+            },
+            "candidate" => ClientMessage::Candidate {
+                candidate: serde_json::from_str(&SignalingServer::decode(&payload.sdp)?)?,
+            },
+            "end-of-candidates" => ClientMessage::EndOfCandidates,
+            action => bail!("Unknown action '{}'", action),
+        };
 
-        Ok((msg.payload, msg.responder))
+        Ok((payload.name, message))
     }
 
-    pub fn encode_sdp(&self, sdp: &RTCSessionDescription) -> Result<String> {
-        let json_str = serde_json::to_string(sdp)?;
-        Ok(SignalingServer::encode(&json_str))
-    }
+    /// Encode a message in the legacy double-base64 format.
+    /// Answers are sent as the bare base64 session description, as older clients expect.
+    fn encode_legacy(name: &str, message: &ServerMessage) -> Result<String> {
+        let (action, data) = match message {
+            ServerMessage::Answer { sdp } => {
+                return Ok(SignalingServer::encode(&serde_json::to_string(sdp)?));
+            }
+            ServerMessage::Offer { sdp } => ("offer", serde_json::to_string(sdp)?),
+            ServerMessage::Config { ice_servers } => ("config", serde_json::to_string(ice_servers)?),
+            ServerMessage::Candidate { candidate } => ("candidate", serde_json::to_string(candidate)?),
+            ServerMessage::EndOfCandidates => ("end-of-candidates", String::new()),
+            ServerMessage::BroadcastStarted { resume_token, .. } => ("broadcast-started", resume_token.clone()),
+            ServerMessage::Layers { rids } => ("layers", serde_json::to_string(rids)?),
+            ServerMessage::Playback { playing, position } => {
+                ("playback", serde_json::json!({ "playing": playing, "position": position }).to_string())
+            }
+            ServerMessage::Subtitles { languages } => ("subtitles", serde_json::to_string(languages)?),
+            ServerMessage::Metadata { metadata, .. } => ("metadata", serde_json::to_string(metadata)?),
+            ServerMessage::ViewerCount { count } => ("viewer-count", count.to_string()),
+            ServerMessage::BroadcastEnded { .. } => ("broadcast-ended", String::new()),
+            ServerMessage::Error { message, .. } => ("error", message.clone()),
+        };
+
+        let payload = ClientPayload {
+            action: action.to_owned(),
+            name: name.to_owned(),
+            sdp: if data.is_empty() { data } else { SignalingServer::encode(&data) },
+            metadata: None,
+        };
 
-    pub fn decode_sdp(&self, encoded_sdp: &str) -> Result<RTCSessionDescription> {
-        let desc_data = SignalingServer::decode(encoded_sdp)?;
-        let sdp = serde_json::from_str::<RTCSessionDescription>(&desc_data)?;
-        Ok(sdp)
+        Ok(SignalingServer::encode(&serde_json::to_string(&payload)?))
     }
 
     fn encode(b: &str) -> String {
@@ -105,28 +196,61 @@ impl SignalingServer {
     }
 }
 
+/// Wire format negotiated by the first message of a WebSocket session
+enum WireFormat {
+    Json,
+    // Holds the broadcast name, which every legacy payload repeats
+    Legacy(String),
+}
+
+impl WireFormat {
+    /// Parse a client message. JSON messages always start with `{`, while legacy ones are base64.
+    fn parse(&mut self, text: &str, legacy_enabled: bool) -> Result<ClientMessage, SignalingError> {
+        if !legacy_enabled || text.trim_start().starts_with('{') {
+            return protocol::parse_client_message(text);
+        }
+
+        let (name, message) = SignalingServer::decode_legacy(text)
+            .map_err(|e| SignalingError::new(ErrorCode::BadMessage, format!("Invalid legacy payload: {e}")))?;
+        *self = WireFormat::Legacy(name);
+
+        Ok(message)
+    }
+
+    fn serialize(&self, message: &ServerMessage) -> Result<String> {
+        match self {
+            WireFormat::Json => Ok(protocol::serialize_server_message(message)?),
+            WireFormat::Legacy(name) => SignalingServer::encode_legacy(name, message),
+        }
+    }
+}
+
+async fn send_message(session: &mut Session, format: &WireFormat, message: &ServerMessage) -> Result<()> {
+    let text = format.serialize(message)?;
+    session.text(text).await?;
+    Ok(())
+}
+
 async fn ws_handler(
     req: HttpRequest,
     stream: web::Payload,
     ws_recv_tx: web::Data<mpsc::Sender<SdpMessage>>,
-    // The ws_handler will use this to send its own message-sender to the SignalingServer
-    ws_send_tx: web::Data<mpsc::Sender<mpsc::Sender<ServerToClientMsg>>>,
+    legacy: web::Data<LegacySignaling>,
+    session_manager: web::Data<SessionManager>,
+    api: web::Data<ApiSettings>,
 ) -> Result<HttpResponse, Error> {
     let (res, mut session, stream) = actix_ws::handle(&req, stream)?;
     let mut stream = stream.aggregate_continuations().max_continuation_size(2_usize.pow(20));
     let ws_recv_tx = ws_recv_tx.get_ref().clone();
-    let ws_send_tx = ws_send_tx.get_ref().clone();
+    let LegacySignaling(legacy_enabled) = *legacy.get_ref();
+    let peer_addr = req.peer_addr();
 
     // Create a channel for the SignalingServer to send messages to this WebSocket session
     let (to_client_tx, mut to_client_rx) = mpsc::channel::<ServerToClientMsg>(10);
 
-    // Send the to_client_tx channel to the SignalingServer thread
-    // This allows the main SignalingServer thread to talk to this specific WebSocket connection
-    if let Err(e) = ws_send_tx.send(to_client_tx).await {
-        error!("Failed to register client sender with SignalingServer: {}", e);
-        let _ = session.close(None).await;
-        return Ok(res); // Return response but stop processing
-    }
+    // Once the offer has been forwarded, any further client messages go to this session's task
+    let mut incoming_tx: Option<mpsc::Sender<ClientMessage>> = None;
+    let mut format = WireFormat::Json;
 
     // Spawn a new task to handle the message stream
     rt::spawn(async move {
@@ -136,36 +260,68 @@ async fn ws_handler(
                 msg = stream.next() => {
                     match msg {
                         Some(Ok(AggregatedMessage::Text(text))) => {
-                            match BASE64_STANDARD.decode(&text) {
-                                Ok(raw) => match String::from_utf8(raw) {
-                                    Ok(payload_json) => match serde_json::from_str::<ClientPayload>(&payload_json) {
-                                        Ok(payload) => {
-                                            let (resp_tx, resp_rx) = oneshot::channel::<String>();
-                                            // SdpMessage expects a parsed payload (not the raw base64)
-                                            let sdp_msg = SdpMessage { payload: payload.clone(), responder: resp_tx };
-
-                                            if let Err(e) = ws_recv_tx.send(sdp_msg).await {
-                                                error!("Failed to send SDP message to signaling server: {}", e);
-                                                break;
-                                            }
-
-                                            match resp_rx.await {
-                                                Ok(answer_sdp) => {
-                                                    if let Err(e) = session.text(answer_sdp).await {
-                                                        error!("Failed to send SDP answer to client: {}", e);
-                                                    }
-                                                    break; 
-                                                }
-                                                Err(e) => {
-                                                    error!("Signaling server failed to provide an answer: {}", e);
-                                                }
-                                            }
+                            let message = match format.parse(&text, legacy_enabled) {
+                                Ok(message) => message,
+                                Err(e) => {
+                                    debug!("Rejected client message: {}", e);
+                                    if let Err(e) = send_message(&mut session, &format, &e.into()).await {
+                                        error!("Failed to send parse error to client: {e}");
+                                        break;
+                                    }
+                                    continue;
+                                }
+                            };
+
+                            // The configuration is answered right away, and can be asked for before the offer
+                            if let ClientMessage::Hello = message {
+                                let config = ServerMessage::Config { ice_servers: session_manager.ice_servers() };
+                                if let Err(e) = send_message(&mut session, &format, &config).await {
+                                    error!("Failed to send session configuration to client: {e}");
+                                    break;
+                                }
+                                continue;
+                            }
+
+                            // Never wait on the session task here, or this loop stops delivering its answers
+                            let message = match &incoming_tx {
+                                None => message,
+                                Some(tx) => match tx.try_send(message) {
+                                    Ok(()) => continue,
+                                    Err(mpsc::error::TrySendError::Full(_)) => {
+                                        let reply = SignalingError::new(ErrorCode::Conflict, "Too many pending messages").into();
+                                        if let Err(e) = send_message(&mut session, &format, &reply).await {
+                                            error!("Failed to send back-pressure error to client: {e}");
+                                            break;
                                         }
-                                        Err(e) => { error!("Failed to parse ClientPayload JSON: {}", e); }
-                                    },
-                                    Err(e) => { error!("Failed to parse UTF-8 from decoded SDP: {}", e); }
+                                        continue;
+                                    }
+                                    // The session has ended, so this starts a new one
+                                    Err(mpsc::error::TrySendError::Closed(message)) => message,
                                 },
-                                Err(e) => { error!("Invalid base64 SDP received: {}", e); }
+                            };
+
+                            // A watch party starts a playout, which needs the same credentials as the control API
+                            if let ClientMessage::WatchParty { token, .. } = &message {
+                                if let Err(e) = control_api::authorize(peer_addr, token.as_deref(), &api) {
+                                    debug!("Rejected watch party: {}", e);
+                                    let reply = SignalingError::from_anyhow(&e).into();
+                                    if let Err(e) = send_message(&mut session, &format, &reply).await {
+                                        error!("Failed to send authorization error to client: {e}");
+                                        break;
+                                    }
+                                    continue;
+                                }
+                            }
+
+                            // The answer is delivered later through to_client_rx, so this session
+                            // keeps serving pings while the offer is processed.
+                            let (tx, rx) = mpsc::channel::<ClientMessage>(32);
+                            let sdp_msg = SdpMessage { message, responder: to_client_tx.clone(), incoming: rx };
+                            incoming_tx = Some(tx);
+
+                            if let Err(e) = ws_recv_tx.send(sdp_msg).await {
+                                error!("Failed to send SDP message to signaling server: {}", e);
+                                break;
                             }
                         }
                         Some(Ok(AggregatedMessage::Ping(msg))) => {
@@ -177,12 +333,23 @@ async fn ws_handler(
                     }
                 }
 
+                /* --- Accept a new offer once the session task has ended, e.g. on a rejected offer --- */
+                _ = async {
+                    match &incoming_tx {
+                        Some(tx) => tx.closed().await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    debug!("Session task ended, the client may send a new offer");
+                    incoming_tx = None;
+                }
+
                 /* --- Handle outgoing server messages --- */
                 out_msg = to_client_rx.recv() => {
                     match out_msg {
-                        Some(ServerToClientMsg::Text(text)) => {
-                            if let Err(e) = session.text(text).await {
-                                error!("Failed to send ServerToClientMsg::Text: {e}");
+                        Some(ServerToClientMsg::Message(message)) => {
+                            if let Err(e) = send_message(&mut session, &format, &message).await {
+                                error!("Failed to send ServerToClientMsg::Message: {e}");
                                 break;
                             }
                         }
@@ -191,11 +358,98 @@ async fn ws_handler(
                 }
             }
         }
- 
+
         // Ensure the session is closed when the task ends
         let _ = session.close(None).await;
-        // NOTE: The mpsc sender for SdpMessage is dropped, signaling to SignalingServer
+        // NOTE: The mpsc sender for incoming client messages is dropped, signaling to the session task
     });
 
     Ok(res)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A legacy message: base64 JSON, whose `sdp` is base64 JSON too
+    fn legacy(action: &str, name: &str, data: &str) -> String {
+        let payload = ClientPayload {
+            action: action.to_owned(),
+            name: name.to_owned(),
+            sdp: SignalingServer::encode(data),
+            metadata: None,
+        };
+        SignalingServer::encode(&serde_json::to_string(&payload).unwrap())
+    }
+
+    #[test]
+    fn parses_json_messages() {
+        let mut format = WireFormat::Json;
+        let message = format.parse(r#" {"version": 1, "type": "hello"}"#, true).unwrap();
+
+        assert!(matches!(message, ClientMessage::Hello));
+        assert!(matches!(format, WireFormat::Json));
+    }
+
+    #[test]
+    fn falls_back_to_legacy_payloads() {
+        let mut format = WireFormat::Json;
+        let text = legacy("broadcast", "main-hall", r#"{"type": "offer", "sdp": "v=0"}"#);
+        let message = format.parse(&text, true).unwrap();
+
+        match message {
+            ClientMessage::Broadcast { name, sdp, resume_token, record, metadata } => {
+                assert_eq!(name, "main-hall");
+                assert_eq!(sdp.sdp_type, RTCSdpType::Offer);
+                assert_eq!(sdp.sdp, "v=0");
+                assert!(resume_token.is_none() && !record && metadata.is_none());
+            }
+            message => panic!("Unexpected message {message:?}"),
+        }
+        assert!(matches!(format, WireFormat::Legacy(name) if name == "main-hall"));
+    }
+
+    #[test]
+    fn parses_legacy_end_of_candidates() {
+        let mut format = WireFormat::Json;
+        let message = format.parse(&legacy("end-of-candidates", "main-hall", ""), true).unwrap();
+        assert!(matches!(message, ClientMessage::EndOfCandidates));
+    }
+
+    #[test]
+    fn rejects_legacy_payloads_when_disabled() {
+        let mut format = WireFormat::Json;
+        let text = legacy("join", "main-hall", r#"{"type": "offer", "sdp": "v=0"}"#);
+
+        let error = format.parse(&text, false).unwrap_err();
+        assert_eq!(error.code, ErrorCode::BadMessage);
+        assert!(matches!(format, WireFormat::Json));
+    }
+
+    #[test]
+    fn rejects_invalid_legacy_payloads() {
+        let mut format = WireFormat::Json;
+        assert_eq!(format.parse("not base64!", true).unwrap_err().code, ErrorCode::BadMessage);
+        assert_eq!(format.parse(&legacy("teleport", "main-hall", ""), true).unwrap_err().code, ErrorCode::BadMessage);
+    }
+
+    #[test]
+    fn encodes_legacy_answers_as_bare_descriptions() {
+        let answer = RTCSessionDescription::default();
+        let text = SignalingServer::encode_legacy("main-hall", &ServerMessage::Answer { sdp: Box::new(answer) }).unwrap();
+
+        let description: serde_json::Value = serde_json::from_str(&SignalingServer::decode(&text).unwrap()).unwrap();
+        assert!(description.get("sdp").is_some());
+    }
+
+    #[test]
+    fn encodes_legacy_messages_with_the_broadcast_name() {
+        let format = WireFormat::Legacy("main-hall".to_owned());
+        let text = format.serialize(&ServerMessage::ViewerCount { count: 3 }).unwrap();
+
+        let payload: ClientPayload = serde_json::from_str(&SignalingServer::decode(&text).unwrap()).unwrap();
+        assert_eq!(payload.action, "viewer-count");
+        assert_eq!(payload.name, "main-hall");
+        assert_eq!(SignalingServer::decode(&payload.sdp).unwrap(), "3");
+    }
+}
@@ -0,0 +1,596 @@
+use crate::{
+    components::{ BroadcastManager, ErrorCode, PlayoutManager, ServerMessage, SignalingError },
+    prelude::*,
+};
+use std::{ collections::BTreeMap, time::Duration };
+
+use anyhow::Result;
+use serde::Serialize;
+use webrtc::data_channel::{ data_channel_init::RTCDataChannelInit, RTCDataChannel };
+
+/// Label of the subtitles data channel
+const SUBTITLES_CHANNEL_LABEL: &str = "subtitles";
+
+/// Id of the subtitles data channel, negotiated out of band like the chat's
+const SUBTITLES_CHANNEL_ID: u16 = 1;
+
+/// How often the cues are checked against the timeline of the broadcasts
+const SUBTITLES_TICK: Duration = Duration::from_millis(100);
+
+/// Timeline step between two ticks beyond which the broadcast jumped, such as after a seek, and
+/// viewers are sent the cues active at the new position
+const SUBTITLES_MAX_STEP: Duration = Duration::from_secs(1);
+
+/// Longest display time of a live cue, in seconds
+const SUBTITLES_MAX_LIVE_DURATION: f64 = 30.0;
+
+/// Longest subtitle language tag, in characters
+const SUBTITLES_MAX_LANGUAGE_LENGTH: usize = 35;
+
+/// A cue of a subtitle track, timed on the broadcast's timeline
+#[derive(Debug, Clone)]
+struct Cue {
+    start: Duration,
+    end: Duration,
+    text: String,
+    // Sent by the broadcaster, pushed to viewers as it arrives
+    live: bool,
+}
+
+/// Messages sent by the server over the subtitles data channel
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+enum SubtitleEvent<'a> {
+    /// Show a cue from now on, for `duration` seconds
+    Cue { language: &'a str, text: &'a str, duration: f64 },
+    /// Hide every cue shown, before the cues of a new timeline position or language
+    Clear,
+}
+
+/// A subtitle track of a broadcast, as reported by the control API
+#[derive(Debug, Clone, Serialize)]
+pub struct SubtitleTrackInfo {
+    pub language: String,
+    pub cues: usize,
+}
+
+/// Subtitle track picked by a viewer
+enum SubtitleSelection {
+    // The broadcast's first language, whichever it is when tracks are added or removed
+    Default,
+    Off,
+    Language(String),
+}
+
+struct SubtitleViewer {
+    channel: Arc<RTCDataChannel>,
+    selection: SubtitleSelection,
+    // Cleared when the viewer joins or changes language, to be sent the active cues
+    synced: bool,
+}
+
+/// Subtitles of a broadcast, dropped once it has neither tracks nor viewers.
+/// Tracks are kept by broadcast name, so they can be loaded before the broadcast goes live.
+#[derive(Default)]
+struct SubtitleRoom {
+    // Cues of each language, in order of start time
+    tracks: BTreeMap<String, Vec<Cue>>,
+    viewers: HashMap<String, SubtitleViewer>,
+    // Position of the broadcast's timeline at the last tick
+    clock: Option<Duration>,
+}
+
+impl SubtitleRoom {
+    fn languages(&self) -> Vec<String> {
+        self.tracks.keys().cloned().collect()
+    }
+
+    fn resync(&mut self) {
+        for viewer in self.viewers.values_mut() {
+            viewer.synced = false;
+        }
+    }
+
+    /// Move the room's clock to `time` and collect the messages each viewer is sent: the cues
+    /// that started since the last tick, or every active cue after a jump or a language change
+    fn advance(&mut self, time: Option<Duration>) -> Vec<(Arc<RTCDataChannel>, Vec<String>)> {
+        let Some(time) = time else {
+            self.clock = None;
+            return Vec::new();
+        };
+        let jumped = self.clock.is_none_or(|clock| time < clock || time - clock > SUBTITLES_MAX_STEP);
+        let clock = self.clock.replace(time).unwrap_or_default();
+
+        let mut deliveries = Vec::new();
+        for viewer in self.viewers.values_mut() {
+            let resync = jumped || !viewer.synced;
+            viewer.synced = true;
+
+            let mut messages = Vec::new();
+            if resync {
+                messages.extend(serde_json::to_string(&SubtitleEvent::Clear));
+            }
+
+            if let Some((language, cues)) = selected_track(&self.tracks, &viewer.selection) {
+                let started = cues.partition_point(|cue| cue.start <= time);
+                let shown: Box<dyn Iterator<Item = &Cue>> = match resync {
+                    true => Box::new(cues[..started].iter()),
+                    // Live cues were pushed as they arrived
+                    false => Box::new(cues[cues.partition_point(|cue| cue.start <= clock)..started].iter().filter(|cue| !cue.live)),
+                };
+                messages.extend(shown.filter_map(|cue| cue_message(language, cue, time)));
+            }
+
+            if !messages.is_empty() {
+                deliveries.push((Arc::clone(&viewer.channel), messages));
+            }
+        }
+        deliveries
+    }
+}
+
+/// The track a viewer's selection points to, if it is loaded
+fn selected_track<'a>(
+    tracks: &'a BTreeMap<String, Vec<Cue>>,
+    selection: &SubtitleSelection,
+) -> Option<(&'a str, &'a [Cue])> {
+    let (language, cues) = match selection {
+        SubtitleSelection::Default => tracks.iter().next()?,
+        SubtitleSelection::Off => return None,
+        SubtitleSelection::Language(language) => tracks.get_key_value(language)?,
+    };
+    Some((language.as_str(), cues.as_slice()))
+}
+
+/// The message showing a cue for the rest of its duration, None if it is over at `time`
+fn cue_message(language: &str, cue: &Cue, time: Duration) -> Option<String> {
+    if cue.end <= time {
+        return None;
+    }
+    let duration = (cue.end - time.max(cue.start)).as_secs_f64();
+    serde_json::to_string(&SubtitleEvent::Cue { language, text: &cue.text, duration }).ok()
+}
+
+type SubtitleRooms = Arc<Mutex<HashMap<String, SubtitleRoom>>>;
+
+/// Delivers the subtitles of broadcasts to their viewers, over a data channel the server
+/// negotiates on each viewer's peer connection. Cues come from WebVTT files or live from the
+/// broadcaster, and are sent as the broadcast's timeline reaches them.
+#[derive(Clone)]
+pub struct SubtitleManager {
+    broadcast_manager: Arc<BroadcastManager>,
+    playout_manager: Arc<PlayoutManager>,
+    rooms: SubtitleRooms,
+}
+
+impl SubtitleManager {
+    pub fn new(broadcast_manager: Arc<BroadcastManager>, playout_manager: Arc<PlayoutManager>) -> Self {
+        let subtitles = Self {
+            broadcast_manager,
+            playout_manager,
+            rooms: Arc::new(Mutex::new(HashMap::new())),
+        };
+
+        tokio::spawn(subtitles.clone().run());
+        subtitles
+    }
+
+    /// Create the subtitles data channel of a viewer's peer connection, before the SDP exchange.
+    /// The viewer is sent cues once the channel opens, until it closes.
+    pub async fn attach(&self, broadcast: &str, session_id: &str, peer_connection: &RTCPeerConnection) -> Result<()> {
+        let channel = peer_connection.create_data_channel(SUBTITLES_CHANNEL_LABEL, Some(RTCDataChannelInit {
+            ordered: Some(true),
+            negotiated: Some(SUBTITLES_CHANNEL_ID),
+            ..Default::default()
+        })).await?;
+
+        let subtitles = self.clone();
+        let broadcast = broadcast.to_owned();
+        let session_id = session_id.to_owned();
+        let weak_channel = Arc::downgrade(&channel);
+
+        channel.on_open(Box::new({
+            let (subtitles, broadcast, session_id) = (subtitles.clone(), broadcast.clone(), session_id.clone());
+            move || {
+                let (subtitles, broadcast, session_id) = (subtitles.clone(), broadcast.clone(), session_id.clone());
+                let channel = weak_channel.clone();
+                Box::pin(async move {
+                    if let Some(channel) = channel.upgrade() {
+                        subtitles.join(&broadcast, session_id, channel).await;
+                    }
+                })
+            }
+        }));
+
+        channel.on_close(Box::new(move || {
+            let (subtitles, broadcast, session_id) = (subtitles.clone(), broadcast.clone(), session_id.clone());
+            Box::pin(async move {
+                subtitles.leave(&broadcast, &session_id).await;
+            })
+        }));
+
+        Ok(())
+    }
+
+    /// Add a viewer to the subtitles of a broadcast, showing its first language
+    async fn join(&self, broadcast: &str, session_id: String, channel: Arc<RTCDataChannel>) {
+        let mut rooms = self.rooms.lock().await;
+        let room = rooms.entry(broadcast.to_owned()).or_default();
+        room.viewers.insert(session_id, SubtitleViewer { channel, selection: SubtitleSelection::Default, synced: false });
+        debug!("Broadcast '{}': Subtitles viewer joined, {} viewer(s)", broadcast, room.viewers.len());
+    }
+
+    /// Remove a viewer from the subtitles of a broadcast
+    pub async fn leave(&self, broadcast: &str, session_id: &str) {
+        let mut rooms = self.rooms.lock().await;
+        let Some(room) = rooms.get_mut(broadcast) else {
+            return;
+        };
+
+        if room.viewers.remove(session_id).is_some() {
+            debug!("Broadcast '{}': Subtitles viewer left, {} viewer(s)", broadcast, room.viewers.len());
+        }
+        if room.viewers.is_empty() && room.tracks.is_empty() {
+            rooms.remove(broadcast);
+        }
+    }
+
+    /// Languages of the subtitle tracks of a broadcast
+    pub async fn languages(&self, broadcast: &str) -> Vec<String> {
+        self.rooms.lock().await.get(broadcast).map(SubtitleRoom::languages).unwrap_or_default()
+    }
+
+    /// Subtitle tracks of a broadcast, with their number of cues
+    pub async fn tracks(&self, broadcast: &str) -> Vec<SubtitleTrackInfo> {
+        let rooms = self.rooms.lock().await;
+        let Some(room) = rooms.get(broadcast) else {
+            return Vec::new();
+        };
+
+        room.tracks.iter()
+            .map(|(language, cues)| SubtitleTrackInfo { language: language.clone(), cues: cues.len() })
+            .collect()
+    }
+
+    /// Load a WebVTT file as the subtitle track of a broadcast in `language`, replacing any
+    /// track in that language. Its cue times refer to the broadcast's timeline.
+    pub async fn load(&self, broadcast: &str, language: &str, webvtt: &str) -> Result<SubtitleTrackInfo> {
+        validate_language(language)?;
+        let cues = parse_webvtt(webvtt)?;
+        if cues.is_empty() {
+            bail!(SignalingError::new(ErrorCode::BadMessage, "The WebVTT file has no cues"));
+        }
+        let info = SubtitleTrackInfo { language: language.to_owned(), cues: cues.len() };
+
+        let languages = {
+            let mut rooms = self.rooms.lock().await;
+            let room = rooms.entry(broadcast.to_owned()).or_default();
+            let added = room.tracks.insert(language.to_owned(), cues).is_none();
+            room.resync();
+            added.then(|| room.languages())
+        };
+
+        info!("Broadcast '{}': Loaded {} subtitle cue(s) in '{}'", broadcast, info.cues, language);
+        if let Some(languages) = languages {
+            self.broadcast_manager.notify_viewers(broadcast, ServerMessage::Subtitles { languages }).await;
+        }
+        Ok(info)
+    }
+
+    /// Remove the subtitle track of a broadcast in `language`
+    pub async fn remove(&self, broadcast: &str, language: &str) -> Result<()> {
+        let languages = {
+            let mut rooms = self.rooms.lock().await;
+            let Some(room) = rooms.get_mut(broadcast).filter(|room| room.tracks.contains_key(language)) else {
+                bail!(SignalingError::new(ErrorCode::NotFound, format!("No subtitles in '{}' for broadcast '{}'", language, broadcast)));
+            };
+
+            room.tracks.remove(language);
+            room.resync();
+            let languages = room.languages();
+            if room.viewers.is_empty() && room.tracks.is_empty() {
+                rooms.remove(broadcast);
+            }
+            languages
+        };
+
+        info!("Broadcast '{}': Removed the subtitles in '{}'", broadcast, language);
+        self.broadcast_manager.notify_viewers(broadcast, ServerMessage::Subtitles { languages }).await;
+        Ok(())
+    }
+
+    /// Show a viewer the subtitles in `language`, or none
+    pub async fn select(&self, broadcast: &str, session_id: &str, language: Option<&str>) -> Result<()> {
+        let mut rooms = self.rooms.lock().await;
+        let Some(room) = rooms.get_mut(broadcast) else {
+            bail!(SignalingError::new(ErrorCode::NotFound, "The subtitles data channel is not open"));
+        };
+        if let Some(language) = language.filter(|language| !room.tracks.contains_key(*language)) {
+            bail!(SignalingError::new(ErrorCode::NotFound, format!("No subtitles in '{}'", language)));
+        }
+        let Some(viewer) = room.viewers.get_mut(session_id) else {
+            bail!(SignalingError::new(ErrorCode::NotFound, "The subtitles data channel is not open"));
+        };
+
+        viewer.selection = match language {
+            Some(language) => SubtitleSelection::Language(language.to_owned()),
+            None => SubtitleSelection::Off,
+        };
+        viewer.synced = false;
+        Ok(())
+    }
+
+    /// Show a cue from the broadcaster right away, for `duration` seconds, to the viewers of
+    /// `language`. The cue is timed at the current position of the broadcast, so viewers joining
+    /// while it is shown get it too.
+    pub async fn add_cue(&self, broadcast: &str, language: &str, text: String, duration: f64) -> Result<()> {
+        validate_language(language)?;
+        if text.trim().is_empty() {
+            bail!(SignalingError::new(ErrorCode::BadMessage, "Empty subtitle cue"));
+        }
+        if !(duration > 0.0 && duration <= SUBTITLES_MAX_LIVE_DURATION) {
+            bail!(SignalingError::new(
+                ErrorCode::BadMessage,
+                format!("Live cues are shown between 0 and {} seconds", SUBTITLES_MAX_LIVE_DURATION),
+            ));
+        }
+        let Some(time) = self.media_time(broadcast).await else {
+            bail!(SignalingError::new(ErrorCode::NotFound, format!("Broadcast '{}' not found", broadcast)));
+        };
+
+        let cue = Cue { start: time, end: time + Duration::from_secs_f64(duration), text, live: true };
+        let message = cue_message(language, &cue, time);
+
+        let (channels, languages) = {
+            let mut rooms = self.rooms.lock().await;
+            let room = rooms.entry(broadcast.to_owned()).or_default();
+            let added = !room.tracks.contains_key(language);
+
+            // Live cues are only kept while they are shown
+            let cues = room.tracks.entry(language.to_owned()).or_default();
+            cues.retain(|cue| !cue.live || cue.end > time);
+            let position = cues.partition_point(|c| c.start <= cue.start);
+            cues.insert(position, cue);
+
+            // Viewers about to be synced get the cue with the active ones
+            let channels: Vec<_> = room.viewers.values()
+                .filter(|viewer| viewer.synced)
+                .filter(|viewer| selected_track(&room.tracks, &viewer.selection).is_some_and(|(l, _)| l == language))
+                .map(|viewer| Arc::clone(&viewer.channel))
+                .collect();
+            (channels, added.then(|| room.languages()))
+        };
+
+        if let Some(message) = message {
+            for channel in channels {
+                let _ = channel.send_text(message.clone()).await;
+            }
+        }
+        if let Some(languages) = languages {
+            self.broadcast_manager.notify_viewers(broadcast, ServerMessage::Subtitles { languages }).await;
+        }
+        Ok(())
+    }
+
+    /// Position of a broadcast's timeline: the film position of a broadcast played out by the
+    /// server, which its subtitle files are timed against, or else the RTP timeline of its tracks.
+    /// None if the broadcast is not live.
+    async fn media_time(&self, broadcast: &str) -> Option<Duration> {
+        if let Some(state) = self.playout_manager.playback(broadcast).await {
+            return Some(Duration::from_secs_f64(state.position.max(0.0)));
+        }
+
+        let tracks = self.broadcast_manager.get_broadcast(broadcast).await?;
+        Some(tracks.first()?.media_time().await)
+    }
+
+    /// Send the cues of every broadcast as its timeline reaches them
+    async fn run(self) {
+        let mut interval = tokio::time::interval(SUBTITLES_TICK);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+
+            let broadcasts: Vec<String> = self.rooms.lock().await.iter()
+                .filter(|(_, room)| !room.viewers.is_empty() && !room.tracks.is_empty())
+                .map(|(broadcast, _)| broadcast.clone())
+                .collect();
+
+            for broadcast in broadcasts {
+                let time = self.media_time(&broadcast).await;
+                let deliveries = match self.rooms.lock().await.get_mut(&broadcast) {
+                    Some(room) => room.advance(time),
+                    None => continue,
+                };
+
+                for (channel, messages) in deliveries {
+                    for message in messages {
+                        if channel.send_text(message).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Check a subtitle language tag, such as `en` or `pt-BR`
+fn validate_language(language: &str) -> Result<()> {
+    let valid = !language.is_empty()
+        && language.len() <= SUBTITLES_MAX_LANGUAGE_LENGTH
+        && language.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+    if !valid {
+        bail!(SignalingError::new(ErrorCode::BadMessage, format!("Invalid subtitle language '{}'", language)));
+    }
+    Ok(())
+}
+
+/// Parse the cues of a WebVTT file, skipping its header, notes, styles and regions.
+/// Cue settings are ignored and the cue text is kept with its markup.
+fn parse_webvtt(webvtt: &str) -> Result<Vec<Cue>> {
+    let mut lines = webvtt.trim_start_matches('\u{feff}').lines().enumerate().peekable();
+    match lines.next() {
+        Some((_, header)) if header == "WEBVTT" || header.starts_with("WEBVTT ") || header.starts_with("WEBVTT\t") => {}
+        _ => bail!(SignalingError::new(ErrorCode::BadMessage, "Not a WebVTT file")),
+    }
+
+    let mut cues = Vec::new();
+    let mut block = Vec::new();
+    loop {
+        // Blocks are separated by blank lines
+        block.clear();
+        while lines.peek().is_some_and(|(_, line)| line.trim().is_empty()) {
+            lines.next();
+        }
+        while let Some((number, line)) = lines.next_if(|(_, line)| !line.trim().is_empty()) {
+            block.push((number + 1, line));
+        }
+        let Some(&(number, first)) = block.first() else {
+            break;
+        };
+
+        if ["NOTE", "STYLE", "REGION"].iter().any(|keyword| first == *keyword || first.starts_with(&format!("{keyword} "))) {
+            continue;
+        }
+
+        // The timing line comes first, or after the cue identifier
+        let Some(timing) = block.iter().take(2).position(|(_, line)| line.contains("-->")) else {
+            bail!(SignalingError::new(ErrorCode::BadMessage, format!("Missing cue timings at line {}", number)));
+        };
+        let (number, line) = block[timing];
+        let (start, end) = parse_cue_timings(line).ok_or_else(|| {
+            SignalingError::new(ErrorCode::BadMessage, format!("Invalid cue timings at line {}", number))
+        })?;
+
+        let text = block[timing + 1..].iter().map(|(_, line)| *line).collect::<Vec<_>>().join("\n");
+        cues.push(Cue { start, end, text, live: false });
+    }
+
+    cues.sort_by_key(|cue| cue.start);
+    Ok(cues)
+}
+
+/// Parse a `00:01:02.500 --> 00:01:04.000 line:90%` timing line
+fn parse_cue_timings(line: &str) -> Option<(Duration, Duration)> {
+    let (start, rest) = line.split_once("-->")?;
+    let start = parse_timestamp(start.trim())?;
+    let end = parse_timestamp(rest.split_whitespace().next()?)?;
+    (end > start).then_some((start, end))
+}
+
+/// Parse a `hh:mm:ss.ttt` or `mm:ss.ttt` WebVTT timestamp
+fn parse_timestamp(timestamp: &str) -> Option<Duration> {
+    let (clock, millis) = timestamp.split_once('.')?;
+    if millis.len() != 3 {
+        return None;
+    }
+    let millis: u64 = millis.parse().ok()?;
+
+    let fields: Vec<u64> = clock.split(':').map(|field| field.parse().ok()).collect::<Option<_>>()?;
+    let (hours, minutes, seconds) = match fields[..] {
+        [hours, minutes, seconds] => (hours, minutes, seconds),
+        [minutes, seconds] => (0, minutes, seconds),
+        _ => return None,
+    };
+    if minutes >= 60 || seconds >= 60 {
+        return None;
+    }
+
+    // The hours are unbounded, so an absurd timestamp is rejected rather than overflowing
+    let seconds = hours.checked_mul(3600)?.checked_add(minutes * 60 + seconds)?;
+    Some(Duration::from_millis(seconds.checked_mul(1000)?.checked_add(millis)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_code(result: Result<Vec<Cue>>) -> ErrorCode {
+        SignalingError::from_anyhow(&result.map(|_| ()).unwrap_err()).code
+    }
+
+    #[test]
+    fn parses_timestamps() {
+        assert_eq!(parse_timestamp("00:01:02.500"), Some(Duration::from_millis(62_500)));
+        assert_eq!(parse_timestamp("01:02.500"), Some(Duration::from_millis(62_500)));
+        assert_eq!(parse_timestamp("100:00:00.000"), Some(Duration::from_secs(360_000)));
+    }
+
+    #[test]
+    fn rejects_invalid_timestamps() {
+        for timestamp in ["", "01:02", "01:02.5", "01:02.5000", "00:60:00.000", "00:00:60.000", "1:2:3:4.000", "aa:00.000"] {
+            assert_eq!(parse_timestamp(timestamp), None, "{timestamp}");
+        }
+    }
+
+    #[test]
+    fn rejects_overflowing_timestamps() {
+        assert_eq!(parse_timestamp(&format!("{}:00:00.000", u64::MAX)), None);
+        assert_eq!(parse_timestamp(&format!("{}:00:00.000", u64::MAX / 3600)), None);
+    }
+
+    #[test]
+    fn parses_cue_timings_with_settings() {
+        let timings = parse_cue_timings("00:01.000 --> 00:02.500 line:90% align:center");
+        assert_eq!(timings, Some((Duration::from_secs(1), Duration::from_millis(2500))));
+
+        // A cue must end after it starts
+        assert_eq!(parse_cue_timings("00:02.000 --> 00:01.000"), None);
+        assert_eq!(parse_cue_timings("00:01.000 --> 00:01.000"), None);
+    }
+
+    #[test]
+    fn parses_cues() {
+        let webvtt = "\u{feff}WEBVTT - Metropolis\n\
+                      \n\
+                      NOTE Translated by the projectionist\n\
+                      \n\
+                      STYLE\n\
+                      ::cue { color: yellow }\n\
+                      \n\
+                      intro\n\
+                      00:00:05.000 --> 00:00:07.000\n\
+                      <i>The city</i>\n\
+                      of the future\n\
+                      \n\
+                      \n\
+                      00:00:01.000 --> 00:00:03.000 align:start\n\
+                      Prologue\n";
+        let cues = parse_webvtt(webvtt).unwrap();
+
+        // Sorted by start time
+        assert_eq!(cues.len(), 2);
+        assert_eq!(cues[0].start, Duration::from_secs(1));
+        assert_eq!(cues[0].end, Duration::from_secs(3));
+        assert_eq!(cues[0].text, "Prologue");
+        assert_eq!(cues[1].start, Duration::from_secs(5));
+        assert_eq!(cues[1].text, "<i>The city</i>\nof the future");
+        assert!(cues.iter().all(|cue| !cue.live));
+    }
+
+    #[test]
+    fn parses_files_without_cues() {
+        assert!(parse_webvtt("WEBVTT\n").unwrap().is_empty());
+        assert!(parse_webvtt("WEBVTT\r\n\r\nNOTE nothing yet\r\n").unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_files() {
+        assert_eq!(error_code(parse_webvtt("")), ErrorCode::BadMessage);
+        assert_eq!(error_code(parse_webvtt("WEBVTTX\n")), ErrorCode::BadMessage);
+        assert_eq!(error_code(parse_webvtt("1\n00:00:01.000 --> 00:00:02.000\nNo header\n")), ErrorCode::BadMessage);
+        assert_eq!(error_code(parse_webvtt("WEBVTT\n\nJust some text\n")), ErrorCode::BadMessage);
+        assert_eq!(error_code(parse_webvtt("WEBVTT\n\n00:00:01 --> 00:00:02\nText\n")), ErrorCode::BadMessage);
+    }
+
+    #[test]
+    fn validates_languages() {
+        assert!(validate_language("en").is_ok());
+        assert!(validate_language("pt-BR").is_ok());
+        assert!(validate_language("").is_err());
+        assert!(validate_language("en_US").is_err());
+        assert!(validate_language(&"a".repeat(SUBTITLES_MAX_LANGUAGE_LENGTH + 1)).is_err());
+    }
+}
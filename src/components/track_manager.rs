@@ -1,153 +1,796 @@
-use std::time::Duration;
+use std::time::{ Duration, Instant };
 
-use crate::prelude::*;
+use crate::{ components::keyframe_cache::{ KeyframeCache, MAX_CACHED_PACKETS }, prelude::* };
 use anyhow::Result;
+use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
+
+/// Minimum time between two keyframe requests sent to a broadcaster
+const KEYFRAME_REQUEST_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Window over which the bitrate of a relay track is measured
+const BITRATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Packets queued for a viewer, beyond which a slow viewer loses packets instead of holding up the others.
+/// Leaves room for the live packets next to a full keyframe cache priming the viewer.
+const VIEWER_QUEUE_SIZE: usize = MAX_CACHED_PACKETS + 512;
+
+/// Broadcaster video track that keyframes are requested from
+struct KeyframeSource {
+    peer_connection: Weak<RTCPeerConnection>,
+    media_ssrc: u32,
+}
+
+/// Sends PLIs to the broadcaster on behalf of the viewers. Requests arriving while one is pending
+/// or within `KEYFRAME_REQUEST_INTERVAL` of the last PLI are merged into a single PLI.
+struct KeyframeRequester {
+    source: Arc<Mutex<Option<KeyframeSource>>>,
+    request_tx: mpsc::Sender<()>,
+}
+
+impl KeyframeRequester {
+    fn new(broadcast: String) -> Self {
+        let source = Arc::new(Mutex::new(None::<KeyframeSource>));
+        let (request_tx, mut request_rx) = mpsc::channel::<()>(1);
+
+        let task_source = Arc::clone(&source);
+        // Ends once the relay track, and with it `request_tx`, is dropped
+        tokio::spawn(async move {
+            while request_rx.recv().await.is_some() {
+                let target = task_source.lock().await
+                    .as_ref()
+                    .and_then(|s| s.peer_connection.upgrade().map(|pc| (pc, s.media_ssrc)));
+
+                if let Some((peer_connection, media_ssrc)) = target {
+                    debug!("Broadcast '{}': Sending PLI for video (SSRC: {})", broadcast, media_ssrc);
+
+                    let pli = PictureLossIndication { sender_ssrc: 0, media_ssrc };
+                    if let Err(e) = peer_connection.write_rtcp(&[Box::new(pli)]).await {
+                        debug!("Broadcast '{}': PLI send failed: {}", broadcast, e);
+                    }
+                }
+
+                tokio::time::sleep(KEYFRAME_REQUEST_INTERVAL).await;
+            }
+        });
+
+        Self { source, request_tx }
+    }
+
+    async fn set_source(&self, peer_connection: Weak<RTCPeerConnection>, media_ssrc: u32) {
+        *self.source.lock().await = Some(KeyframeSource { peer_connection, media_ssrc });
+    }
+
+    fn request(&self) {
+        // A full channel means a request is already pending
+        let _ = self.request_tx.try_send(());
+    }
+}
+
+/// Sequence number and timestamp rewriting of an RTP stream fed by successive inputs.
+/// Each input starts at its own random values, so later inputs are rebased to continue
+/// where the previous one stopped.
+#[derive(Default)]
+struct RtpRewriter {
+    // Set when a new input takes over, the offsets are computed from its first packet
+    rebase: bool,
+    seq_offset: u16,
+    ts_offset: u32,
+    last_seq: u16,
+    last_ts: u32,
+    last_written: Option<Instant>,
+}
+
+impl RtpRewriter {
+    fn rewrite(&mut self, rtp: &mut webrtc::rtp::packet::Packet, clock_rate: u32) {
+        // The first input is written as is, later ones continue the previous output
+        if std::mem::take(&mut self.rebase) {
+            if let Some(last_written) = self.last_written {
+                self.seq_offset = self.last_seq.wrapping_add(1).wrapping_sub(rtp.header.sequence_number);
+
+                let elapsed = (last_written.elapsed().as_millis() as u64 * clock_rate as u64 / 1000) as u32;
+                self.ts_offset = self.last_ts.wrapping_add(elapsed.max(1)).wrapping_sub(rtp.header.timestamp);
+            }
+        }
+
+        rtp.header.sequence_number = rtp.header.sequence_number.wrapping_add(self.seq_offset);
+        rtp.header.timestamp = rtp.header.timestamp.wrapping_add(self.ts_offset);
+        self.last_seq = rtp.header.sequence_number;
+        self.last_ts = rtp.header.timestamp;
+        self.last_written = Some(Instant::now());
+    }
+}
+
+/// The broadcaster session feeding a relay track
+#[derive(Default)]
+struct RelayInput {
+    // Broadcaster session currently allowed to write to the track
+    source: u64,
+    rewriter: RtpRewriter,
+    // RTP clock ticks elapsed since the track's first packet, across every source
+    elapsed: u64,
+}
+
+/// Incoming bitrate of a relay track, measured over windows of `BITRATE_WINDOW`
+#[derive(Default)]
+struct BitrateMeter {
+    window_start: Option<Instant>,
+    window_bytes: u64,
+    bitrate: u64,
+}
+
+impl BitrateMeter {
+    fn record(&mut self, bytes: usize) {
+        let now = Instant::now();
+        let window_start = *self.window_start.get_or_insert(now);
+        self.window_bytes += bytes as u64;
+
+        let elapsed = now.duration_since(window_start);
+        if elapsed >= BITRATE_WINDOW {
+            self.bitrate = self.window_bytes * 8 * 1000 / elapsed.as_millis() as u64;
+            self.window_start = Some(now);
+            self.window_bytes = 0;
+        }
+    }
+
+    /// Bits per second, 0 once the track stopped receiving packets
+    fn bitrate(&self) -> u64 {
+        match self.window_start {
+            Some(window_start) if window_start.elapsed() < 2 * BITRATE_WINDOW => self.bitrate,
+            _ => 0,
+        }
+    }
+}
+
+/// A viewer's copy of a media section of the broadcast. Each viewer gets its own local track,
+/// so it can be primed with the cached keyframe and switch simulcast layers in its own
+/// sequence number space.
+pub struct ViewerTrack {
+    pub kind: RTPCodecType,
+    pub local_track: Arc<TrackLocalStaticRTP>,
+    // Every simulcast layer of the media section, a single one without simulcast
+    layers: Vec<Arc<RelayTrack>>,
+    output: Arc<ViewerOutput>,
+}
+
+impl ViewerTrack {
+    /// Create a viewer's copies of the broadcast's tracks, one per media section whatever its
+    /// number of simulcast layers
+    pub fn subscribe(tracks: &[Arc<RelayTrack>]) -> Vec<ViewerTrack> {
+        let mut sections: Vec<Vec<Arc<RelayTrack>>> = Vec::new();
+        for track in tracks {
+            match sections.iter_mut().find(|layers| layers[0].kind == track.kind && layers[0].mid == track.mid) {
+                Some(layers) => layers.push(Arc::clone(track)),
+                None => sections.push(vec![Arc::clone(track)]),
+            }
+        }
+
+        sections.into_iter().map(Self::new).collect()
+    }
+
+    fn new(layers: Vec<Arc<RelayTrack>>) -> Self {
+        let first = &layers[0];
+        let local_track = Arc::new(TrackLocalStaticRTP::new(
+            first.codec.clone(),
+            format!("{}-{}", first.kind, first.mid),
+            "webrtc-rs".to_owned(),
+        ));
+
+        // Start with the best layer, the viewer's feedback steps it down if needed
+        let target = Self::best_layer(&layers, None).unwrap_or(first).rid.clone();
+
+        // Without a keyframe cache there is nothing to prime, so forwarding starts right away
+        let primed = first.keyframe_cache.is_some();
+        let output = Arc::new(ViewerOutput {
+            queue: ViewerOutput::spawn_writer(Arc::clone(&local_track)),
+            state: Mutex::new(ViewerOutputState {
+                started: !primed,
+                active: (!primed).then(|| target.clone()),
+                target,
+                pinned: None,
+                estimate: None,
+                remb_seen: false,
+                rewriter: RtpRewriter::default(),
+            }),
+        });
+        for layer in &layers {
+            layer.outputs.lock().unwrap_or_else(|e| e.into_inner()).push(Arc::clone(&output));
+        }
+
+        Self { kind: first.kind, local_track, layers, output }
+    }
+
+    /// The live layer with the highest bitrate within `estimate`, or else the lowest live layer
+    fn best_layer(layers: &[Arc<RelayTrack>], estimate: Option<u64>) -> Option<&Arc<RelayTrack>> {
+        let mut live: Vec<_> = layers.iter()
+            .map(|layer| (layer.bitrate(), layer))
+            .filter(|(bitrate, _)| *bitrate > 0)
+            .collect();
+        live.sort_by_key(|(bitrate, _)| std::cmp::Reverse(*bitrate));
+
+        match estimate {
+            Some(estimate) => live.iter().find(|(bitrate, _)| *bitrate <= estimate).or(live.last()),
+            None => live.first(),
+        }.map(|(_, layer)| *layer)
+    }
+
+    fn layer(&self, rid: &str) -> Option<&Arc<RelayTrack>> {
+        self.layers.iter().find(|layer| layer.rid == rid)
+    }
+
+    /// The rids of the simulcast layers, from the highest to the lowest bitrate.
+    /// Empty without simulcast.
+    pub fn layers(&self) -> Vec<String> {
+        if self.layers.len() < 2 {
+            return Vec::new();
+        }
+
+        let mut layers: Vec<_> = self.layers.iter().map(|layer| (layer.bitrate(), layer.rid.clone())).collect();
+        layers.sort_by_key(|(bitrate, _)| std::cmp::Reverse(*bitrate));
+        layers.into_iter().map(|(_, rid)| rid).collect()
+    }
+
+    /// Start forwarding to a viewer whose connection is up, beginning with the cached packets
+    /// since the last keyframe
+    pub async fn start(&self) {
+        let target = self.output.state.lock().await.target.clone();
+        let Some(layer) = self.layer(&target) else {
+            return;
+        };
+        let Some(keyframe_cache) = &layer.keyframe_cache else {
+            return;
+        };
+
+        // The relay holds the cache while fanning a packet out, so holding it until the viewer
+        // is live hands every packet to the viewer once, either from the cache or live
+        let keyframe_cache = keyframe_cache.lock().await;
+
+        let mut state = self.output.state.lock().await;
+        if state.started {
+            return;
+        }
+        state.started = true;
+
+        // Without cached packets, forwarding starts with the layer's next keyframe
+        let cached = keyframe_cache.packets();
+        if cached.is_empty() {
+            return;
+        }
+
+        for packet in cached {
+            let mut packet = packet.clone();
+            state.rewriter.rewrite(&mut packet, layer.codec.clock_rate);
+            // Never wait on the viewer with the cache held, that would stall every other viewer
+            if self.output.queue.try_send(packet).is_err() {
+                debug!("Viewer {} track queue is full, waiting for the next keyframe", layer.kind);
+                return;
+            }
+        }
+        state.active = Some(layer.rid.clone());
+    }
+
+    /// Ask the broadcaster for a keyframe of the layers the viewer receives or switches to
+    pub async fn request_keyframe(&self) {
+        let state = self.output.state.lock().await;
+        for layer in &self.layers {
+            if layer.rid == state.target || state.active.as_deref() == Some(layer.rid.as_str()) {
+                layer.request_keyframe();
+            }
+        }
+    }
+
+    /// Pin the viewer to a simulcast layer, or follow its bandwidth estimate again with None.
+    /// Returns false if the layer does not exist.
+    pub async fn select_layer(&self, rid: Option<&str>) -> bool {
+        if rid.is_some_and(|rid| self.layer(rid).is_none()) {
+            return false;
+        }
+
+        let mut state = self.output.state.lock().await;
+        state.pinned = rid.map(str::to_owned);
+        self.update_target(&mut state);
+        true
+    }
+
+    /// Bandwidth estimate reported by the viewer (REMB), in bits per second
+    pub async fn report_estimate(&self, bitrate: u64) {
+        let mut state = self.output.state.lock().await;
+        state.estimate = Some(bitrate);
+        state.remb_seen = true;
+        self.update_target(&mut state);
+    }
+
+    /// Packet loss reported by the viewer, as a fraction of 256. Viewers that send no estimate
+    /// of their own get one from their losses.
+    pub async fn report_loss(&self, fraction_lost: u8) {
+        let mut state = self.output.state.lock().await;
+        if state.remb_seen {
+            return;
+        }
+
+        let loss = fraction_lost as f64 / 256.0;
+        let current = state.active.as_deref()
+            .and_then(|rid| self.layer(rid))
+            .map(|layer| layer.bitrate())
+            .unwrap_or_default();
+
+        if loss > 0.1 && current > 0 {
+            state.estimate = Some((current as f64 * (1.0 - 0.5 * loss)) as u64);
+        } else if loss < 0.02 {
+            state.estimate = state.estimate.map(|estimate| estimate + estimate / 12);
+        }
+        self.update_target(&mut state);
+    }
+
+    /// Pick the layer to switch to, which takes effect on its next keyframe
+    fn update_target(&self, state: &mut ViewerOutputState) {
+        let target = match &state.pinned {
+            Some(pinned) => self.layer(pinned),
+            None => Self::best_layer(&self.layers, state.estimate),
+        };
+
+        if let Some(target) = target {
+            if target.rid != state.target {
+                debug!("Viewer {} track switching to layer '{}' ({} bps)", self.kind, target.rid, target.bitrate());
+                state.target = target.rid.clone();
+                target.request_keyframe();
+            }
+        }
+    }
+}
+
+impl Drop for ViewerTrack {
+    fn drop(&mut self) {
+        for layer in &self.layers {
+            let mut outputs = layer.outputs.lock().unwrap_or_else(|e| e.into_inner());
+            outputs.retain(|output| !Arc::ptr_eq(output, &self.output));
+        }
+    }
+}
+
+/// Fan-out stage between the relay tracks of a media section and a viewer's local track
+struct ViewerOutput {
+    // Packets waiting to be written to the viewer's local track
+    queue: mpsc::Sender<webrtc::rtp::packet::Packet>,
+    state: Mutex<ViewerOutputState>,
+}
+
+struct ViewerOutputState {
+    // Live packets are held back until the viewer is connected and primed
+    started: bool,
+    // Layer being forwarded, None until the first keyframe
+    active: Option<String>,
+    // Layer to switch to on its next keyframe
+    target: String,
+    // Layer chosen by the viewer, None to follow its bandwidth estimate
+    pinned: Option<String>,
+    // Bandwidth available to the viewer in bits per second, None if unknown
+    estimate: Option<u64>,
+    // Estimates reported by the viewer take precedence over the ones derived from losses
+    remb_seen: bool,
+    rewriter: RtpRewriter,
+}
+
+impl ViewerOutput {
+    /// Write queued packets to the local track, until the output is dropped
+    fn spawn_writer(local_track: Arc<TrackLocalStaticRTP>) -> mpsc::Sender<webrtc::rtp::packet::Packet> {
+        let (queue_tx, mut queue_rx) = mpsc::channel::<webrtc::rtp::packet::Packet>(VIEWER_QUEUE_SIZE);
+
+        tokio::spawn(async move {
+            while let Some(rtp) = queue_rx.recv().await {
+                // A viewer whose connection broke is removed by its state handler
+                let _ = local_track.write_rtp(&rtp).await;
+            }
+        });
+
+        queue_tx
+    }
+
+    /// Queue a packet of `layer` for the viewer, if it is the layer the viewer receives
+    async fn write_rtp(&self, layer: &RelayTrack, rtp: &webrtc::rtp::packet::Packet, keyframe_start: bool) {
+        let mut state = self.state.lock().await;
+        if !state.started {
+            return;
+        }
+
+        if state.active.as_deref() != Some(layer.rid.as_str()) {
+            // Switch on a keyframe, or right away when keyframes cannot be recognized
+            let can_switch = keyframe_start || layer.keyframe_cache.is_none();
+            if layer.rid != state.target || !can_switch {
+                return;
+            }
+
+            state.active = Some(layer.rid.clone());
+            state.rewriter.rebase = true;
+        }
+
+        let Ok(permit) = self.queue.try_reserve() else {
+            // The viewer cannot keep up. The packets it misses break its decoding until the
+            // next keyframe, so nothing more is forwarded until then.
+            debug!("Viewer {} track queue is full, dropping packets until the next keyframe", layer.kind);
+            state.active = None;
+            // A layer being switched to already had its keyframe requested
+            if layer.rid == state.target {
+                layer.request_keyframe();
+            }
+            return;
+        };
+
+        let mut rtp = rtp.clone();
+        state.rewriter.rewrite(&mut rtp, layer.codec.clock_rate);
+        permit.send(rtp);
+    }
+}
+
+/// A track that outlives the broadcaster sessions feeding it, so viewers stay bound to the same
+/// track when a broadcaster reconnects. Simulcast broadcasts have one relay track per layer.
+/// Packets are forwarded to one local track per viewer, whose SSRC and payload type are
+/// rewritten for that viewer by `TrackLocalStaticRTP`.
+pub struct RelayTrack {
+    pub codec: RTCRtpCodecCapability,
+    pub kind: RTPCodecType,
+    // Media section of the broadcaster's SDP the track was negotiated in
+    pub mid: String,
+    // Simulcast layer, empty without simulcast
+    pub rid: String,
+    input: Mutex<RelayInput>,
+    bitrate: std::sync::Mutex<BitrateMeter>,
+    // Only video tracks request keyframes
+    keyframe_requester: Option<KeyframeRequester>,
+    // Only video tracks of a codec whose keyframes can be recognized are cached
+    keyframe_cache: Option<Mutex<KeyframeCache>>,
+    outputs: std::sync::Mutex<Vec<Arc<ViewerOutput>>>,
+    // File writer of the broadcast's recording, when the track is recorded
+    recorder: std::sync::Mutex<Option<mpsc::Sender<webrtc::rtp::packet::Packet>>>,
+}
+
+impl RelayTrack {
+    pub fn new(broadcast: String, codec: RTCRtpCodecCapability, kind: RTPCodecType, mid: String, rid: String) -> Self {
+        let is_video = kind == RTPCodecType::Video;
+        let keyframe_requester = is_video.then(|| KeyframeRequester::new(broadcast));
+        let keyframe_cache = KeyframeCache::new(&codec.mime_type)
+            .filter(|_| is_video)
+            .map(Mutex::new);
+
+        Self {
+            codec,
+            kind,
+            mid,
+            rid,
+            input: Mutex::new(RelayInput::default()),
+            bitrate: std::sync::Mutex::new(BitrateMeter::default()),
+            keyframe_requester,
+            keyframe_cache,
+            outputs: std::sync::Mutex::new(Vec::new()),
+            recorder: std::sync::Mutex::new(None),
+        }
+    }
+
+    /// Incoming bitrate in bits per second, 0 if the broadcaster stopped sending the track
+    pub fn bitrate(&self) -> u64 {
+        self.bitrate.lock().unwrap_or_else(|e| e.into_inner()).bitrate()
+    }
+
+    /// Position of the track on its RTP timeline, from its first packet
+    pub async fn media_time(&self) -> Duration {
+        let elapsed = self.input.lock().await.elapsed;
+        Duration::from_secs_f64(elapsed as f64 / self.codec.clock_rate.max(1) as f64)
+    }
+
+    /// Hand the track over to a new broadcaster session, returning its source id.
+    /// Packets from any previous source are dropped from now on.
+    pub async fn attach_source(&self, peer_connection: Weak<RTCPeerConnection>, media_ssrc: u32) -> u64 {
+        if let Some(keyframe_requester) = &self.keyframe_requester {
+            keyframe_requester.set_source(peer_connection, media_ssrc).await;
+        }
+
+        // The keyframe of the previous session cannot be followed by the new session's frames
+        if let Some(keyframe_cache) = &self.keyframe_cache {
+            keyframe_cache.lock().await.clear();
+        }
+
+        let mut input = self.input.lock().await;
+        input.source += 1;
+        input.rewriter.rebase = true;
+        input.source
+    }
+
+    /// Send the track's packets to a recording, or stop recording it with None
+    pub fn set_recorder(&self, recorder: Option<mpsc::Sender<webrtc::rtp::packet::Packet>>) {
+        *self.recorder.lock().unwrap_or_else(|e| e.into_inner()) = recorder;
+    }
+
+    /// Hand a relayed packet to the recording, if the track is recorded
+    fn record(&self, rtp: &webrtc::rtp::packet::Packet) {
+        if let Some(recorder) = &*self.recorder.lock().unwrap_or_else(|e| e.into_inner()) {
+            // A recording that cannot keep up with the disk loses packets, never the viewers
+            let _ = recorder.try_send(rtp.clone());
+        }
+    }
+
+    /// Ask the broadcaster for a keyframe, for a viewer that joined or lost packets.
+    /// Does nothing for audio tracks.
+    pub fn request_keyframe(&self) {
+        if let Some(keyframe_requester) = &self.keyframe_requester {
+            keyframe_requester.request();
+        }
+    }
+
+    /// Rebase a packet from `source` and forward it to every viewer and the recording.
+    /// Returns false once the source has been replaced.
+    pub async fn write_rtp(&self, source: u64, rtp: &mut webrtc::rtp::packet::Packet) -> bool {
+        {
+            let mut input = self.input.lock().await;
+            if input.source != source {
+                return false;
+            }
+            let last_ts = input.rewriter.last_written.map(|_| input.rewriter.last_ts);
+            input.rewriter.rewrite(rtp, self.codec.clock_rate);
+            if let Some(last_ts) = last_ts {
+                // Reordered packets never move the timeline back
+                input.elapsed += (rtp.header.timestamp.wrapping_sub(last_ts) as i32).max(0) as u64;
+            }
+        }
+
+        self.bitrate.lock().unwrap_or_else(|e| e.into_inner()).record(rtp.payload.len());
+
+        // Held while the packet is fanned out, so viewers being started see it either in the
+        // cache or live, never both. Viewers are never waited on, their queues are not blocking.
+        let mut keyframe_cache = match &self.keyframe_cache {
+            Some(keyframe_cache) => Some(keyframe_cache.lock().await),
+            None => None,
+        };
+        let keyframe_start = keyframe_cache.as_mut().is_some_and(|cache| cache.push(rtp));
+
+        let outputs = self.outputs.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        for output in outputs {
+            output.write_rtp(self, rtp, keyframe_start).await;
+        }
+        drop(keyframe_cache);
+        self.record(rtp);
+
+        true
+    }
+}
 
 pub struct TrackManager {
     broadcast: String,
-    video_track_chan_tx: Arc<mpsc::Sender<Arc<TrackLocalStaticRTP>>>,
-    video_track_chan_rx: mpsc::Receiver<Arc<TrackLocalStaticRTP>>,
-    audio_track_chan_tx: Arc<mpsc::Sender<Arc<TrackLocalStaticRTP>>>,
-    audio_track_chan_rx: mpsc::Receiver<Arc<TrackLocalStaticRTP>>,
+    // Relay tracks of the broadcast being resumed, re-bound instead of creating new ones
+    resumed_tracks: Option<Arc<Mutex<Vec<Arc<RelayTrack>>>>>,
+    track_chan_tx: Arc<mpsc::Sender<Arc<RelayTrack>>>,
+    track_chan_rx: mpsc::Receiver<Arc<RelayTrack>>,
 }
 
 impl TrackManager {
     pub fn new(broadcast: String) -> Self {
-        let (video_track_chan_tx, video_track_chan_rx) =
-            mpsc::channel::<Arc<TrackLocalStaticRTP>>(1);
-        let (audio_track_chan_tx, audio_track_chan_rx) =
-            mpsc::channel::<Arc<TrackLocalStaticRTP>>(1);
+        let (track_chan_tx, track_chan_rx) = mpsc::channel::<Arc<RelayTrack>>(8);
 
         Self {
             broadcast,
-            video_track_chan_tx: Arc::new(video_track_chan_tx),
-            video_track_chan_rx,
-            audio_track_chan_tx: Arc::new(audio_track_chan_tx),
-            audio_track_chan_rx,
+            resumed_tracks: None,
+            track_chan_tx: Arc::new(track_chan_tx),
+            track_chan_rx,
         }
     }
 
-    pub fn get_video_track_receiver(&mut self) -> &mut mpsc::Receiver<Arc<TrackLocalStaticRTP>> {
-        &mut self.video_track_chan_rx
+    /// Track manager for a reconnecting broadcaster, feeding the relay tracks viewers already hold
+    pub fn resume(broadcast: String, tracks: Vec<Arc<RelayTrack>>) -> Self {
+        Self {
+            resumed_tracks: Some(Arc::new(Mutex::new(tracks))),
+            ..Self::new(broadcast)
+        }
     }
 
-    pub fn get_audio_track_receiver(&mut self) -> &mut mpsc::Receiver<Arc<TrackLocalStaticRTP>> {
-        &mut self.audio_track_chan_rx
+    /// Number of tracks the broadcaster's offer negotiated, once it is set as remote description.
+    /// Each simulcast layer is a track of its own.
+    pub async fn negotiated_track_count(peer_connection: &Arc<RTCPeerConnection>) -> usize {
+        let offer = peer_connection.remote_description().await.and_then(|offer| offer.unmarshal().ok());
+
+        let mut count = 0;
+        for transceiver in peer_connection.get_transceivers().await {
+            let receiving = matches!(
+                transceiver.direction(),
+                RTCRtpTransceiverDirection::Recvonly | RTCRtpTransceiverDirection::Sendrecv
+            );
+            let Some(mid) = transceiver.mid().filter(|_| receiving) else {
+                continue;
+            };
+
+            // `a=rid:<rid> send` lines of the media section
+            let layers = offer.as_ref()
+                .and_then(|offer| offer.media_descriptions.iter().find(|m| m.attribute("mid") == Some(Some(mid.as_str()))))
+                .map(|media| media.attributes.iter()
+                    .filter(|a| a.key == "rid")
+                    .filter(|a| a.value.as_deref().is_some_and(|v| v.split_whitespace().nth(1) == Some("send")))
+                    .count())
+                .unwrap_or_default();
+            count += layers.max(1);
+        }
+        count
+    }
+
+    /// Wait for the broadcaster's tracks: as long as needed for the first one, then up to
+    /// `settle_timeout` for the others, in case some of the `expected` tracks never arrive
+    pub async fn collect_tracks(&mut self, expected: usize, settle_timeout: Duration) -> Vec<Arc<RelayTrack>> {
+        let mut tracks = Vec::new();
+
+        match self.track_chan_rx.recv().await {
+            Some(track) => tracks.push(track),
+            None => return tracks,
+        }
+
+        let deadline = tokio::time::Instant::now() + settle_timeout;
+        while tracks.len() < expected {
+            match tokio::time::timeout_at(deadline, self.track_chan_rx.recv()).await {
+                Ok(Some(track)) => tracks.push(track),
+                Ok(None) => break,
+                Err(_) => {
+                    debug!("Broadcast '{}': Only {} of {} tracks arrived within {:?}",
+                           self.broadcast, tracks.len(), expected, settle_timeout);
+                    break;
+                }
+            }
+        }
+
+        tracks
     }
 
     pub fn setup_track_handlers(
         &self,
         peer_connection: Arc<RTCPeerConnection>
     ) -> Result<()> {
-        let video_track_sender = Arc::clone(&self.video_track_chan_tx);
-        let audio_track_sender = Arc::clone(&self.audio_track_chan_tx);
+        let track_sender = Arc::clone(&self.track_chan_tx);
+        let resumed_tracks = self.resumed_tracks.clone();
         let peer_conn_weak = Arc::downgrade(&peer_connection);
         let broadcast = self.broadcast.clone();
 
-        peer_connection.on_track(Box::new(move |track, _, _| {
-            let video_track_sender = Arc::clone(&video_track_sender);
-            let audio_track_sender = Arc::clone(&audio_track_sender);
+        peer_connection.on_track(Box::new(move |track, _, transceiver| {
+            let track_sender = Arc::clone(&track_sender);
+            let resumed_tracks = resumed_tracks.clone();
             let peer_conn_weak = peer_conn_weak.clone();
             let broadcast = broadcast.clone();
 
-            debug!("Broadcast '{}': Received {} track (SSRC: {})", 
-                   broadcast, track.kind(), track.ssrc());
+            debug!("Broadcast '{}': Received {} track (SSRC: {}, RID: '{}')",
+                   broadcast, track.kind(), track.ssrc(), track.rid());
 
-            match track.kind() {
-                RTPCodecType::Video => {
-                    // Spawn PLI (Picture Loss Indication) sender for video
-                    Self::spawn_pli_sender(broadcast.clone(), peer_conn_weak.clone(), track.ssrc());
-                    
-                    // Spawn video track relay
-                    Self::spawn_track_relay(broadcast.clone(), "video", track, video_track_sender);
-                }
-                RTPCodecType::Audio => {
-                    // Spawn audio track relay (no PLI needed for audio)
-                    Self::spawn_track_relay(broadcast.clone(), "audio", track, audio_track_sender);
-                }
-                RTPCodecType::Unspecified => {
+            Box::pin(async move {
+                let mid = transceiver.mid().map(|mid| mid.to_string()).unwrap_or_default();
+                let rid = track.rid().to_owned();
+
+                if track.kind() == RTPCodecType::Unspecified {
                     error!("Broadcast '{}': Got unspecified track type", broadcast);
+                    return;
                 }
-            }
 
-            Box::pin(async {})
+                // A resumed broadcaster feeds the relay track negotiated in the same media section and
+                // simulcast layer, or else the first one left of the same kind and layer, or kind
+                let resumed_track = match &resumed_tracks {
+                    Some(resumed_tracks) => {
+                        let mut resumed_tracks = resumed_tracks.lock().await;
+                        let position = resumed_tracks.iter().position(|t| t.kind == track.kind() && t.mid == mid && t.rid == rid)
+                            .or_else(|| resumed_tracks.iter().position(|t| t.kind == track.kind() && t.rid == rid))
+                            .or_else(|| resumed_tracks.iter().position(|t| t.kind == track.kind()));
+
+                        // Viewers cannot receive a track the broadcast did not have before
+                        let Some(position) = position else {
+                            warn!("Broadcast '{}': Resumed broadcaster sent an extra {} track, not relaying it",
+                                  broadcast, track.kind());
+                            return;
+                        };
+                        Some(resumed_tracks.remove(position))
+                    }
+                    None => None,
+                };
+
+                Self::spawn_track_relay(broadcast, mid, rid, track, peer_conn_weak, resumed_track, track_sender);
+            })
         }));
 
         Ok(())
     }
 
-    fn spawn_pli_sender(
+    fn spawn_track_relay(
         broadcast: String,
+        mid: String,
+        rid: String,
+        track: Arc<TrackRemote>,
         peer_conn_weak: Weak<RTCPeerConnection>,
-        media_ssrc: u32
+        resumed_track: Option<Arc<RelayTrack>>,
+        track_sender: Arc<mpsc::Sender<Arc<RelayTrack>>>
     ) {
-        tokio::spawn(async move {
-            let mut result = Result::<usize>::Ok(0);
-            debug!("Broadcast '{}': Starting PLI sender for video (SSRC: {})", broadcast, media_ssrc);
-            
-            while result.is_ok() {
-                tokio::time::sleep(Duration::from_secs(3)).await;
+        let track_type = track.kind();
 
-                if let Some(peer_connection) = peer_conn_weak.upgrade() {
-                    debug!("Broadcast '{}': Sending PLI for video (SSRC: {})", broadcast, media_ssrc);
-                    
-                    result = peer_connection.write_rtcp(&[Box::new(PictureLossIndication {
-                        sender_ssrc: 0,
-                        media_ssrc,
-                    })]).await.map_err(Into::into);
- 
-                    if let Err(e) = &result {
-                        debug!("Broadcast '{}': PLI send failed: {}", broadcast, e);
-                    } else {
-                        debug!("Broadcast '{}': PLI sent successfully", broadcast);
+        tokio::spawn(async move {
+            let relay_track = match resumed_track {
+                Some(relay_track) => {
+                    // Viewers are bound to the previous session's codec, which cannot change mid-stream
+                    let codec = &relay_track.codec;
+                    if !codec.mime_type.eq_ignore_ascii_case(&track.codec().capability.mime_type) {
+                        warn!("Broadcast '{}': Resumed {} track uses {} instead of {}, not relaying it",
+                              broadcast, track_type, track.codec().capability.mime_type, codec.mime_type);
+                        return;
                     }
-                } else {
-                    debug!("Broadcast '{}': Peer connection closed, stopping PLI sender", broadcast);
-                    break;
+
+                    debug!("Broadcast '{}': Re-binding {} track of resumed broadcaster", broadcast, track_type);
+                    relay_track
                 }
-            }
-            debug!("Broadcast '{}': PLI sender terminated", broadcast);
-        });
-    }
+                None => Arc::new(RelayTrack::new(broadcast.clone(), track.codec().capability, track_type, mid, rid)),
+            };
+            let source = relay_track.attach_source(peer_conn_weak, track.ssrc()).await;
 
-    fn spawn_track_relay(
-        broadcast: String,
-        track_type: &'static str,
-        track: Arc<TrackRemote>,
-        track_sender: Arc<mpsc::Sender<Arc<TrackLocalStaticRTP>>>
-    ) {
-        tokio::spawn(async move {
-            let local_track = Arc::new(TrackLocalStaticRTP::new(
-                track.codec().capability,
-                track_type.to_owned(),
-                "webrtc-rs".to_owned(),
-            ));
+            // Viewers of a resumed broadcast cannot decode the new stream until its next keyframe
+            relay_track.request_keyframe();
 
-            let _ = track_sender.send(Arc::clone(&local_track)).await;
+            let _ = track_sender.send(Arc::clone(&relay_track)).await;
 
             debug!("Broadcast '{}': {} track relay started, waiting for RTP packets...", 
                    broadcast, track_type);
 
             let mut packet_count = 0;
-            while let Ok((rtp, _)) = track.read_rtp().await {
+            while let Ok((mut rtp, _)) = track.read_rtp().await {
                 packet_count += 1;
                 if packet_count % 100 == 0 {
                     debug!("Broadcast '{}': Relayed {} {} RTP packets", 
                            broadcast, packet_count, track_type);
                 }
 
-                if let Err(err) = local_track.write_rtp(&rtp).await {
-                    if Error::ErrClosedPipe != err {
-                        debug!("Broadcast '{}': {} track relay error: {}, stopping", 
-                               broadcast, track_type, err);
-                        break;
-                    } else {
-                        debug!("Broadcast '{}': {} track relay closed pipe: {}", 
-                               broadcast, track_type, err);
-                    }
+                if !relay_track.write_rtp(source, &mut rtp).await {
+                    debug!("Broadcast '{}': {} track taken over by a resumed broadcaster, stopping",
+                           broadcast, track_type);
+                    break;
                 }
             }
             debug!("Broadcast '{}': {} track relay ended", broadcast, track_type);
         });
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webrtc::rtp::{ header::Header, packet::Packet };
+
+    fn rewrite(rewriter: &mut RtpRewriter, sequence_number: u16, timestamp: u32) -> (u16, u32) {
+        let mut packet = Packet {
+            header: Header { sequence_number, timestamp, ..Default::default() },
+            ..Default::default()
+        };
+        rewriter.rewrite(&mut packet, 90000);
+        (packet.header.sequence_number, packet.header.timestamp)
+    }
+
+    #[test]
+    fn writes_the_first_input_as_is() {
+        let mut rewriter = RtpRewriter { rebase: true, ..Default::default() };
+        assert_eq!(rewrite(&mut rewriter, 1000, 50000), (1000, 50000));
+        assert_eq!(rewrite(&mut rewriter, 1001, 53000), (1001, 53000));
+    }
+
+    #[test]
+    fn continues_the_previous_input() {
+        let mut rewriter = RtpRewriter::default();
+        rewrite(&mut rewriter, u16::MAX - 1, 50000);
+        rewrite(&mut rewriter, u16::MAX, 53000);
+
+        // The new input picks up 100 ms after the last packet of the previous one
+        rewriter.rebase = true;
+        rewriter.last_written = Some(Instant::now() - Duration::from_millis(100));
+        let (sequence_number, timestamp) = rewrite(&mut rewriter, 7, u32::MAX - 10);
+        assert_eq!(sequence_number, 0);
+        let gap = timestamp.wrapping_sub(53000);
+        assert!((9000..9900).contains(&gap), "Unexpected timestamp gap {gap}");
+
+        // Later packets of the input keep their spacing
+        assert_eq!(rewrite(&mut rewriter, 8, 2989), (1, timestamp.wrapping_add(3000)));
+    }
+
+    #[test]
+    fn never_repeats_a_timestamp_across_inputs() {
+        let mut rewriter = RtpRewriter::default();
+        rewrite(&mut rewriter, 10, 50000);
+
+        rewriter.rebase = true;
+        let (sequence_number, timestamp) = rewrite(&mut rewriter, 500, 1234);
+        assert_eq!(sequence_number, 11);
+        assert!(timestamp.wrapping_sub(50000) >= 1);
+    }
+}
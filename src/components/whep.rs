@@ -0,0 +1,68 @@
+use crate::{
+    components::{
+        http_signaling::{ created_response, error_response, parse_sdp_offer, HttpEndpoint, HttpSessions },
+        SessionManager,
+    },
+    prelude::*,
+};
+
+use actix_web::{ web, HttpRequest, HttpResponse };
+
+/// WHEP playback endpoint, so standard players and GStreamer's `whepsrc` can watch a broadcast.
+///
+/// - `POST /whep/{broadcast}` with an `application/sdp` offer starts watching
+/// - `DELETE /whep/{broadcast}/{resource}` stops watching
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg
+        .route("/whep/{broadcast}", web::post().to(whep_post))
+        .route("/whep/{broadcast}/{resource}", web::delete().to(whep_delete))
+        // Trickle ICE and ICE restarts are not supported
+        .route("/whep/{broadcast}/{resource}", web::patch().to(HttpResponse::MethodNotAllowed));
+}
+
+async fn whep_post(
+    req: HttpRequest,
+    path: web::Path<String>,
+    body: String,
+    session_manager: web::Data<SessionManager>,
+    sessions: web::Data<HttpSessions>,
+) -> HttpResponse {
+    let broadcast = path.into_inner();
+    info!("Broadcast '{}': New WHEP viewer request", broadcast);
+
+    let offer = match parse_sdp_offer(&req, body) {
+        Ok(offer) => offer,
+        Err(res) => return res,
+    };
+
+    // A missing broadcast fails with `not_found`, answered with 404
+    let result = async {
+        let peer_connection = session_manager.join_broadcast(broadcast.clone(), Some(offer), None).await?.peer_connection;
+        let answer = session_manager.create_gathered_answer(&peer_connection).await?;
+        Ok::<_, anyhow::Error>((peer_connection, answer))
+    }.await;
+
+    match result {
+        Ok((peer_connection, answer)) => {
+            let location = sessions.insert(HttpEndpoint::Whep, broadcast.clone(), peer_connection).await;
+            info!("Broadcast '{}': SDP answer sent to WHEP viewer", broadcast);
+
+            created_response(location, &answer, &session_manager.ice_servers())
+        }
+        Err(e) => error_response(&e),
+    }
+}
+
+async fn whep_delete(
+    path: web::Path<(String, String)>,
+    sessions: web::Data<HttpSessions>,
+) -> HttpResponse {
+    let (broadcast, resource) = path.into_inner();
+
+    if sessions.close(HttpEndpoint::Whep, &broadcast, &resource).await {
+        info!("Broadcast '{}': WHEP viewer left", broadcast);
+        HttpResponse::Ok().finish()
+    } else {
+        HttpResponse::NotFound().body("Unknown WHEP resource")
+    }
+}
@@ -0,0 +1,67 @@
+use crate::{
+    components::{
+        http_signaling::{ created_response, error_response, parse_sdp_offer, HttpEndpoint, HttpSessions },
+        SessionManager,
+    },
+    prelude::*,
+};
+
+use actix_web::{ web, HttpRequest, HttpResponse };
+
+/// WHIP (RFC 9725) ingest endpoint, so broadcasters can go live from OBS or GStreamer's `whipsink`.
+///
+/// - `POST /whip/{broadcast}` with an `application/sdp` offer starts the broadcast
+/// - `DELETE /whip/{broadcast}/{resource}` ends it
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg
+        .route("/whip/{broadcast}", web::post().to(whip_post))
+        .route("/whip/{broadcast}/{resource}", web::delete().to(whip_delete))
+        // Trickle ICE and ICE restarts are not supported, as allowed by the RFC
+        .route("/whip/{broadcast}/{resource}", web::patch().to(HttpResponse::MethodNotAllowed));
+}
+
+async fn whip_post(
+    req: HttpRequest,
+    path: web::Path<String>,
+    body: String,
+    session_manager: web::Data<SessionManager>,
+    sessions: web::Data<HttpSessions>,
+) -> HttpResponse {
+    let broadcast = path.into_inner();
+    info!("Broadcast '{}': New WHIP broadcaster request", broadcast);
+
+    let offer = match parse_sdp_offer(&req, body) {
+        Ok(offer) => offer,
+        Err(res) => return res,
+    };
+
+    let result = async {
+        let peer_connection = session_manager.start_broadcast(broadcast.clone(), offer, None, false, None, None).await?;
+        let answer = session_manager.create_gathered_answer(&peer_connection).await?;
+        Ok::<_, anyhow::Error>((peer_connection, answer))
+    }.await;
+
+    match result {
+        Ok((peer_connection, answer)) => {
+            let location = sessions.insert(HttpEndpoint::Whip, broadcast.clone(), peer_connection).await;
+            info!("Broadcast '{}': SDP answer sent to WHIP broadcaster", broadcast);
+
+            created_response(location, &answer, &session_manager.ice_servers())
+        }
+        Err(e) => error_response(&e),
+    }
+}
+
+async fn whip_delete(
+    path: web::Path<(String, String)>,
+    sessions: web::Data<HttpSessions>,
+) -> HttpResponse {
+    let (broadcast, resource) = path.into_inner();
+
+    if sessions.close(HttpEndpoint::Whip, &broadcast, &resource).await {
+        info!("Broadcast '{}': WHIP broadcaster ended the broadcast", broadcast);
+        HttpResponse::Ok().finish()
+    } else {
+        HttpResponse::NotFound().body("Unknown WHIP resource")
+    }
+}
@@ -9,7 +9,7 @@ use united_cinemas::{
 #[tokio::main]
 async fn main() -> Result<()> {
     // Build settings
-    let settings = Settings::new();
+    let settings = Settings::new()?;
 
     // Init the tracing subscriber
     let (subscriber, _guard) = telemetry::get_subscriber(&settings).await?;
@@ -18,113 +18,276 @@ async fn main() -> Result<()> {
     let host = settings.host;
     let port = settings.port;
 
+    // Recordings are kept as IVF and Ogg files without ffmpeg
+    if let Err(e) = recorder::Recording::check_ffmpeg(&settings.recording).await {
+        warn!("{}: recordings will not be muxed into WebM files", e);
+    }
+
     // Init components
-    let mut signaling = SignalingServer::new(host.clone(), port).await?;
-    let peer_conn_factory = Arc::new(PeerConnectionFactory::new().await?);
-    let broadcast_manager = Arc::new(BroadcastManager::new());
-    let session_manager = SessionManager::new(Arc::clone(&peer_conn_factory), Arc::clone(&broadcast_manager));
+    let peer_conn_factory = Arc::new(PeerConnectionFactory::new(settings.ice_servers, &settings.network).await?);
+    let broadcast_manager = Arc::new(BroadcastManager::new(settings.recording));
+    let playout_manager = Arc::new(PlayoutManager::new(Arc::clone(&broadcast_manager), settings.playout));
+    let scheduler = Arc::new(Scheduler::new(Arc::clone(&playout_manager)));
+    scheduler.load(settings.schedule).await;
+    let session_manager = SessionManager::new(
+        Arc::clone(&peer_conn_factory),
+        Arc::clone(&broadcast_manager),
+        ChatManager::new(settings.chat),
+        SubtitleManager::new(Arc::clone(&broadcast_manager), Arc::clone(&playout_manager)),
+        settings.session
+    );
+    let mut signaling = SignalingServer::new(
+        host.clone(),
+        port,
+        settings.legacy_signaling,
+        session_manager.clone(),
+        Arc::clone(&playout_manager),
+        scheduler,
+        settings.api
+    ).await?;
 
     info!("Signaling server waiting for offer via WebSocket connection on ws://{}:{}/ws", host, port);
+    info!("WHIP ingest endpoint available on http://{}:{}/whip/{{broadcast}}", host, port);
+    info!("WHEP playback endpoint available on http://{}:{}/whep/{{broadcast}}", host, port);
+    info!("Control API available on http://{}:{}/api", host, port);
 
     loop {
         // Wait for any client connection (broadcaster or viewer)
-        let (payload, responder) = signaling.wait_for_offer().await?;
-        let broadcast = payload.name;
-
-        match payload.action.as_str() {
-            "broadcast" => {
-                info!("Broadcast '{}': New broadcaster request", broadcast);
-
-                // Create a dedicated track manager for this broadcaster
-                let mut track_manager = TrackManager::new(broadcast.clone());
-
-                // Decode the SDP offer from the broadcaster
-                let offer = signaling.decode_sdp(&payload.sdp)?;
-                debug!("Broadcast '{}': SDP offer decoded successfully", broadcast);
- 
-                // Create a WebRTC session to receive video from the broadcaster
-                let peer_connection = session_manager
-                    .create_broadcaster_session(broadcast.clone(), offer, &mut track_manager)
-                    .await?;
-                debug!("Broadcast '{}': WebRTC session created for broadcaster", broadcast);
- 
-                // Create and send the SDP answer back to the broadcaster
-                let local_desc = session_manager.create_answer(&peer_connection).await?;
-                let response = signaling.encode_sdp(&local_desc)?;
-                let _ = responder.send(response);
-                
-                info!("Broadcast '{}': SDP answer sent to broadcaster", broadcast);
- 
-                // Wait for both video and audio tracks to arrive, then register the broadcast
-                let broadcast_name = broadcast.clone();
-                let broadcast_manager_clone = Arc::clone(&broadcast_manager);
-
-                tokio::spawn(async move {
-                    debug!("Broadcast '{}': Waiting for video and audio tracks from broadcaster", broadcast_name);
-
-                    // Wait for both tracks sequentially
-                    let video_track = track_manager.get_video_track_receiver().recv().await;
-                    let audio_track = track_manager.get_audio_track_receiver().recv().await;
-
-                    if let (Some(video_track), Some(audio_track)) = (&video_track, &audio_track) {
-                        debug!("Broadcast '{}': Both video and audio tracks received, registering broadcast", broadcast_name);
- 
-                        broadcast_manager_clone.register_broadcast(
-                            broadcast_name.clone(),
-                            Arc::clone(video_track),
-                            Arc::clone(audio_track),
-                        ).await;
-
-                        info!("Broadcast '{}': Ready for viewers (with video and audio)", broadcast_name);
-                    } else {
-                        if video_track.is_none() {
-                            debug!("Broadcast '{}': Failed to receive video track from broadcaster", broadcast_name);
-                        }
-                        if audio_track.is_none() {
-                            debug!("Broadcast '{}': Failed to receive audio track from broadcaster", broadcast_name);
-                        }
-                        warn!("Broadcast '{}': Incomplete tracks received", broadcast_name);
-                    }
-                });
-            }
- 
-            "join" => {
-                info!("Broadcast '{}': Viewer wants to join broadcast", broadcast);
- 
-                // Look up the broadcast in the registry
-                if let Some((video_track, audio_track)) = broadcast_manager.get_broadcast(&broadcast).await {
-                    debug!("Broadcast '{}': Broadcast found in registry (with video and audio)", broadcast);
- 
-                    // Decode the SDP offer from the viewer
-                    let offer = signaling.decode_sdp(&payload.sdp)?;
-                    debug!("Broadcast '{}': Viewer SDP offer decoded", broadcast);
- 
-                    // Create a WebRTC session to send video and audio to the viewer
-                    let peer_connection = session_manager
-                        .create_viewer_session(
-                            broadcast.clone(), 
-                            offer, 
-                            Arc::clone(&video_track),
-                            Arc::clone(&audio_track)
-                        )
-                        .await?;
-                    debug!("Broadcast '{}': WebRTC session created for viewer", broadcast);
- 
-                    // Create and send the SDP answer back to the viewer
-                    let local_desc = session_manager.create_answer(&peer_connection).await?;
-                    let response = signaling.encode_sdp(&local_desc)?;
-                    let _ = responder.send(response);
-
-                    info!("Broadcast '{}': Viewer connected (with video and audio)", broadcast);
-                } else {
-                    debug!("Broadcast '{}': Broadcast not found in registry", broadcast);
-                    // TODO: should send an error back to the client here
+        let msg = signaling.wait_for_offer().await?;
+
+        // Handle each session in its own task, so a slow ICE gathering never holds up other clients
+        let session_manager = session_manager.clone();
+        let playout_manager = Arc::clone(&playout_manager);
+
+        // Errors are reported to the client through its responder and never reach the main loop
+        tokio::spawn(async move {
+            let responder = msg.responder.clone();
+
+            if let Err(e) = handle_client(msg, session_manager, playout_manager).await {
+                let error = SignalingError::from_anyhow(&e);
+                match error.code {
+                    ErrorCode::Internal => error!("Failed to handle client request: {:#}", e),
+                    _ => debug!("Rejected client request: {}", error),
                 }
+                let _ = responder.send(ServerToClientMsg::Message(error.into())).await;
             }
+        });
+    }
+}
+
+async fn handle_client(
+    msg: SdpMessage,
+    session_manager: SessionManager,
+    playout_manager: Arc<PlayoutManager>,
+) -> Result<()> {
+    let SdpMessage { message, responder, incoming } = msg;
+
+    match message {
+        ClientMessage::Broadcast { name: broadcast, sdp: offer, resume_token, record, metadata } => {
+            info!("Broadcast '{}': New broadcaster request", broadcast);
+
+            // Create a WebRTC session to receive video from the broadcaster
+            let peer_connection = session_manager.start_broadcast(broadcast.clone(), offer, resume_token, record, metadata, Some(responder.clone())).await?;
+            debug!("Broadcast '{}': WebRTC session created for broadcaster", broadcast);
+
+            // Create and send the SDP answer back to the broadcaster
+            session_manager.setup_ice_candidate_handler(broadcast.clone(), &peer_connection, responder.clone());
+            let local_desc = session_manager.create_answer(&peer_connection).await?;
+            let _ = responder.send(ServerToClientMsg::Message(ServerMessage::Answer { sdp: Box::new(local_desc) })).await;
+
+            info!("Broadcast '{}': SDP answer sent to broadcaster", broadcast);
+
+            // Keep the session open to exchange trickled ICE candidates
+            handle_session_messages(&broadcast, &session_manager, &peer_connection, None, None, responder, incoming).await;
+        }
+
+        ClientMessage::Join { name: broadcast, sdp: offer } => {
+            info!("Broadcast '{}': Viewer wants to join broadcast", broadcast);
+            handle_viewer(&broadcast, offer, &session_manager, &playout_manager, None, responder, incoming).await?;
+        }
+
+        ClientMessage::WatchParty { name: broadcast, video, audio, sdp: offer, .. } => {
+            info!("Broadcast '{}': New watch party request", broadcast);
+
+            // The film is played out by the server, on a timeline only the host controls
+            let source = PlayoutSource { video, audio, looping: false };
+            let playout_id = playout_manager.start(broadcast.clone(), source).await?;
+
+            // The host watches the party like any viewer, and the party ends with the host's session
+            let host = Some(playout_id.as_str());
+            let result = handle_viewer(&broadcast, offer, &session_manager, &playout_manager, host, responder, incoming).await;
+            playout_manager.stop_playout(&broadcast, &playout_id).await;
+            result?;
+        }
+
+        ClientMessage::Hello
+        | ClientMessage::Answer { .. }
+        | ClientMessage::Candidate { .. }
+        | ClientMessage::EndOfCandidates
+        | ClientMessage::SelectLayer { .. }
+        | ClientMessage::Play
+        | ClientMessage::Pause
+        | ClientMessage::Seek { .. }
+        | ClientMessage::SelectSubtitles { .. }
+        | ClientMessage::Cue { .. }
+        | ClientMessage::UpdateMetadata { .. } => {
+            debug!("Received a message other than an offer first, rejecting session");
+            bail!(SignalingError::new(ErrorCode::BadMessage, "Expected a 'broadcast' or 'join' offer first"));
+        }
+    }
+
+    Ok(())
+}
 
-            _ => {
-                debug!("Unknown action '{}': Invalid action received from client", payload.action);
+/// Connect a viewer to a broadcast, or the host of a watch party (with the id of its playout),
+/// then serve its signaling session
+async fn handle_viewer(
+    broadcast: &str,
+    offer: Option<RTCSessionDescription>,
+    session_manager: &SessionManager,
+    playout_manager: &PlayoutManager,
+    host: Option<&str>,
+    responder: mpsc::Sender<ServerToClientMsg>,
+    incoming: mpsc::Receiver<ClientMessage>,
+) -> Result<()> {
+    // Create a WebRTC session to send the broadcast's tracks to the viewer
+    let has_offer = offer.is_some();
+    let viewer = session_manager.join_broadcast(broadcast.to_owned(), offer, Some(responder.clone())).await?;
+    let peer_connection = viewer.peer_connection;
+    debug!("Broadcast '{}': WebRTC session created for viewer", broadcast);
+
+    session_manager.setup_ice_candidate_handler(broadcast.to_owned(), &peer_connection, responder.clone());
+    let message = if has_offer {
+        // Create and send the SDP answer back to the viewer
+        let local_desc = session_manager.create_answer(&peer_connection).await?;
+        ServerMessage::Answer { sdp: Box::new(local_desc) }
+    } else {
+        // Offer every track of the broadcast, the viewer's answer arrives with the candidates
+        let local_desc = session_manager.create_offer(&peer_connection).await?;
+        ServerMessage::Offer { sdp: Box::new(local_desc) }
+    };
+    let _ = responder.send(ServerToClientMsg::Message(message)).await;
+
+    // Describe the broadcast to the viewer
+    if let Some(metadata) = session_manager.broadcast_metadata(broadcast).await {
+        let message = ServerMessage::Metadata { name: broadcast.to_owned(), metadata };
+        let _ = responder.send(ServerToClientMsg::Message(message)).await;
+    }
+
+    // Let the viewer pick a simulcast layer
+    let rids = session_manager.viewer_layers(broadcast, &viewer.id).await;
+    if !rids.is_empty() {
+        let _ = responder.send(ServerToClientMsg::Message(ServerMessage::Layers { rids })).await;
+    }
+
+    // Let the viewer pick a subtitle language
+    let languages = session_manager.subtitles().languages(broadcast).await;
+    if !languages.is_empty() {
+        let _ = responder.send(ServerToClientMsg::Message(ServerMessage::Subtitles { languages })).await;
+    }
+
+    // Sync the viewer with the film played out by the server
+    if let Some(state) = playout_manager.playback(broadcast).await {
+        let message = ServerMessage::Playback { playing: state.playing, position: state.position };
+        let _ = responder.send(ServerToClientMsg::Message(message)).await;
+    }
+
+    info!("Broadcast '{}': Viewer connected", broadcast);
+
+    // Keep the session open to exchange trickled ICE candidates
+    let host = host.map(|playout_id| (playout_manager, playout_id));
+    handle_session_messages(broadcast, session_manager, &peer_connection, Some(&viewer.id), host, responder, incoming).await;
+    Ok(())
+}
+
+/// Add the ICE candidates trickled by the client, its answer to a server offer, the layers and
+/// subtitles picked by a viewer (identified by `viewer_id`) and the live subtitles and metadata
+/// of a broadcaster, until its WebSocket session closes.
+/// The host of a watch party (with the id of its playout) also controls playback.
+async fn handle_session_messages(
+    broadcast: &str,
+    session_manager: &SessionManager,
+    peer_connection: &Arc<RTCPeerConnection>,
+    viewer_id: Option<&str>,
+    host: Option<(&PlayoutManager, &str)>,
+    responder: mpsc::Sender<ServerToClientMsg>,
+    mut incoming: mpsc::Receiver<ClientMessage>,
+) {
+    while let Some(message) = incoming.recv().await {
+        let candidate = match message {
+            ClientMessage::Candidate { candidate } => candidate,
+            ClientMessage::EndOfCandidates => RTCIceCandidateInit::default(),
+            ClientMessage::Hello
+            | ClientMessage::Broadcast { .. }
+            | ClientMessage::Join { .. }
+            | ClientMessage::WatchParty { .. } => {
+                debug!("Broadcast '{}': Ignoring second offer on the same signaling session", broadcast);
+                continue;
             }
+            request => {
+                let result = handle_session_request(broadcast, session_manager, peer_connection, viewer_id, host, request).await;
+                if let Err(e) = result {
+                    debug!("Broadcast '{}': Rejected client request: {}", broadcast, e);
+                    let error = SignalingError::from_anyhow(&e);
+                    let _ = responder.send(ServerToClientMsg::Message(error.into())).await;
+                }
+                continue;
+            }
+        };
+
+        if let Err(e) = session_manager.add_remote_candidate(peer_connection, candidate).await {
+            warn!("Broadcast '{}': Failed to add remote ICE candidate: {}", broadcast, e);
+        }
+    }
+
+    debug!("Broadcast '{}': Signaling session closed", broadcast);
+}
+
+/// Handle a request of an established signaling session. Requests the session's role does not
+/// allow are rejected: `viewer_id` is only set for viewers, and `host` for the host of a watch party.
+async fn handle_session_request(
+    broadcast: &str,
+    session_manager: &SessionManager,
+    peer_connection: &Arc<RTCPeerConnection>,
+    viewer_id: Option<&str>,
+    host: Option<(&PlayoutManager, &str)>,
+    request: ClientMessage,
+) -> Result<()> {
+    let viewer = |message: &str| viewer_id.ok_or_else(|| SignalingError::new(ErrorCode::BadMessage, message));
+    let broadcaster = |message: &str| match viewer_id {
+        Some(_) => Err(SignalingError::new(ErrorCode::Forbidden, message)),
+        None => Ok(()),
+    };
+
+    match request {
+        ClientMessage::Answer { sdp } => session_manager.apply_answer(peer_connection, sdp).await,
+        ClientMessage::SelectLayer { rid } => {
+            let viewer_id = viewer("Only viewers can select a layer")?;
+            session_manager.select_layer(broadcast, viewer_id, rid.as_deref()).await
+        }
+        ClientMessage::SelectSubtitles { language } => {
+            let viewer_id = viewer("Only viewers can select subtitles")?;
+            session_manager.subtitles().select(broadcast, viewer_id, language.as_deref()).await
         }
+        ClientMessage::Cue { language, text, duration } => {
+            broadcaster("Only the broadcaster sends live subtitles")?;
+            session_manager.subtitles().add_cue(broadcast, &language, text, duration).await
+        }
+        ClientMessage::UpdateMetadata { metadata } => {
+            broadcaster("Only the broadcaster edits the broadcast's metadata")?;
+            session_manager.update_metadata(broadcast, metadata).await
+        }
+        ClientMessage::Play => control_playback(broadcast, host, PlaybackCommand::Play).await,
+        ClientMessage::Pause => control_playback(broadcast, host, PlaybackCommand::Pause).await,
+        ClientMessage::Seek { position } => control_playback(broadcast, host, PlaybackCommand::Seek(position)).await,
+        _ => bail!(SignalingError::new(ErrorCode::BadMessage, "Unexpected message on an established session")),
     }
-}
\ No newline at end of file
+}
+
+/// Apply a playback command from the host of a watch party, rejecting it from anyone else
+async fn control_playback(broadcast: &str, host: Option<(&PlayoutManager, &str)>, command: PlaybackCommand) -> Result<()> {
+    let Some((playout_manager, playout_id)) = host else {
+        bail!(SignalingError::new(ErrorCode::Forbidden, "Only the host of a watch party controls its playback"));
+    };
+    playout_manager.control(broadcast, playout_id, command).await.map(|_| ())
+}
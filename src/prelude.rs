@@ -6,18 +6,25 @@ pub use webrtc::{
         media_engine::MediaEngine,
         APIBuilder,
     },
-    ice_transport::ice_server::RTCIceServer,
+    ice_transport::{
+        ice_server::RTCIceServer,
+        ice_candidate::{ RTCIceCandidate, RTCIceCandidateInit },
+    },
     interceptor::registry::Registry,
     peer_connection::{
         configuration::RTCConfiguration,
         peer_connection_state::RTCPeerConnectionState,
-        sdp::session_description::RTCSessionDescription,
+        sdp::{
+            session_description::RTCSessionDescription,
+            sdp_type::RTCSdpType,
+        },
         RTCPeerConnection,
     },
     rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication,
     rtp_transceiver::{
         rtp_sender::RTCRtpSender,
         rtp_codec::RTPCodecType,
+        rtp_transceiver_direction::RTCRtpTransceiverDirection,
     },
     track::{
         track_remote::TrackRemote,
@@ -1,6 +1,27 @@
+use std::{ net::IpAddr, path::PathBuf, time::Duration };
+
+use anyhow::{ bail, Context, Result };
+use chrono::{ DateTime, Utc };
 use clap::Parser;
+use serde::{ Deserialize, Serialize };
+
+const DEFAULT_HOST: &str = "0.0.0.0";
+const DEFAULT_PORT: u16 = 8080;
+const DEFAULT_STUN_SERVER: &str = "stun:stun.l.google.com:19302";
+const DEFAULT_TURN_CREDENTIAL_TTL: u64 = 86400;
+const DEFAULT_BROADCASTER_DISCONNECT_TIMEOUT: u64 = 10;
+const DEFAULT_BROADCASTER_GRACE_PERIOD: u64 = 30;
+const DEFAULT_TRACK_SETTLE_TIMEOUT: u64 = 3;
+const DEFAULT_RECORDING_DIRECTORY: &str = "recordings";
+const DEFAULT_FFMPEG: &str = "ffmpeg";
+const DEFAULT_PLAYOUT_DIRECTORY: &str = "media";
+const DEFAULT_CHAT_MAX_MESSAGE_SIZE: usize = 1024;
+const DEFAULT_CHAT_RATE_LIMIT: f64 = 1.0;
+const DEFAULT_CHAT_HISTORY: usize = 50;
 
-#[derive(Parser)]
+/// Command line flags. Every option can also be set through its `UNITED_CINEMAS_*` environment
+/// variable, and takes precedence over the configuration file.
+#[derive(Parser, Default)]
 #[command(
     name = "United Cinemas - WebRTC SFU Server",
     version = "0.1.0",
@@ -8,34 +29,539 @@ use clap::Parser;
     about = "United Cinemas - WebRTC SFU Server\n\nA simple WebRTC SFU implementation in Rust built for broadcasting video streams.",
     long_about = None
 )]
-
 struct Args {
-    /// Signaling server host 
-    #[arg(short = 'H', long, default_value = "0.0.0.0")]
-    pub host: String,
+    /// TOML configuration file
+    #[arg(short, long, env = "UNITED_CINEMAS_CONFIG")]
+    pub config: Option<PathBuf>,
 
-    /// Signaling server port
-    #[arg(short, long, default_value_t = 8080)]
-    pub port: u16,
+    /// Signaling server host [default: 0.0.0.0]
+    #[arg(short = 'H', long, env = "UNITED_CINEMAS_HOST")]
+    pub host: Option<String>,
+
+    /// Signaling server port [default: 8080]
+    #[arg(short, long, env = "UNITED_CINEMAS_PORT")]
+    pub port: Option<u16>,
 
     /// Output debug logs
-    #[arg(short, long, default_value_t = false)]
-    pub debug: bool
+    #[arg(short, long, env = "UNITED_CINEMAS_DEBUG", num_args = 0..=1, default_missing_value = "true")]
+    pub debug: Option<bool>,
+
+    /// Also accept the legacy base64 signaling format, for older clients
+    #[arg(long, env = "UNITED_CINEMAS_LEGACY_SIGNALING", num_args = 0..=1, default_missing_value = "true")]
+    pub legacy_signaling: Option<bool>,
+
+    /// Seconds a disconnected broadcaster may take to reconnect before its broadcast ends [default: 10]
+    #[arg(long, value_name = "SECS", env = "UNITED_CINEMAS_BROADCASTER_DISCONNECT_TIMEOUT")]
+    pub broadcaster_disconnect_timeout: Option<u64>,
+
+    /// Seconds a broadcast waits for its lost broadcaster to resume it before ending, 0 to end it right away [default: 30]
+    #[arg(long, value_name = "SECS", env = "UNITED_CINEMAS_BROADCASTER_GRACE_PERIOD")]
+    pub broadcaster_grace_period: Option<u64>,
+
+    /// Seconds to wait for the rest of a broadcaster's tracks once the first one arrived [default: 3]
+    #[arg(long, value_name = "SECS", env = "UNITED_CINEMAS_TRACK_SETTLE_TIMEOUT")]
+    pub track_settle_timeout: Option<u64>,
+
+    /// STUN server URL, can be repeated. Replaces the configuration file's ICE servers.
+    /// Pass an empty URL to use no STUN server [default: stun:stun.l.google.com:19302]
+    #[arg(long = "stun-server", value_name = "URL", env = "UNITED_CINEMAS_STUN_SERVERS", value_delimiter = ',')]
+    pub stun_servers: Option<Vec<String>>,
+
+    /// TURN server URL, can be repeated. Replaces the configuration file's ICE servers
+    #[arg(long = "turn-server", value_name = "URL", env = "UNITED_CINEMAS_TURN_SERVERS", value_delimiter = ',')]
+    pub turn_servers: Option<Vec<String>>,
+
+    /// Username for the TURN servers
+    #[arg(long, env = "UNITED_CINEMAS_TURN_USERNAME")]
+    pub turn_username: Option<String>,
+
+    /// Static credential for the TURN servers
+    #[arg(long, env = "UNITED_CINEMAS_TURN_CREDENTIAL")]
+    pub turn_credential: Option<String>,
+
+    /// TURN REST API shared secret, used to generate time-limited credentials instead of a static one
+    #[arg(long, env = "UNITED_CINEMAS_TURN_SECRET")]
+    pub turn_secret: Option<String>,
+
+    /// Lifetime in seconds of the generated TURN credentials [default: 86400]
+    #[arg(long, env = "UNITED_CINEMAS_TURN_CREDENTIAL_TTL")]
+    pub turn_credential_ttl: Option<u64>,
+
+    /// Lowest UDP port used for ICE candidates
+    #[arg(long, env = "UNITED_CINEMAS_UDP_PORT_MIN")]
+    pub udp_port_min: Option<u16>,
+
+    /// Highest UDP port used for ICE candidates
+    #[arg(long, env = "UNITED_CINEMAS_UDP_PORT_MAX")]
+    pub udp_port_max: Option<u16>,
+
+    /// Public IP advertised in host candidates instead of the local one (NAT 1:1 mapping), can be repeated
+    #[arg(long = "nat-1to1-ip", value_name = "IP", env = "UNITED_CINEMAS_NAT_1TO1_IPS", value_delimiter = ',')]
+    pub nat_1to1_ips: Option<Vec<IpAddr>>,
+
+    /// Run as an ICE-lite agent, for servers with a public IP
+    #[arg(long, env = "UNITED_CINEMAS_ICE_LITE", num_args = 0..=1, default_missing_value = "true")]
+    pub ice_lite: Option<bool>,
+
+    /// Multiplex every peer connection over this single UDP port
+    #[arg(long, env = "UNITED_CINEMAS_UDP_MUX_PORT")]
+    pub udp_mux_port: Option<u16>,
+
+    /// Directory the broadcasts are recorded to [default: recordings]
+    #[arg(long, value_name = "DIR", env = "UNITED_CINEMAS_RECORDING_DIRECTORY")]
+    pub recording_directory: Option<PathBuf>,
+
+    /// ffmpeg executable, used to mux recordings into WebM files [default: ffmpeg]
+    #[arg(long, value_name = "PATH", env = "UNITED_CINEMAS_FFMPEG")]
+    pub ffmpeg: Option<PathBuf>,
+
+    /// Directory of the media files that can be played out as broadcasts [default: media]
+    #[arg(long, value_name = "DIR", env = "UNITED_CINEMAS_PLAYOUT_DIRECTORY")]
+    pub playout_directory: Option<PathBuf>,
+
+    /// Largest chat message accepted from a user, in bytes [default: 1024]
+    #[arg(long, value_name = "BYTES", env = "UNITED_CINEMAS_CHAT_MAX_MESSAGE_SIZE")]
+    pub chat_max_message_size: Option<usize>,
+
+    /// Chat messages and reactions a user may send per second, after a burst of 5 [default: 1]
+    #[arg(long, value_name = "PER_SEC", env = "UNITED_CINEMAS_CHAT_RATE_LIMIT")]
+    pub chat_rate_limit: Option<f64>,
+
+    /// Chat messages kept for users joining later, 0 to keep none [default: 50]
+    #[arg(long, value_name = "COUNT", env = "UNITED_CINEMAS_CHAT_HISTORY")]
+    pub chat_history: Option<usize>,
+
+    /// Bearer token required by the control API to change anything. Without one, changes are
+    /// only accepted from localhost
+    #[arg(long, value_name = "TOKEN", env = "UNITED_CINEMAS_API_TOKEN")]
+    pub api_token: Option<String>
+}
+
+/// Layout of the TOML configuration file. Every field is optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    host: Option<String>,
+    port: Option<u16>,
+    debug: Option<bool>,
+    legacy_signaling: Option<bool>,
+    broadcaster_disconnect_timeout: Option<u64>,
+    broadcaster_grace_period: Option<u64>,
+    track_settle_timeout: Option<u64>,
+    ice_servers: Option<Vec<IceServerConfig>>,
+    network: FileNetworkConfig,
+    recording: FileRecordingConfig,
+    playout: FilePlayoutConfig,
+    chat: FileChatConfig,
+    api: FileApiConfig,
+    schedule: Vec<ScreeningConfig>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileNetworkConfig {
+    udp_port_min: Option<u16>,
+    udp_port_max: Option<u16>,
+    nat_1to1_ips: Option<Vec<IpAddr>>,
+    ice_lite: Option<bool>,
+    udp_mux_port: Option<u16>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileRecordingConfig {
+    directory: Option<PathBuf>,
+    ffmpeg: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FilePlayoutConfig {
+    directory: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileChatConfig {
+    max_message_size: Option<usize>,
+    rate_limit: Option<f64>,
+    history: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileApiConfig {
+    token: Option<String>,
+}
+
+impl FileConfig {
+    fn load(path: &PathBuf) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read configuration file '{}'", path.display()))?;
+
+        toml::from_str(&contents)
+            .with_context(|| format!("Invalid configuration file '{}'", path.display()))
+    }
+}
+
+/// A STUN or TURN server handed to the server and client peer connections
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IceServerConfig {
+    pub urls: Vec<String>,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub credential: Option<String>,
+    /// TURN REST API shared secret. When set, a time-limited credential is generated for each
+    /// peer connection instead of using `credential`
+    #[serde(default)]
+    pub secret: Option<String>,
+    /// Lifetime in seconds of the generated credentials
+    #[serde(default = "default_turn_credential_ttl")]
+    pub credential_ttl: u64,
+}
+
+/// A screening of the schedule: media files of the playout directory published as a broadcast
+/// at a set time
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScreeningConfig {
+    pub broadcast: String,
+    #[serde(default)]
+    pub video: Option<PathBuf>,
+    #[serde(default)]
+    pub audio: Option<PathBuf>,
+    /// Start time, as an RFC 3339 string
+    pub start: DateTime<Utc>,
+    /// End time. Without one, the screening ends with its files, or runs until cancelled if it loops
+    #[serde(default)]
+    pub end: Option<DateTime<Utc>>,
+    #[serde(default, rename = "loop")]
+    pub looping: bool,
+}
+
+fn default_turn_credential_ttl() -> u64 {
+    DEFAULT_TURN_CREDENTIAL_TTL
+}
+
+impl IceServerConfig {
+    fn stun(urls: Vec<String>) -> Self {
+        Self {
+            urls,
+            username: None,
+            credential: None,
+            secret: None,
+            credential_ttl: DEFAULT_TURN_CREDENTIAL_TTL,
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.urls.is_empty() {
+            bail!("Every ICE server needs at least one URL");
+        }
+
+        for url in &self.urls {
+            if !["stun:", "stuns:", "turn:", "turns:"].iter().any(|scheme| url.starts_with(scheme)) {
+                bail!("Invalid ICE server URL '{}': expected a stun:, stuns:, turn: or turns: URL", url);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Timeouts of the broadcaster and viewer sessions
+#[derive(Debug, Clone, Copy)]
+pub struct SessionSettings {
+    pub broadcaster_disconnect_timeout: Duration,
+    pub broadcaster_grace_period: Duration,
+    pub track_settle_timeout: Duration,
+}
+
+/// Options of the WebRTC network stack
+#[derive(Debug, Clone, Default)]
+pub struct NetworkSettings {
+    pub udp_port_range: Option<(u16, u16)>,
+    pub nat_1to1_ips: Vec<String>,
+    pub ice_lite: bool,
+    pub udp_mux_port: Option<u16>,
+}
+
+/// Where and how broadcasts are recorded
+#[derive(Debug, Clone)]
+pub struct RecordingSettings {
+    pub directory: PathBuf,
+    pub ffmpeg: PathBuf,
+}
+
+/// Where the files played out as broadcasts are read from
+#[derive(Debug, Clone)]
+pub struct PlayoutSettings {
+    pub directory: PathBuf,
+}
+
+/// Limits of the chat relayed over data channels
+#[derive(Debug, Clone, Copy)]
+pub struct ChatSettings {
+    pub max_message_size: usize,
+    // Messages per second and user
+    pub rate_limit: f64,
+    // Chat messages kept for late joiners
+    pub history: usize,
+}
+
+/// Access to the control API
+#[derive(Debug, Clone, Default)]
+pub struct ApiSettings {
+    // Bearer token required to change anything, or None to only accept changes from localhost
+    pub token: Option<String>,
 }
 
 pub struct Settings {
     pub host: String,
     pub port: u16,
-    pub debug: bool
+    pub debug: bool,
+    pub legacy_signaling: bool,
+    pub session: SessionSettings,
+    pub ice_servers: Vec<IceServerConfig>,
+    pub network: NetworkSettings,
+    pub recording: RecordingSettings,
+    pub playout: PlayoutSettings,
+    pub chat: ChatSettings,
+    pub api: ApiSettings,
+    pub schedule: Vec<ScreeningConfig>
 }
 
 impl Settings {
-    pub fn new() -> Self {
+    /// Build the settings from, in increasing order of precedence: the defaults, the TOML file
+    /// given with `--config`, the `UNITED_CINEMAS_*` environment variables and the CLI flags.
+    pub fn new() -> Result<Self> {
         let args = Args::parse();
-        Self {
-            host: args.host,
-            port: args.port,
-            debug: args.debug
+
+        let file = match &args.config {
+            Some(path) => FileConfig::load(path)?,
+            None => FileConfig::default(),
+        };
+
+        let ice_servers = Self::merge_ice_servers(&args, file.ice_servers)?;
+        for server in &ice_servers {
+            server.validate()?;
         }
+
+        let udp_port_min = args.udp_port_min.or(file.network.udp_port_min);
+        let udp_port_max = args.udp_port_max.or(file.network.udp_port_max);
+        let udp_port_range = match (udp_port_min, udp_port_max) {
+            (Some(min), Some(max)) if min > max => {
+                bail!("Invalid UDP port range: udp_port_min ({}) is greater than udp_port_max ({})", min, max)
+            }
+            (Some(min), Some(max)) => Some((min, max)),
+            (None, None) => None,
+            _ => bail!("udp_port_min and udp_port_max must be set together"),
+        };
+
+        let udp_mux_port = args.udp_mux_port.or(file.network.udp_mux_port);
+        if udp_mux_port.is_some() && udp_port_range.is_some() {
+            bail!("udp_mux_port cannot be combined with a UDP port range");
+        }
+
+        let chat_rate_limit = args.chat_rate_limit.or(file.chat.rate_limit).unwrap_or(DEFAULT_CHAT_RATE_LIMIT);
+        if !chat_rate_limit.is_finite() || chat_rate_limit <= 0.0 {
+            bail!("Invalid chat rate limit {}: expected a positive number of messages per second", chat_rate_limit);
+        }
+
+        let api_token = args.api_token.or(file.api.token);
+        if api_token.as_deref().is_some_and(|token| token.trim().is_empty()) {
+            bail!("The control API token cannot be empty");
+        }
+
+        let nat_1to1_ips = args.nat_1to1_ips.or(file.network.nat_1to1_ips).unwrap_or_default();
+
+        Ok(Self {
+            host: args.host.or(file.host).unwrap_or_else(|| DEFAULT_HOST.to_owned()),
+            port: args.port.or(file.port).unwrap_or(DEFAULT_PORT),
+            debug: args.debug.or(file.debug).unwrap_or(false),
+            legacy_signaling: args.legacy_signaling.or(file.legacy_signaling).unwrap_or(false),
+            session: SessionSettings {
+                broadcaster_disconnect_timeout: Duration::from_secs(
+                    args.broadcaster_disconnect_timeout
+                        .or(file.broadcaster_disconnect_timeout)
+                        .unwrap_or(DEFAULT_BROADCASTER_DISCONNECT_TIMEOUT)
+                ),
+                broadcaster_grace_period: Duration::from_secs(
+                    args.broadcaster_grace_period
+                        .or(file.broadcaster_grace_period)
+                        .unwrap_or(DEFAULT_BROADCASTER_GRACE_PERIOD)
+                ),
+                track_settle_timeout: Duration::from_secs(
+                    args.track_settle_timeout
+                        .or(file.track_settle_timeout)
+                        .unwrap_or(DEFAULT_TRACK_SETTLE_TIMEOUT)
+                ),
+            },
+            ice_servers,
+            network: NetworkSettings {
+                udp_port_range,
+                nat_1to1_ips: nat_1to1_ips.iter().map(IpAddr::to_string).collect(),
+                ice_lite: args.ice_lite.or(file.network.ice_lite).unwrap_or(false),
+                udp_mux_port,
+            },
+            recording: RecordingSettings {
+                directory: args.recording_directory
+                    .or(file.recording.directory)
+                    .unwrap_or_else(|| PathBuf::from(DEFAULT_RECORDING_DIRECTORY)),
+                ffmpeg: args.ffmpeg.or(file.recording.ffmpeg).unwrap_or_else(|| PathBuf::from(DEFAULT_FFMPEG)),
+            },
+            playout: PlayoutSettings {
+                directory: args.playout_directory
+                    .or(file.playout.directory)
+                    .unwrap_or_else(|| PathBuf::from(DEFAULT_PLAYOUT_DIRECTORY)),
+            },
+            chat: ChatSettings {
+                max_message_size: args.chat_max_message_size
+                    .or(file.chat.max_message_size)
+                    .unwrap_or(DEFAULT_CHAT_MAX_MESSAGE_SIZE),
+                rate_limit: chat_rate_limit,
+                history: args.chat_history.or(file.chat.history).unwrap_or(DEFAULT_CHAT_HISTORY),
+            },
+            api: ApiSettings { token: api_token },
+            schedule: file.schedule,
+        })
     }
-}
\ No newline at end of file
+
+    /// STUN and TURN flags replace the file's ICE servers altogether, so a server is never
+    /// half-configured from two sources
+    fn merge_ice_servers(args: &Args, file_servers: Option<Vec<IceServerConfig>>) -> Result<Vec<IceServerConfig>> {
+        let has_turn_flags = args.turn_username.is_some()
+            || args.turn_credential.is_some()
+            || args.turn_secret.is_some()
+            || args.turn_credential_ttl.is_some();
+
+        if args.stun_servers.is_none() && args.turn_servers.is_none() {
+            if has_turn_flags {
+                bail!("TURN credentials were given without any --turn-server");
+            }
+
+            return Ok(file_servers.unwrap_or_else(|| {
+                vec![IceServerConfig::stun(vec![DEFAULT_STUN_SERVER.to_owned()])]
+            }));
+        }
+
+        let mut ice_servers = Vec::new();
+
+        let stun_urls: Vec<String> = args.stun_servers.iter().flatten()
+            .filter(|url| !url.is_empty())
+            .cloned()
+            .collect();
+        if !stun_urls.is_empty() {
+            ice_servers.push(IceServerConfig::stun(stun_urls));
+        }
+
+        match &args.turn_servers {
+            Some(turn_urls) if !turn_urls.is_empty() => ice_servers.push(IceServerConfig {
+                urls: turn_urls.clone(),
+                username: args.turn_username.clone(),
+                credential: args.turn_credential.clone(),
+                secret: args.turn_secret.clone(),
+                credential_ttl: args.turn_credential_ttl.unwrap_or(DEFAULT_TURN_CREDENTIAL_TTL),
+            }),
+            _ if has_turn_flags => bail!("TURN credentials were given without any --turn-server"),
+            _ => {}
+        }
+
+        Ok(ice_servers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A list of URL flags. The tests build their flags by hand, as parsing them would also read
+    /// the `UNITED_CINEMAS_*` variables of the environment they run in.
+    fn urls(urls: &[&str]) -> Option<Vec<String>> {
+        Some(urls.iter().map(|url| url.to_string()).collect())
+    }
+
+    fn file_servers() -> Option<Vec<IceServerConfig>> {
+        Some(vec![IceServerConfig::stun(vec!["stun:stun.example.com:3478".to_owned()])])
+    }
+
+    #[test]
+    fn defaults_to_the_public_stun_server() {
+        let servers = Settings::merge_ice_servers(&Args::default(), None).unwrap();
+        assert_eq!(servers.len(), 1);
+        assert_eq!(servers[0].urls, [DEFAULT_STUN_SERVER]);
+    }
+
+    #[test]
+    fn keeps_the_file_servers_without_flags() {
+        let servers = Settings::merge_ice_servers(&Args::default(), file_servers()).unwrap();
+        assert_eq!(servers[0].urls, ["stun:stun.example.com:3478"]);
+
+        // An empty list in the file disables ICE servers altogether
+        assert!(Settings::merge_ice_servers(&Args::default(), Some(Vec::new())).unwrap().is_empty());
+    }
+
+    #[test]
+    fn flags_replace_the_file_servers() {
+        let flags = Args { stun_servers: urls(&["stun:a.example.com", "stun:b.example.com"]), ..Default::default() };
+        let servers = Settings::merge_ice_servers(&flags, file_servers()).unwrap();
+
+        assert_eq!(servers.len(), 1);
+        assert_eq!(servers[0].urls, ["stun:a.example.com", "stun:b.example.com"]);
+    }
+
+    #[test]
+    fn builds_a_turn_server_from_flags() {
+        let flags = Args {
+            turn_servers: urls(&["turn:turn.example.com:3478"]),
+            turn_username: Some("cinema".to_owned()),
+            turn_secret: Some("shared-secret".to_owned()),
+            turn_credential_ttl: Some(600),
+            ..Default::default()
+        };
+        let servers = Settings::merge_ice_servers(&flags, file_servers()).unwrap();
+
+        assert_eq!(servers.len(), 1);
+        let turn = &servers[0];
+        assert_eq!(turn.urls, ["turn:turn.example.com:3478"]);
+        assert_eq!(turn.username.as_deref(), Some("cinema"));
+        assert_eq!(turn.secret.as_deref(), Some("shared-secret"));
+        assert_eq!(turn.credential_ttl, 600);
+    }
+
+    #[test]
+    fn skips_empty_stun_flags() {
+        let flags = Args {
+            stun_servers: urls(&[""]),
+            turn_servers: urls(&["turn:turn.example.com"]),
+            ..Default::default()
+        };
+        let servers = Settings::merge_ice_servers(&flags, None).unwrap();
+
+        assert_eq!(servers.len(), 1);
+        assert_eq!(servers[0].urls, ["turn:turn.example.com"]);
+        assert_eq!(servers[0].credential_ttl, DEFAULT_TURN_CREDENTIAL_TTL);
+    }
+
+    #[test]
+    fn rejects_turn_credentials_without_a_turn_server() {
+        let flags = Args { turn_username: Some("cinema".to_owned()), ..Default::default() };
+        assert!(Settings::merge_ice_servers(&flags, None).is_err());
+
+        let flags = Args {
+            stun_servers: urls(&["stun:a.example.com"]),
+            turn_secret: Some("s".to_owned()),
+            ..Default::default()
+        };
+        assert!(Settings::merge_ice_servers(&flags, None).is_err());
+    }
+
+    #[test]
+    fn validates_ice_server_urls() {
+        assert!(IceServerConfig::stun(vec!["turns:turn.example.com:5349".to_owned()]).validate().is_ok());
+        assert!(IceServerConfig::stun(vec!["https://turn.example.com".to_owned()]).validate().is_err());
+        assert!(IceServerConfig::stun(Vec::new()).validate().is_err());
+    }
+}